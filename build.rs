@@ -1,11 +1,33 @@
 use cmake::Config;
 use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SOURCE_DIR: &str = "src/true_libopenvpn3";
 
 fn main() {
-    let mut dst = Config::new("src/true_libopenvpn3");
-    
+    //Otherwise cargo reruns this on any change in the package
+    println!("cargo:rerun-if-changed=build.rs");
+    //Nothing to build or link for the mock backend alone
+    if !enabled("NATIVE") {
+        return;
+    }
+    for variable in ["LIBOPENVPN3_LIB_DIR", "LIBOPENVPN3_STATIC", "PKG_CONFIG_PATH"].iter() {
+        println!("cargo:rerun-if-env-changed={}", variable);
+    }
+    let crypto = crypto_backend();
+    let mut dst = Config::new(SOURCE_DIR);
+    dst.define("USE_MBEDTLS", if crypto == "mbedtls" { "ON" } else { "OFF" });
+    dst.define("USE_LZ4", if enabled("LZ4") { "ON" } else { "OFF" });
+    dst.define("USE_LZO", if enabled("LZO") { "ON" } else { "OFF" });
+    //Packets are rewritten in Rust (OVPNClient's Nat), not with libtins
+    dst.define("USE_TINS", "OFF");
+
     let target_os = env::var("CARGO_CFG_TARGET_OS").unwrap();
     let target_arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap();
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap();
+    let toolchain = Toolchain::new(&target_os);
     if target_os=="android" {
         if target_arch == "x86" {
             dst.define("ANDROID_ABI", "x86");
@@ -22,22 +44,333 @@ fn main() {
     } else {
         //panic!("not android");
     }
-    let dst = dst.build();
 
-    println!("cargo:rustc-link-search=native={}", dst.display());
-    println!("cargo:rustc-link-lib=dylib=stdc++");
-    println!("cargo:rustc-link-lib=static=libopenvpn3");
-    //println!("cargo:rustc-link-lib=static=ssl");
-    println!("cargo:rustc-link-lib=dylib=ssl");
-    println!("cargo:rustc-link-lib=dylib=crypto");
-    println!("cargo:rustc-link-lib=dylib=lz4");
-    println!("cargo:rustc-link-lib=dylib=lzo2");
-    println!("cargo:rustc-link-lib=static=tins");
+    //A prebuilt library, like one cached by CI, saves the cmake build. The cmake build is static.
+    let prebuilt_dir = env::var_os("LIBOPENVPN3_LIB_DIR").map(PathBuf::from);
+    let static_lib = prebuilt_dir.is_none() || env::var("LIBOPENVPN3_STATIC").map(|v| v != "0").unwrap_or(true);
+    let deps = if !static_lib {
+        //A shared library brings its own dependencies
+        NativeDeps::default()
+    } else if enabled("VENDORED") {
+        vendored_deps(crypto, &toolchain)
+    } else if target_os == "android" {
+        android_deps(crypto)
+    } else {
+        //musl binaries are static, so are their dependencies
+        system_deps(crypto, target_env == "musl", &toolchain)
+    };
+    //Where the C++ build's find_package, find_path and find_library look first
+    dst.define("CMAKE_INCLUDE_PATH", join(&deps.include_paths));
+    dst.define("CMAKE_LIBRARY_PATH", join(&deps.link_paths));
+    if let Some(openssl_root) = &deps.openssl_root {
+        dst.define("OPENSSL_ROOT_DIR", openssl_root);
+        dst.define("OPENSSL_USE_STATIC_LIBS", "ON");
+    }
+    let mut find_roots = deps.include_paths.clone();
+    find_roots.extend(deps.link_paths.iter().cloned());
+    find_roots.extend(deps.openssl_root.iter().cloned());
+    toolchain.configure(&mut dst, &find_roots);
+    let lib_dir = match prebuilt_dir {
+        Some(lib_dir) => prebuilt(&lib_dir, &target_os, static_lib),
+        None => {
+            if !Path::new(SOURCE_DIR).join("CMakeLists.txt").exists() {
+                panic!("{} is empty: run `git submodule update --init`, or set LIBOPENVPN3_LIB_DIR to a prebuilt library", SOURCE_DIR);
+            }
+            println!("cargo:rerun-if-changed={}", SOURCE_DIR);
+            dst.build()
+        }
+    };
+
+    println!("cargo:rustc-link-search=native={}", lib_dir.display());
+    println!("cargo:rustc-link-lib={}=libopenvpn3", if static_lib { "static" } else { "dylib" });
+    for path in deps.link_paths.iter() {
+        println!("cargo:rustc-link-search=native={}", path.display());
+    }
+    for (kind, lib) in deps.libs.iter() {
+        println!("cargo:rustc-link-lib={}={}", kind, lib);
+    }
+    //Last, a static libstdc++ only resolves what's linked before it
+    if let Some((kind, lib)) = cxx_stdlib(&target_os, &target_env) {
+        if kind == "static" {
+            if let Some(dir) = toolchain.library_dir(&format!("lib{}.a", lib)) {
+                println!("cargo:rustc-link-search=native={}", dir.display());
+            }
+        }
+        println!("cargo:rustc-link-lib={}={}", kind, lib);
+    }
+}
+
+//The C++ standard library of the target, overridden with CXXSTDLIB like the cc crate does
+fn cxx_stdlib(target_os: &str, target_env: &str) -> Option<(&'static str, String)> {
+    if let Some(lib) = target_var("CXXSTDLIB") {
+        let lib = lib.to_string_lossy().into_owned();
+        return if lib.is_empty() { None } else { Some(("dylib", lib)) };
+    }
+    match (target_os, target_env) {
+        (_, "msvc") => None,
+        ("macos", _) | ("ios", _) | ("freebsd", _) | ("openbsd", _) => Some(("dylib", "c++".to_string())),
+        ("android", _) => Some(("dylib", "c++_shared".to_string())),
+        (_, "musl") => Some(("static", "stdc++".to_string())),
+        _ => Some(("dylib", "stdc++".to_string()))
+    }
+}
+
+//What building for another target than the host takes beyond what the cmake crate does. It
+//already passes the target's compilers and flags (CC_<target>, CXXFLAGS_<target> and so on)
+//and sets CMAKE_SYSTEM_NAME and CMAKE_SYSTEM_PROCESSOR. Android builds use the NDK's toolchain file instead.
+struct Toolchain {
+    cross: bool,
+    ar: Option<OsString>,
+    cxx: Option<OsString>,
+    sysroot: Option<PathBuf>,
+}
+
+impl Toolchain {
+    fn new(target_os: &str) -> Toolchain {
+        let cross = env::var("TARGET").unwrap() != env::var("HOST").unwrap() && target_os != "android";
+        if !cross {
+            return Toolchain { cross: false, ar: None, cxx: None, sysroot: None };
+        }
+        let cxx = target_var("CXX");
+        //Toolchains like musl-cross and crosstool-NG know their own sysroot
+        let sysroot = target_var("LIBOPENVPN3_SYSROOT").map(PathBuf::from)
+            .or_else(|| target_var("CC").and_then(|cc| run(&cc, &["-print-sysroot"])).map(PathBuf::from))
+            .filter(|sysroot| sysroot.is_dir());
+        Toolchain { cross: true, ar: target_var("AR"), cxx: cxx, sysroot: sysroot }
+    }
+
+    fn configure(&self, config: &mut Config, find_roots: &[PathBuf]) {
+        if !self.cross {
+            return;
+        }
+        if let Some(ar) = &self.ar {
+            config.define("CMAKE_AR", ar);
+        }
+        if let Some(sysroot) = &self.sysroot {
+            config.define("CMAKE_SYSROOT", sysroot);
+            //Libraries and headers from the sysroot and the dependencies only, programs from the host
+            let mut roots = vec![sysroot.clone()];
+            roots.extend(find_roots.iter().cloned());
+            config.define("CMAKE_FIND_ROOT_PATH", join(&roots));
+            config.define("CMAKE_FIND_ROOT_PATH_MODE_PROGRAM", "NEVER");
+            for kind in ["LIBRARY", "INCLUDE", "PACKAGE"].iter() {
+                config.define(format!("CMAKE_FIND_ROOT_PATH_MODE_{}", kind), "ONLY");
+            }
+        }
+    }
+
+    //pkg-config run as is would find the host's libraries, so it refuses to when cross-compiling.
+    //Its settings come from the environment only, which is the user's to set.
+    fn check_pkg_config(&self, config: &pkg_config::Config) {
+        if !self.cross || config.target_supported() {
+            return;
+        }
+        let target = env::var("TARGET").unwrap();
+        let sysroot = self.sysroot.as_ref().map(|sysroot| sysroot.display().to_string())
+            .unwrap_or_else(|| "the target's sysroot".to_string());
+        panic!("pkg-config isn't set up for {0}: set PKG_CONFIG_SYSROOT_DIR_{0} to {1} and PKG_CONFIG_LIBDIR_{0} \
+            to its usr/lib/pkgconfig, or build everything from source with the vendored feature", target, sysroot);
+    }
+
+    //Where the target's C++ compiler keeps `file`, for libraries the linker wouldn't find by itself
+    fn library_dir(&self, file: &str) -> Option<PathBuf> {
+        let path = PathBuf::from(run(self.cxx.as_ref()?, &[&format!("-print-file-name={}", file)])?);
+        //Only the file name back means it wasn't found
+        if path.is_absolute() { path.parent().map(|dir| dir.to_owned()) } else { None }
+    }
+}
+
+//The trimmed output of `command` (which may carry arguments of its own, like "ccache gcc") run with `args`
+fn run(command: &OsString, args: &[&str]) -> Option<String> {
+    let command = command.to_string_lossy();
+    let mut words = command.split_whitespace();
+    let output = Command::new(words.next()?).args(words).args(args).output().ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    Some(output.trim().to_string()).filter(|output| !output.is_empty())
+}
+
+//A variable for the target, looked up like the cc crate does: `NAME_<target>`, `NAME_<target with _>`, `TARGET_NAME`, `NAME`
+fn target_var(name: &str) -> Option<OsString> {
+    let target = env::var("TARGET").unwrap();
+    let variables = [format!("{}_{}", name, target), format!("{}_{}", name, target.replace('-', "_")),
+        format!("TARGET_{}", name), name.to_string()];
+    variables.iter().find_map(|variable| {
+        println!("cargo:rerun-if-env-changed={}", variable);
+        env::var_os(variable)
+    })
+}
+
+//Checks that `lib_dir` has the library in the asked for flavor
+fn prebuilt(lib_dir: &Path, target_os: &str, static_lib: bool) -> PathBuf {
+    let file = match (target_os, static_lib) {
+        //The import library, for a DLL
+        ("windows", _) => "libopenvpn3.lib",
+        ("macos", false) | ("ios", false) => "liblibopenvpn3.dylib",
+        (_, false) => "liblibopenvpn3.so",
+        (_, true) => "liblibopenvpn3.a",
+    };
+    let path = lib_dir.join(file);
+    if !path.exists() {
+        panic!("LIBOPENVPN3_LIB_DIR is {} but it has no {}{}", lib_dir.display(), file,
+            if static_lib { ", set LIBOPENVPN3_STATIC=0 to link a shared library" } else { "" });
+    }
+    println!("cargo:rerun-if-changed={}", path.display());
+    lib_dir.to_owned()
+}
+
+//`openssl` or `mbedtls`, from the features of the same name
+fn crypto_backend() -> &'static str {
+    match (enabled("OPENSSL"), enabled("MBEDTLS")) {
+        (true, true) => panic!("the openssl and mbedtls features are mutually exclusive, enable only one"),
+        (_, true) => "mbedtls",
+        _ => "openssl"
+    }
+}
 
-    if cfg!(target_os = "android") {
-        println!("cargo:rustc-link-lib=static=crypto");
-        println!("cargo:rustc-link-lib=static=lzo");
-        println!("cargo:rustc-link-lib=static=lz4");
-        println!("cargo:rustc-link-lib=static=ssl");
+//In link order: TLS, then X.509, then the primitives they use
+fn crypto_libs(crypto: &str) -> &'static [&'static str] {
+    match crypto {
+        "mbedtls" => &["mbedtls", "mbedx509", "mbedcrypto"],
+        _ => &["ssl", "crypto"]
     }
 }
+
+//What the C++ library links against, after libopenvpn3 itself
+#[derive(Default)]
+struct NativeDeps {
+    include_paths: Vec<PathBuf>,
+    link_paths: Vec<PathBuf>,
+    //(kind, name) in link order
+    libs: Vec<(&'static str, String)>,
+    openssl_root: Option<PathBuf>,
+}
+
+impl NativeDeps {
+    fn add(&mut self, kind: &'static str, libs: &[&str]) {
+        self.libs.extend(libs.iter().map(|lib| (kind, lib.to_string())));
+    }
+
+    fn add_pkg_config(&mut self, kind: &'static str, library: pkg_config::Library) {
+        self.include_paths.extend(library.include_paths);
+        self.link_paths.extend(library.link_paths);
+        self.libs.extend(library.libs.into_iter().map(|lib| (kind, lib)));
+    }
+}
+
+//The system's libraries, found with pkg-config. When cross-compiling, the target's libraries
+//in the sysroot (PKG_CONFIG_SYSROOT_DIR_<target>, PKG_CONFIG_LIBDIR_<target>).
+fn system_deps(crypto: &str, static_libs: bool, toolchain: &Toolchain) -> NativeDeps {
+    let mut deps = NativeDeps::default();
+    let kind = if static_libs { "static" } else { "dylib" };
+    let mut config = pkg_config::Config::new();
+    config.cargo_metadata(false).statik(static_libs);
+    toolchain.check_pkg_config(&config);
+    if crypto == "mbedtls" {
+        match probe(&config, "mbedtls", "mbed TLS", "libmbedtls-dev or mbedtls-devel") {
+            Ok(library) => deps.add_pkg_config(kind, library),
+            //mbed TLS 2 ships no .pc files, try the plain names
+            Err(e) => {
+                println!("cargo:warning={}", e);
+                deps.add(kind, crypto_libs(crypto));
+            }
+        }
+    } else {
+        deps.add_pkg_config(kind, require(probe(&config, "openssl", "OpenSSL", "libssl-dev or openssl-devel")));
+    }
+    if enabled("LZ4") {
+        deps.add_pkg_config(kind, require(probe(&config, "liblz4", "LZ4", "liblz4-dev or lz4-devel")));
+    }
+    if enabled("LZO") {
+        deps.add_pkg_config(kind, require(probe(&config, "lzo2", "LZO", "liblzo2-dev or lzo-devel")));
+    }
+    deps
+}
+
+//Prebuilt static libraries for the Android ABI, as found by the NDK
+fn android_deps(crypto: &str) -> NativeDeps {
+    let mut deps = NativeDeps::default();
+    if enabled("LZO") {
+        deps.add("static", &["lzo"]);
+    }
+    if enabled("LZ4") {
+        deps.add("static", &["lz4"]);
+    }
+    deps.add("static", crypto_libs(crypto));
+    deps
+}
+
+//Everything built from source and linked statically, for self-contained binaries
+fn vendored_deps(crypto: &str, toolchain: &Toolchain) -> NativeDeps {
+    let mut deps = NativeDeps::default();
+    //Before the long OpenSSL build, so a missing source tree fails early
+    if enabled("LZO") {
+        let lzo = build_from_source(toolchain, "LZO_SRC_DIR", "LZO 2", &[("ENABLE_SHARED", "OFF"), ("ENABLE_STATIC", "ON")]);
+        deps.include_paths.push(lzo.join("include"));
+        deps.link_paths.push(lzo.join("lib"));
+    }
+    if crypto == "mbedtls" {
+        let mbedtls = build_from_source(toolchain, "MBEDTLS_SRC_DIR", "mbed TLS 2", &[("ENABLE_PROGRAMS", "OFF"), ("ENABLE_TESTING", "OFF")]);
+        deps.include_paths.push(mbedtls.join("include"));
+        deps.link_paths.push(mbedtls.join("lib"));
+    } else {
+        build_openssl(&mut deps);
+    }
+    //lz4-sys builds and links LZ4, we only need its headers
+    if let Some(include) = env::var_os("DEP_LZ4_INCLUDE") {
+        deps.include_paths.push(include.into());
+    }
+    deps.add("static", crypto_libs(crypto));
+    if enabled("LZO") {
+        deps.add("static", &["lzo2"]);
+    }
+    deps
+}
+
+//OpenSSL built from source by openssl-src, which only the vendored feature pulls in
+#[cfg(feature = "vendored")]
+fn build_openssl(deps: &mut NativeDeps) {
+    let openssl = openssl_src::Build::new().build();
+    deps.include_paths.push(openssl.include_dir().to_owned());
+    deps.link_paths.push(openssl.lib_dir().to_owned());
+    deps.openssl_root = openssl.lib_dir().parent().map(|p| p.to_owned());
+}
+
+#[cfg(not(feature = "vendored"))]
+fn build_openssl(_deps: &mut NativeDeps) {
+    panic!("the vendored feature builds OpenSSL with openssl-src, but that build dependency isn't enabled");
+}
+
+//Builds the cmake project in the directory `variable` points to, returns where it's installed
+fn build_from_source(toolchain: &Toolchain, variable: &str, name: &str, defines: &[(&str, &str)]) -> PathBuf {
+    println!("cargo:rerun-if-env-changed={}", variable);
+    let source = env::var_os(variable).unwrap_or_else(|| {
+        panic!("the vendored feature builds {} from source: set {} to its source tree", name, variable)
+    });
+    let mut config = Config::new(source);
+    for (key, value) in defines {
+        config.define(key, value);
+    }
+    toolchain.configure(&mut config, &[]);
+    config.build()
+}
+
+fn probe(config: &pkg_config::Config, package: &str, name: &str, install: &str) -> Result<pkg_config::Library, String> {
+    config.probe(package).map_err(|e| not_found(name, install, e))
+}
+
+fn not_found(name: &str, install: &str, e: pkg_config::Error) -> String {
+    format!("{} not found with pkg-config. Install {}, point PKG_CONFIG_PATH to it, or build everything from source with the vendored feature. {}", name, install, e)
+}
+
+fn require(library: Result<pkg_config::Library, String>) -> pkg_config::Library {
+    library.unwrap_or_else(|e| panic!("{}", e))
+}
+
+fn join(paths: &[PathBuf]) -> String {
+    paths.iter().map(|p| p.display().to_string()).collect::<Vec<String>>().join(";")
+}
+
+//Whether the cargo feature is on, `name` in upper case with `_` for `-`
+fn enabled(name: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{}", name)).is_some()
+}
@@ -1 +1,5 @@
-pub mod openvpn;
\ No newline at end of file
+pub mod openvpn;
+
+//Links the LZ4 it builds from source, which the C++ library uses
+#[cfg(all(feature = "vendored", feature = "lz4"))]
+extern crate lz4_sys;
@@ -1,3 +1,497 @@
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+use std::net::IpAddr;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::{CaptureFormat, CaptureLimits, OVPNClient, OVPNEvent, OVPNEventKind, OnVpnEvent, OpenVpnReceiveError, PacketCapture, ProxyConfig, PushedConfig};
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+use libopenvpn3::openvpn::{IpPrefix, RouteTable};
+use libopenvpn3::openvpn::cli::{self, Args};
+
+//Addresses the C++ side rewrites our tunnel addresses to. The userspace stack uses these.
+const REPLACEMENT_IPV4: Ipv4Addr = Ipv4Addr::new(10, 255, 0, 2);
+const REPLACEMENT_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const PUSH_TIMEOUT: Duration = Duration::from_secs(30);
+//How long `connect` waits for DISCONNECTED after Ctrl-C
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+//Set by the SIGINT/SIGTERM handler
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+//Set when a fatal event arrives, like AUTH_FAILED
+static FAILED: AtomicBool = AtomicBool::new(false);
+static DISCONNECTED: AtomicBool = AtomicBool::new(false);
+
+const USAGE: &str = "usage:
+    libopenvpn3 connect <profile.ovpn> [--user USER --pass PASS | --auth-file FILE] [--stats SECONDS]
+                        [--socks5 127.0.0.1:1080] [--http-proxy 127.0.0.1:8080] [--tun NAME]
+    libopenvpn3 socks5 <profile.ovpn> [--listen 127.0.0.1:1080] [--user USER] [--pass PASS]
+                       [--socks-user USER --socks-pass PASS]
+    libopenvpn3 http-proxy <profile.ovpn> [--listen 127.0.0.1:8080] [--user USER] [--pass PASS]
+                           [--proxy-user USER --proxy-pass PASS]
+    libopenvpn3 pki <dir> [--remote 127.0.0.1] [--port 1194] [--proto udp|tcp]
+                    [--tls-wrap none|auth|crypt] [--clients client,...]
+    libopenvpn3 validate <profile.ovpn>...
+
+    connect prints the events and, every --stats seconds (10 by default), the traffic counters
+    until Ctrl-C disconnects it. It can serve the tunnel as a SOCKS5 or HTTP proxy, or over a
+    tun device, when built with those features. --auth-file has the username and password on
+    two lines, like OpenVPN's auth-user-pass file. Every command that connects takes it, and
+    --compression allow|asym|refuse (asym by default) too. They also take --upstream-proxy
+    http://HOST:PORT to reach the server through an HTTP proxy, with
+    --upstream-proxy-auth-file FILE (same format) for its credentials and
+    --upstream-proxy-cleartext yes to allow sending them with Basic auth.
+
+    proxies also take --include PREFIX,... and --exclude PREFIX,... to override which
+    destinations go through the tunnel, and --dns IP,... to override the pushed DNS servers.
+    --capture FILE writes the tunnel traffic to FILE in pcapng format, or classic pcap
+    without packet directions when FILE ends in .pcap.
+    pki writes a throwaway CA, server.conf and one <client>.ovpn per client to <dir>
+    validate checks profiles without connecting and exits with 1 when one can't connect";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2)
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1)
+}
+
+//Username and password from --user/--pass or --auth-file
+fn credentials(args: &Args) -> (Option<String>, Option<String>) {
+    cli::credentials(args).unwrap_or_else(|e| fail(format!("reading {}: {}", args.option("auth-file").unwrap_or(""), e)))
+}
+
+//--upstream-proxy and its credentials. Credentials alone go to the profile's own proxy.
+fn upstream_proxy(args: &Args) -> Option<ProxyConfig> {
+    let server = args.option("upstream-proxy").map(|s| s.parse().unwrap_or_else(|e| fail(e)));
+    let credentials = args.option("upstream-proxy-auth-file").map(|path| {
+        let (username, password) = cli::read_auth_file(path).unwrap_or_else(|e| fail(format!("reading {}: {}", path, e)));
+        (username.unwrap_or_default(), password.unwrap_or_default())
+    });
+    if server.is_none() && credentials.is_none() {
+        return None;
+    }
+    let mut proxy = ProxyConfig {
+        server: server,
+        allow_cleartext_auth: args.option("upstream-proxy-cleartext") == Some("yes"),
+        ..ProxyConfig::default()
+    };
+    if let Some((username, password)) = credentials {
+        proxy = proxy.with_credentials(&username, &password);
+    }
+    Some(proxy)
+}
+
+//Creates a client for the profile in args and waits until the server pushed its options.
+//Also returns the profile. `replace_addresses` turns on the replacement IPs.
+fn connect(args: &Args, on_vpn_event: Option<OnVpnEvent>, replace_addresses: bool) -> (OVPNClient, PushedConfig, String) {
+    let path = args.positional.first().unwrap_or_else(|| usage());
+    let profile = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("reading {}: {}", path, e)));
+    let (username, password) = credentials(args);
+    let (replacement_ipv4, replacement_ipv6) = if replace_addresses {
+        (Some(&REPLACEMENT_IPV4), Some(&REPLACEMENT_IPV6))
+    } else {
+        (None, None)
+    };
+    let compression = args.option("compression").map(|c| c.parse().unwrap_or_else(|e| fail(e))).unwrap_or_default();
+    let client = OVPNClient::new(profile.clone(), username.as_deref(), password.as_deref(), None, None, None, on_vpn_event, replacement_ipv4, replacement_ipv6, compression, upstream_proxy(args).as_ref())
+        .unwrap_or_else(|e| fail(format!("{:?}", e)));
+    if let Some(path) = args.option("capture") {
+        let capture = PacketCapture::to_file(path, CaptureFormat::from_path(path), CaptureLimits::default())
+            .unwrap_or_else(|e| fail(format!("creating {}: {}", path, e)));
+        client.set_capture(Some(Arc::new(capture)));
+    }
+    if client.connect().is_err() {
+        fail("could not start the connection".into());
+    }
+    //Short waits, so a fatal event or Ctrl-C doesn't have to sit out the whole timeout
+    let deadline = Instant::now() + PUSH_TIMEOUT;
+    let pushed_config = loop {
+        if let Some(pushed_config) = client.wait_pushed_config(Duration::from_millis(200)) {
+            break pushed_config;
+        }
+        if FAILED.load(Ordering::SeqCst) {
+            fail("the connection failed".into());
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            let _ = client.disconnect();
+            std::process::exit(130);
+        }
+        if Instant::now() >= deadline {
+            fail("timed out waiting for the server to push its options".into());
+        }
+    };
+    (client, pushed_config, profile)
+}
+
+//Split tunneling from the profile, the pushed options and --include/--exclude
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+fn route_table(args: &Args, profile: &str, pushed_config: &PushedConfig) -> RouteTable {
+    let mut routes = RouteTable::from_profile(profile);
+    routes.apply_pushed(pushed_config);
+    let prefixes = |key: &str| -> Vec<IpPrefix> {
+        args.option(key).unwrap_or("").split(',')
+            .filter(|p| !p.is_empty())
+            .map(|p| p.parse().unwrap_or_else(|e: String| fail(e)))
+            .collect()
+    };
+    for prefix in prefixes("include") {
+        routes.include(prefix);
+    }
+    for prefix in prefixes("exclude") {
+        routes.exclude(prefix);
+    }
+    routes
+}
+
+//Connects and puts a userspace stack, addressed with the replacement IPs, on top of the client.
+//Also returns a resolver that asks the DNS servers through the tunnel.
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+fn stack(args: &Args) -> (Arc<libopenvpn3::openvpn::stack::Stack>, Arc<dyn libopenvpn3::openvpn::dns::Resolver>) {
+    let (client, pushed_config, profile) = connect(args, None, true);
+    stack_over(args, client, &pushed_config, &profile)
+}
+
+//Puts the stack on a client connected with the replacement IPs
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+fn stack_over(args: &Args, client: OVPNClient, pushed_config: &PushedConfig, profile: &str) -> (Arc<libopenvpn3::openvpn::stack::Stack>, Arc<dyn libopenvpn3::openvpn::dns::Resolver>) {
+    use libopenvpn3::openvpn::dns::{SystemResolver, TunnelResolver};
+    use libopenvpn3::openvpn::stack::{Stack, StackConfig};
+
+    let mut config = StackConfig::from_pushed(pushed_config);
+    config.routes = Some(route_table(args, profile, pushed_config));
+    config.ipv4 = config.ipv4.map(|p| IpPrefix::new(IpAddr::V4(REPLACEMENT_IPV4), p.len));
+    config.ipv6 = config.ipv6.map(|p| IpPrefix::new(IpAddr::V6(REPLACEMENT_IPV6), p.len));
+    let stack = Arc::new(Stack::new(client, config).unwrap_or_else(|e| fail(e.to_string())));
+    let dns_servers: Vec<IpAddr> = match args.option("dns") {
+        Some(servers) => servers.split(',')
+            .map(|s| s.parse().unwrap_or_else(|_| fail(format!("invalid DNS server {}", s))))
+            .collect(),
+        None => pushed_config.dns_servers.clone()
+    };
+    if dns_servers.is_empty() {
+        eprintln!("warning: the server pushed no DNS servers, names will be resolved outside the tunnel");
+        return (stack, Arc::new(SystemResolver));
+    }
+    let resolver = TunnelResolver::new(stack.clone(), dns_servers, pushed_config.search_domains.clone());
+    (stack, Arc::new(resolver))
+}
+
+#[cfg(feature = "socks5")]
+fn socks5_server(args: &Args, stack: Arc<libopenvpn3::openvpn::stack::Stack>, resolver: Arc<dyn libopenvpn3::openvpn::dns::Resolver>, listen: std::net::SocketAddr) -> libopenvpn3::openvpn::socks5::Socks5Server {
+    use libopenvpn3::openvpn::socks5::Socks5Server;
+
+    let mut server = Socks5Server::bind(stack, listen).unwrap_or_else(|e| fail(e.to_string()))
+        .with_resolver(resolver);
+    if let (Some(user), Some(pass)) = (args.option("socks-user"), args.option("socks-pass")) {
+        server = server.with_credentials(user, pass);
+    }
+    server
+}
+
+#[cfg(feature = "socks5")]
+fn socks5(args: &Args) {
+    let listen = args.option("listen").unwrap_or("127.0.0.1:1080").parse()
+        .unwrap_or_else(|_| usage());
+    let (stack, resolver) = stack(args);
+    let server = socks5_server(args, stack, resolver, listen);
+    println!("SOCKS5 listening on {}", listen);
+    if let Err(e) = server.run() {
+        fail(e.to_string());
+    }
+}
+
+#[cfg(feature = "http-proxy")]
+fn http_proxy_server(args: &Args, stack: Arc<libopenvpn3::openvpn::stack::Stack>, resolver: Arc<dyn libopenvpn3::openvpn::dns::Resolver>, listen: std::net::SocketAddr) -> libopenvpn3::openvpn::http_proxy::HttpProxyServer {
+    use libopenvpn3::openvpn::http_proxy::HttpProxyServer;
+
+    let mut server = HttpProxyServer::bind(stack, listen).unwrap_or_else(|e| fail(e.to_string()))
+        .with_resolver(resolver);
+    if let (Some(user), Some(pass)) = (args.option("proxy-user"), args.option("proxy-pass")) {
+        server = server.with_credentials(user, pass);
+    }
+    server
+}
+
+#[cfg(feature = "http-proxy")]
+fn http_proxy(args: &Args) {
+    let listen = args.option("listen").unwrap_or("127.0.0.1:8080").parse()
+        .unwrap_or_else(|_| usage());
+    let (stack, resolver) = stack(args);
+    let server = http_proxy_server(args, stack, resolver, listen);
+    println!("HTTP proxy listening on {}", listen);
+    if let Err(e) = server.run() {
+        fail(e.to_string());
+    }
+}
+
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+fn catch_interrupt() {
+    unsafe {
+        libc::signal(libc::SIGINT, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+fn print_event(event: OVPNEvent) {
+    let kind = event.kind();
+    if kind == OVPNEventKind::Disconnected {
+        DISCONNECTED.store(true, Ordering::SeqCst);
+    }
+    if event.is_fatal() {
+        FAILED.store(true, Ordering::SeqCst);
+    }
+    let description = match kind {
+        OVPNEventKind::Resolve => "resolving the server",
+        OVPNEventKind::Connecting => "connecting",
+        OVPNEventKind::GetConfig => "getting the configuration",
+        OVPNEventKind::AssignIp => "assigning addresses",
+        OVPNEventKind::Connected => "connected",
+        OVPNEventKind::Reconnecting => "reconnecting",
+        OVPNEventKind::Disconnected => "disconnected",
+        OVPNEventKind::AuthFailed => "authentication failed",
+        OVPNEventKind::CertVerifyFail => "certificate verification failed",
+        OVPNEventKind::ConnectionTimeout => "connection timed out",
+        OVPNEventKind::ProxyNeedCreds => "the proxy needs credentials, pass --upstream-proxy-auth-file",
+        _ => &event.name,
+    };
+    let line = if event.info.is_empty() {
+        description.to_owned()
+    } else {
+        format!("{}: {}", description, event.info)
+    };
+    if event.is_error() {
+        eprintln!("event: {}", line);
+    } else {
+        println!("event: {}", line);
+    }
+}
+
+fn print_pushed(pushed_config: &PushedConfig) {
+    if let Some(ipv4) = pushed_config.ipv4 {
+        println!("address {}", ipv4);
+    }
+    if let Some(ipv6) = pushed_config.ipv6 {
+        println!("address {}", ipv6);
+    }
+    for route in pushed_config.routes.iter() {
+        println!("route {}", route);
+    }
+    for server in pushed_config.dns_servers.iter() {
+        println!("dns {}", server);
+    }
+    if let Some(mtu) = pushed_config.mtu {
+        println!("mtu {}", mtu);
+    }
+}
+
+//What `connect` keeps running: the bare client, or a stack with proxies on it
+enum Tunnel {
+    Client(Box<OVPNClient>),
+    #[cfg(any(feature = "socks5", feature = "http-proxy"))]
+    Stack(Arc<libopenvpn3::openvpn::stack::Stack>),
+}
+
+impl Tunnel {
+    fn with_client<R, F: FnOnce(&mut OVPNClient) -> R>(&mut self, f: F) -> R {
+        match self {
+            Tunnel::Client(client) => f(client),
+            #[cfg(any(feature = "socks5", feature = "http-proxy"))]
+            Tunnel::Stack(stack) => stack.with_client(f),
+        }
+    }
+
+    //Nothing reads the bare client's packets, drop them so they don't pile up
+    fn discard_received(&mut self) {
+        match self {
+            Tunnel::Client(client) => while !matches!(client.receive(&mut |_| {}), Err(OpenVpnReceiveError::NoDataAvailable)) {},
+            #[cfg(any(feature = "socks5", feature = "http-proxy"))]
+            Tunnel::Stack(_) => {}
+        }
+    }
+}
+
+//Runs the proxies asked for on a stack over the client
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+fn serve_proxies(args: &Args, client: OVPNClient, pushed_config: &PushedConfig, profile: &str) -> Tunnel {
+    let (stack, resolver) = stack_over(args, client, pushed_config, profile);
+    let parse = |listen: &str| -> std::net::SocketAddr { listen.parse().unwrap_or_else(|_| usage()) };
+    if let Some(listen) = args.option("socks5").map(parse) {
+        #[cfg(feature = "socks5")]
+        {
+            let server = socks5_server(args, stack.clone(), resolver.clone(), listen);
+            println!("SOCKS5 listening on {}", listen);
+            std::thread::spawn(move || if let Err(e) = server.run() {
+                eprintln!("SOCKS5 server stopped: {}", e);
+            });
+        }
+        #[cfg(not(feature = "socks5"))]
+        fail(format!("built without the socks5 feature, can't listen on {}", listen));
+    }
+    if let Some(listen) = args.option("http-proxy").map(parse) {
+        #[cfg(feature = "http-proxy")]
+        {
+            let server = http_proxy_server(args, stack.clone(), resolver.clone(), listen);
+            println!("HTTP proxy listening on {}", listen);
+            std::thread::spawn(move || if let Err(e) = server.run() {
+                eprintln!("HTTP proxy stopped: {}", e);
+            });
+        }
+        #[cfg(not(feature = "http-proxy"))]
+        fail(format!("built without the http-proxy feature, can't listen on {}", listen));
+    }
+    Tunnel::Stack(stack)
+}
+
+//Moves packets between the client and a tun device until Ctrl-C
+#[cfg(all(feature = "tun", target_os = "linux"))]
+fn serve_tun(client: &mut OVPNClient, name: &str, pushed_config: &PushedConfig) {
+    use libopenvpn3::openvpn::tun::{pump, TunDevice};
+
+    let mut tun = TunDevice::create(name).unwrap_or_else(|e| fail(format!("creating {}: {}", name, e)));
+    tun.configure(pushed_config).unwrap_or_else(|e| fail(format!("configuring {}: {}", tun.name(), e)));
+    println!("tun device {} is up, traffic counters are printed on exit", tun.name());
+    if let Err(e) = pump(client, &mut tun, &INTERRUPTED) {
+        eprintln!("tun: {}", e);
+    }
+}
+
+//Connects, prints events and traffic counters, and keeps the tunnel up until Ctrl-C
+fn connect_command(args: &Args) {
+    catch_interrupt();
+    let stats_interval = Duration::from_secs(args.option("stats").map(|s| s.parse().unwrap_or_else(|_| usage())).unwrap_or(10));
+    let proxied = args.option("socks5").is_some() || args.option("http-proxy").is_some();
+    let on_vpn_event: OnVpnEvent = Arc::new(Mutex::new(print_event));
+    println!("crypto: {}", libopenvpn3::openvpn::crypto_backend());
+    //The stack uses the replacement IPs, a tun device the real ones
+    let (client, pushed_config, profile) = connect(args, Some(on_vpn_event), proxied);
+    print_pushed(&pushed_config);
+    let mut tunnel = if proxied {
+        #[cfg(any(feature = "socks5", feature = "http-proxy"))]
+        {
+            serve_proxies(args, client, &pushed_config, &profile)
+        }
+        #[cfg(not(any(feature = "socks5", feature = "http-proxy")))]
+        {
+            let _ = profile;
+            fail("built without the socks5 and http-proxy features".into())
+        }
+    } else {
+        Tunnel::Client(Box::new(client))
+    };
+    if let Some(name) = args.option("tun") {
+        #[cfg(all(feature = "tun", target_os = "linux"))]
+        tunnel.with_client(|client| serve_tun(client, name, &pushed_config));
+        #[cfg(not(all(feature = "tun", target_os = "linux")))]
+        fail(format!("built without the tun feature, can't create {}", name));
+    }
+    let mut next_stats = Instant::now() + stats_interval;
+    while !INTERRUPTED.load(Ordering::SeqCst) && !FAILED.load(Ordering::SeqCst) && !DISCONNECTED.load(Ordering::SeqCst) {
+        tunnel.discard_received();
+        if Instant::now() >= next_stats {
+            println!("{}", tunnel.with_client(|client| client.stats()));
+            next_stats += stats_interval;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    if !DISCONNECTED.load(Ordering::SeqCst) {
+        let _ = tunnel.with_client(|client| client.disconnect());
+        let deadline = Instant::now() + DISCONNECT_TIMEOUT;
+        while !DISCONNECTED.load(Ordering::SeqCst) && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    println!("{}", tunnel.with_client(|client| client.stats()));
+    std::process::exit(if FAILED.load(Ordering::SeqCst) { 1 } else { 0 });
+}
+
+//Throwaway CA and matching profiles, for trying things out against a local server
+#[cfg(feature = "pki")]
+fn pki(args: &Args) {
+    use libopenvpn3::openvpn::pki::{Pki, ProfileOptions, Proto, TlsWrap};
+
+    let dir = std::path::Path::new(args.positional.first().unwrap_or_else(|| usage()));
+    let mut options = ProfileOptions::default();
+    if let Some(remote) = args.option("remote") {
+        options.remote = remote.to_owned();
+    }
+    if let Some(port) = args.option("port") {
+        options.port = port.parse().unwrap_or_else(|_| usage());
+    }
+    options.proto = match args.option("proto").unwrap_or("udp") {
+        "udp" => Proto::Udp,
+        "tcp" => Proto::Tcp,
+        _ => usage()
+    };
+    options.tls_wrap = match args.option("tls-wrap").unwrap_or("none") {
+        "none" => TlsWrap::None,
+        "auth" => TlsWrap::Auth,
+        "crypt" => TlsWrap::Crypt,
+        _ => usage()
+    };
+    let pki = Pki::generate(&options.remote).unwrap_or_else(|e| fail(e.to_string()));
+    let write = |file: String, contents: &str| {
+        let path = dir.join(file);
+        std::fs::write(&path, contents).unwrap_or_else(|e| fail(format!("writing {}: {}", path.display(), e)));
+        println!("wrote {}", path.display());
+    };
+    std::fs::create_dir_all(dir).unwrap_or_else(|e| fail(format!("creating {}: {}", dir.display(), e)));
+    write("ca.crt".into(), &pki.ca.cert_pem);
+    write("server.conf".into(), &pki.server_profile(&options));
+    for name in args.option("clients").unwrap_or("client").split(',').filter(|n| !n.is_empty()) {
+        let client = pki.client(name).unwrap_or_else(|e| fail(e.to_string()));
+        write(format!("{}.ovpn", name), &pki.client_profile(&client, &options));
+    }
+}
+
+#[cfg(feature = "validate")]
+fn validate(args: &Args) {
+    use libopenvpn3::openvpn::validate::{has_errors, validate};
+
+    if args.positional.is_empty() {
+        usage();
+    }
+    let mut failed = false;
+    for path in args.positional.iter() {
+        let profile = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("reading {}: {}", path, e)));
+        let diagnostics = validate(&profile);
+        for diagnostic in diagnostics.iter() {
+            println!("{}: {}", path, diagnostic);
+        }
+        if has_errors(&diagnostics) {
+            failed = true;
+        } else if diagnostics.is_empty() {
+            println!("{}: ok", path);
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
 fn main() {
-    
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().collect();
+    let command = args.get(1).map(|s| s.as_str());
+    let args = Args::parse(args.get(2..).unwrap_or(&[])).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        usage()
+    });
+    match command {
+        Some("connect") => connect_command(&args),
+        #[cfg(feature = "socks5")]
+        Some("socks5") => socks5(&args),
+        #[cfg(feature = "http-proxy")]
+        Some("http-proxy") => http_proxy(&args),
+        #[cfg(feature = "pki")]
+        Some("pki") => pki(&args),
+        #[cfg(feature = "validate")]
+        Some("validate") => validate(&args),
+        _ => usage()
+    }
+}
@@ -0,0 +1,70 @@
+//What an OVPNClient runs on
+//
+//OVPNClient does the Rust side of the data path (filter, NAT, capture, stats) and hands
+//packets to a Backend: the C++ library (`native` feature) or a pure-Rust mock that needs
+//no native dependencies (`mock` feature). Backends report logs and events through the
+//EventSink they're created with, which also keeps the client's pushed config up to date.
+use std::sync::{Arc, Mutex};
+use super::openvpn::{OVPNEvent, OnVpnEvent, OnVpnLog};
+use super::pushed::PushedConfig;
+
+pub trait Backend: Send {
+    ///Starts connecting in the background. Progress is reported as events.
+    fn connect(&self) -> Result<(), String>;
+    fn disconnect(&self) -> Result<(), String>;
+    ///Sends one IP packet through the tunnel
+    fn send(&self, packet: &[u8]) -> Result<(), String>;
+    ///Copies the next received IP packet into `buffer`. `None` when there's none waiting.
+    fn receive(&self, buffer: &mut [u8]) -> Result<Option<usize>, String>;
+    ///Blocks until the tunnel shuts down
+    fn run(&self) -> Result<(), String> {
+        Err("run is not supported by this backend".into())
+    }
+}
+
+///Where a backend sends its log lines and events
+#[derive(Clone)]
+pub struct EventSink {
+    on_vpn_log: Option<OnVpnLog>,
+    on_vpn_event: Option<OnVpnEvent>,
+    //Shared with OVPNClient, filled as the server pushes options to us
+    pushed_config: Arc<Mutex<Option<PushedConfig>>>,
+}
+
+impl EventSink {
+    pub(crate) fn new(on_vpn_log: Option<OnVpnLog>, on_vpn_event: Option<OnVpnEvent>) -> EventSink {
+        EventSink {
+            on_vpn_log: on_vpn_log,
+            on_vpn_event: on_vpn_event,
+            pushed_config: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub(crate) fn pushed_config(&self) -> Arc<Mutex<Option<PushedConfig>>> {
+        self.pushed_config.clone()
+    }
+
+    ///A log line. `PUSH_REPLY` lines update the pushed config.
+    pub fn log(&self, line: String) {
+        if let Some(pushed_config) = PushedConfig::parse(&line) {
+            *self.pushed_config.lock().unwrap() = Some(pushed_config);
+        }
+        match self.on_vpn_log.as_ref() {
+            Some(on_vpn_log) => (on_vpn_log.lock().unwrap())(line),
+            None => println!("OpenVPN: {}", line)
+        }
+    }
+
+    ///An event. `CONNECTED` fills in the addresses and MTU from its info.
+    pub fn event(&self, event: OVPNEvent) {
+        if event.name == "CONNECTED" {
+            self.pushed_config.lock().unwrap()
+                .get_or_insert_with(PushedConfig::default)
+                .apply_connected_info(&event.info);
+        }
+        match self.on_vpn_event.as_ref() {
+            Some(on_vpn_event) => (on_vpn_event.lock().unwrap())(event),
+            None => println!("EVENT: {}", event)
+        }
+    }
+}
@@ -0,0 +1,294 @@
+//Capture of the packets going through an OVPNClient, in pcapng or classic pcap format
+//
+//tcpdump can't see a userspace tunnel, so OVPNClient::set_capture writes the inner
+//packets exchanged with the C++ side (outbound after filtering and NAT, inbound before
+//them) to a file or any writer. Packets are raw IP (LINKTYPE_RAW) with microsecond
+//timestamps. pcapng also gives each an inbound/outbound flag, which Wireshark shows as the
+//packet direction. Classic pcap has no room for it, but older tools read it.
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::filter::Direction;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_RAW: u16 = 101;
+const OPTION_END: u16 = 0;
+const OPTION_EPB_FLAGS: u16 = 2;
+//Direction bits of epb_flags
+const FLAG_INBOUND: u32 = 0b01;
+const FLAG_OUTBOUND: u32 = 0b10;
+const PCAP_MAGIC_MICROSECONDS: u32 = 0xA1B2_C3D4;
+//What pcap files say when packets aren't cut, like tcpdump's default
+const PCAP_DEFAULT_SNAPLEN: u32 = 262_144;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CaptureFormat {
+    #[default]
+    Pcapng,
+    ///Without the packet directions
+    Pcap,
+}
+
+impl CaptureFormat {
+    ///Pcap for `.pcap` files, pcapng for anything else
+    pub fn from_path<P: AsRef<Path>>(path: P) -> CaptureFormat {
+        match path.as_ref().extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("pcap") => CaptureFormat::Pcap,
+            _ => CaptureFormat::Pcapng
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureLimits {
+    ///Packets are cut to this many bytes
+    pub snaplen: Option<usize>,
+    ///Most bytes per file. Files are rotated when it's reached, writers stop capturing.
+    pub max_file_size: Option<u64>,
+    ///Rotated files kept next to the current one, as `path.1` (newest) to `path.N`
+    pub max_rotated_files: usize,
+}
+
+enum Sink {
+    Writer(Box<dyn Write + Send>),
+    File(PathBuf, File),
+}
+
+impl Sink {
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Writer(writer) => writer.write_all(data),
+            Sink::File(_, file) => file.write_all(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Writer(writer) => writer.flush(),
+            Sink::File(_, file) => file.flush(),
+        }
+    }
+}
+
+struct CaptureInner {
+    sink: Sink,
+    //Bytes in the current file
+    written: u64,
+    //Set when a limit was reached or writing failed
+    stopped: bool,
+    packets: u64,
+}
+
+pub struct PacketCapture {
+    inner: Mutex<CaptureInner>,
+    format: CaptureFormat,
+    limits: CaptureLimits,
+}
+
+impl PacketCapture {
+    ///Captures to `path`, truncating it. `CaptureFormat::from_path` picks the format from its name.
+    pub fn to_file<P: AsRef<Path>>(path: P, format: CaptureFormat, limits: CaptureLimits) -> io::Result<PacketCapture> {
+        let path = path.as_ref().to_owned();
+        let file = File::create(&path)?;
+        PacketCapture::new(Sink::File(path, file), format, limits)
+    }
+
+    pub fn to_writer(writer: Box<dyn Write + Send>, format: CaptureFormat, limits: CaptureLimits) -> io::Result<PacketCapture> {
+        PacketCapture::new(Sink::Writer(writer), format, limits)
+    }
+
+    fn new(sink: Sink, format: CaptureFormat, limits: CaptureLimits) -> io::Result<PacketCapture> {
+        let mut inner = CaptureInner {
+            sink: sink,
+            written: 0,
+            stopped: false,
+            packets: 0,
+        };
+        let header = file_header(format, limits.snaplen);
+        inner.sink.write_all(&header)?;
+        inner.written = header.len() as u64;
+        Ok(PacketCapture {
+            inner: Mutex::new(inner),
+            format: format,
+            limits: limits,
+        })
+    }
+
+    ///Packets written so far, across rotated files
+    pub fn packets(&self) -> u64 {
+        self.inner.lock().unwrap().packets
+    }
+
+    ///Whether capturing stopped, because a writer reached its size limit or writing failed
+    pub fn is_stopped(&self) -> bool {
+        self.inner.lock().unwrap().stopped
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.lock().unwrap().sink.flush()
+    }
+
+    ///Writes one packet. Errors stop the capture instead of reaching the data path.
+    pub fn write(&self, direction: Direction, packet: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.stopped {
+            return;
+        }
+        let block = match self.format {
+            CaptureFormat::Pcapng => packet_block(direction, packet, self.limits.snaplen),
+            CaptureFormat::Pcap => packet_record(packet, self.limits.snaplen),
+        };
+        let too_big = self.limits.max_file_size.map(|max| inner.written + block.len() as u64 > max).unwrap_or(false);
+        if too_big && self.rotate(&mut inner).is_err() {
+            inner.stopped = true;
+            return;
+        }
+        match inner.sink.write_all(&block) {
+            Ok(()) => {
+                inner.written += block.len() as u64;
+                inner.packets += 1;
+            },
+            Err(_) => inner.stopped = true
+        }
+    }
+
+    //Moves the current file to `path.1`, shifting older ones, and starts a new one.
+    //Writers can't be rotated, so they stop.
+    fn rotate(&self, inner: &mut CaptureInner) -> io::Result<()> {
+        let path = match &inner.sink {
+            Sink::File(path, _) => path.clone(),
+            Sink::Writer(_) => return Err(io::Error::other("capture size limit reached")),
+        };
+        inner.sink.flush()?;
+        let rotated = |i: usize| PathBuf::from(format!("{}.{}", path.display(), i));
+        let keep = self.limits.max_rotated_files;
+        if keep > 0 {
+            let _ = std::fs::remove_file(rotated(keep));
+            for i in (1..keep).rev() {
+                let _ = std::fs::rename(rotated(i), rotated(i + 1));
+            }
+            std::fs::rename(&path, rotated(1))?;
+        }
+        let mut file = File::create(&path)?;
+        let header = file_header(self.format, self.limits.snaplen);
+        file.write_all(&header)?;
+        inner.sink = Sink::File(path, file);
+        inner.written = header.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for PacketCapture {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn file_header(format: CaptureFormat, snaplen: Option<usize>) -> Vec<u8> {
+    match format {
+        CaptureFormat::Pcapng => pcapng_header(snaplen),
+        CaptureFormat::Pcap => pcap_header(snaplen),
+    }
+}
+
+//Section header and the interface description for the tunnel
+fn pcapng_header(snaplen: Option<usize>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(48);
+    let mut section = Vec::new();
+    section.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    //Version 1.0
+    section.extend_from_slice(&1u16.to_le_bytes());
+    section.extend_from_slice(&0u16.to_le_bytes());
+    //Section length unknown
+    section.extend_from_slice(&(-1i64).to_le_bytes());
+    header.extend_from_slice(&block(SECTION_HEADER_BLOCK, &section));
+    let mut interface = Vec::new();
+    interface.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    interface.extend_from_slice(&0u16.to_le_bytes());
+    interface.extend_from_slice(&(snaplen.unwrap_or(0) as u32).to_le_bytes());
+    header.extend_from_slice(&block(INTERFACE_DESCRIPTION_BLOCK, &interface));
+    header
+}
+
+//Classic pcap's global header, version 2.4 in our byte order
+fn pcap_header(snaplen: Option<usize>) -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend_from_slice(&PCAP_MAGIC_MICROSECONDS.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    //Timestamps in UTC, no accuracy given
+    header.extend_from_slice(&0i32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&snaplen.map(|s| s as u32).unwrap_or(PCAP_DEFAULT_SNAPLEN).to_le_bytes());
+    header.extend_from_slice(&(LINKTYPE_RAW as u32).to_le_bytes());
+    header
+}
+
+fn captured(packet: &[u8], snaplen: Option<usize>) -> &[u8] {
+    &packet[..std::cmp::min(packet.len(), snaplen.unwrap_or(usize::MAX))]
+}
+
+//Microseconds since the epoch
+fn timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+fn packet_record(packet: &[u8], snaplen: Option<usize>) -> Vec<u8> {
+    let captured = captured(packet, snaplen);
+    let timestamp = timestamp();
+    let mut record = Vec::with_capacity(captured.len() + 16);
+    record.extend_from_slice(&((timestamp / 1_000_000) as u32).to_le_bytes());
+    record.extend_from_slice(&((timestamp % 1_000_000) as u32).to_le_bytes());
+    record.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+    record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    record.extend_from_slice(captured);
+    record
+}
+
+fn packet_block(direction: Direction, packet: &[u8], snaplen: Option<usize>) -> Vec<u8> {
+    let captured = captured(packet, snaplen);
+    let timestamp = timestamp();
+    let mut body = Vec::with_capacity(captured.len() + 40);
+    //Interface 0
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    body.extend_from_slice(captured);
+    pad(&mut body);
+    let flags = match direction {
+        Direction::Inbound => FLAG_INBOUND,
+        Direction::Outbound => FLAG_OUTBOUND,
+    };
+    body.extend_from_slice(&OPTION_EPB_FLAGS.to_le_bytes());
+    body.extend_from_slice(&4u16.to_le_bytes());
+    body.extend_from_slice(&flags.to_le_bytes());
+    body.extend_from_slice(&OPTION_END.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    block(ENHANCED_PACKET_BLOCK, &body)
+}
+
+fn pad(data: &mut Vec<u8>) {
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+}
+
+//Wraps a block body with its type and total length, which goes at both ends
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
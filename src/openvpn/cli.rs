@@ -0,0 +1,60 @@
+//Argument and auth file parsing of the `libopenvpn3` binary, here so tests can reach it
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+///`--key value` options and positional arguments
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    ///Every `--key` takes the argument after it as its value, a later one wins. Fails with
+    ///the key of an option missing its value.
+    pub fn parse(args: &[String]) -> Result<Args, String> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if let Some(key) = arg.strip_prefix("--") {
+                match args.next() {
+                    Some(value) => {
+                        options.insert(key.to_owned(), value.clone());
+                    },
+                    None => return Err(format!("--{} needs a value", key))
+                }
+            } else {
+                positional.push(arg.clone());
+            }
+        }
+        Ok(Args {
+            positional: positional,
+            options: options,
+        })
+    }
+
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(|s| s.as_str())
+    }
+}
+
+///Username and password on the first two lines, like OpenVPN's auth-user-pass file.
+///Trailing whitespace and `\r` are dropped, missing lines are None.
+pub fn parse_auth_file(contents: &str) -> (Option<String>, Option<String>) {
+    let mut lines = contents.lines().map(|l| l.trim_end().to_owned());
+    (lines.next(), lines.next())
+}
+
+pub fn read_auth_file<P: AsRef<Path>>(path: P) -> io::Result<(Option<String>, Option<String>)> {
+    std::fs::read_to_string(path).map(|contents| parse_auth_file(&contents))
+}
+
+///Username and password from `--auth-file`, or else from `--user` and `--pass`
+pub fn credentials(args: &Args) -> io::Result<(Option<String>, Option<String>)> {
+    match args.option("auth-file") {
+        Some(path) => read_auth_file(path),
+        None => Ok((args.option("user").map(|s| s.to_owned()), args.option("pass").map(|s| s.to_owned())))
+    }
+}
@@ -0,0 +1,116 @@
+//Compression policy and which algorithms this build has
+//
+//LZ4 and LZO are compiled in with the `lz4` and `lzo` features. Compressing before
+//encrypting leaks information about what's sent (VORACLE), so the policy decides how much
+//of it a client does. It reaches the C++ library as OpenVPN's `allow-compression`.
+
+///How much compression a client does, see `OVPNClient::new`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionPolicy {
+    ///Compresses both ways when the profile or the server asks for it
+    Allow,
+    ///Decompresses what the server sends but never compresses what we send
+    Asymmetric,
+    ///Compresses nothing. Profiles asking for compression are rejected, and so is a server pushing it.
+    Refuse,
+}
+
+impl Default for CompressionPolicy {
+    ///Like OpenVPN 2.5 and later
+    fn default() -> CompressionPolicy {
+        CompressionPolicy::Asymmetric
+    }
+}
+
+impl std::str::FromStr for CompressionPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<CompressionPolicy, String> {
+        match s {
+            "yes" | "allow" => Ok(CompressionPolicy::Allow),
+            "asym" | "asymmetric" => Ok(CompressionPolicy::Asymmetric),
+            "no" | "refuse" => Ok(CompressionPolicy::Refuse),
+            _ => Err(format!("invalid compression policy {}, expected allow, asym or refuse", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionAlgorithm {
+    Lz4,
+    Lzo,
+    ///Compression framing, but nothing compressed
+    Stub,
+}
+
+impl std::fmt::Display for CompressionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            CompressionAlgorithm::Lz4 => "LZ4",
+            CompressionAlgorithm::Lzo => "LZO",
+            CompressionAlgorithm::Stub => "stub",
+        })
+    }
+}
+
+impl CompressionAlgorithm {
+    ///What a `compress` or `comp-lzo` directive with `args` asks for. `None` for other directives.
+    pub fn from_directive(name: &str, args: &[&str]) -> Option<CompressionAlgorithm> {
+        match (name, args.first().copied()) {
+            ("compress", None) | ("compress", Some("stub")) | ("compress", Some("stub-v2")) => Some(CompressionAlgorithm::Stub),
+            ("compress", Some("lz4")) | ("compress", Some("lz4-v2")) => Some(CompressionAlgorithm::Lz4),
+            ("compress", Some("lzo")) => Some(CompressionAlgorithm::Lzo),
+            ("comp-lzo", Some("no")) => Some(CompressionAlgorithm::Stub),
+            ("comp-lzo", _) => Some(CompressionAlgorithm::Lzo),
+            _ => None
+        }
+    }
+
+    ///Whether this build was compiled with it
+    pub fn is_available(&self) -> bool {
+        match self {
+            CompressionAlgorithm::Lz4 => cfg!(feature = "lz4"),
+            CompressionAlgorithm::Lzo => cfg!(feature = "lzo"),
+            CompressionAlgorithm::Stub => true,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    fn directive(&self) -> &'static str {
+        match self {
+            CompressionPolicy::Allow => "allow-compression yes",
+            CompressionPolicy::Asymmetric => "allow-compression asym",
+            CompressionPolicy::Refuse => "allow-compression no",
+        }
+    }
+
+    ///`profile` with the policy in it, replacing any `allow-compression` of its own. Fails
+    ///when the profile asks for compression the policy refuses or this build doesn't have.
+    pub fn apply(&self, profile: &str) -> std::result::Result<String, String> {
+        let mut applied = String::with_capacity(profile.len() + 24);
+        for line in profile.lines() {
+            let mut words = line.split_whitespace();
+            let name = words.next().unwrap_or("");
+            let args: Vec<&str> = words.collect();
+            if name == "allow-compression" {
+                continue;
+            }
+            if let Some(algorithm) = CompressionAlgorithm::from_directive(name, &args) {
+                if algorithm != CompressionAlgorithm::Stub {
+                    if *self == CompressionPolicy::Refuse {
+                        return Err(format!("the profile asks for {} compression, which the policy refuses", algorithm));
+                    }
+                    if !algorithm.is_available() {
+                        return Err(format!("the profile asks for {} compression, which this build doesn't have (the {} feature)",
+                            algorithm, algorithm.to_string().to_lowercase()));
+                    }
+                }
+            }
+            applied.push_str(line);
+            applied.push('\n');
+        }
+        applied.push_str(self.directive());
+        applied.push('\n');
+        Ok(applied)
+    }
+}
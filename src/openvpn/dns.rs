@@ -0,0 +1,304 @@
+//DNS resolution through the tunnel (`stack` feature)
+//
+//Userspace tunnels don't touch the system resolver, so names looked up with it leak
+//outside the VPN. TunnelResolver asks the pushed DNS servers over the userspace stack
+//instead: UDP first, TCP when the answer is truncated. Answers are cached for their TTL.
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::pushed::PushedConfig;
+use super::stack::{self, Stack};
+
+const DNS_PORT: u16 = 53;
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_ATTEMPTS: usize = 2;
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_NXDOMAIN: u16 = 3;
+//Longest name on the wire, RFC 1035 2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+//Names with fewer dots than this try the search domains first, like resolv.conf's ndots
+const NDOTS: usize = 1;
+
+pub type OnResolved = Arc<dyn Fn(&str, &[IpAddr]) + Send + Sync>;
+
+///Turns host names into addresses. The proxies take one so they can resolve through the tunnel.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+///The operating system's resolver. Queries don't go through the tunnel.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|a| a.ip()).collect())
+    }
+}
+
+///Resolves `host` and pairs the first address with `port`
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+pub(crate) fn resolve_addr(resolver: &dyn Resolver, host: &str, port: u16) -> io::Result<SocketAddr> {
+    resolver.resolve(host)?
+        .first()
+        .map(|ip| SocketAddr::new(*ip, port))
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("could not resolve {}", host)))
+}
+
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    expires: Instant,
+}
+
+pub struct TunnelResolver {
+    stack: Arc<Stack>,
+    servers: Vec<SocketAddr>,
+    search_domains: Vec<String>,
+    timeout: Duration,
+    attempts: usize,
+    cache: Mutex<HashMap<(String, u16), CacheEntry>>,
+    next_id: AtomicU16,
+    on_resolved: Option<OnResolved>,
+}
+
+impl TunnelResolver {
+    pub fn new(stack: Arc<Stack>, servers: Vec<IpAddr>, search_domains: Vec<String>) -> TunnelResolver {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u16)
+            .unwrap_or(0);
+        TunnelResolver {
+            stack: stack,
+            servers: servers.into_iter().map(|ip| SocketAddr::new(ip, DNS_PORT)).collect(),
+            search_domains: search_domains,
+            timeout: DEFAULT_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            cache: Mutex::new(HashMap::new()),
+            next_id: AtomicU16::new(seed),
+            on_resolved: None,
+        }
+    }
+
+    ///Uses the DNS servers and search domains the server pushed
+    pub fn from_pushed(stack: Arc<Stack>, pushed: &PushedConfig) -> TunnelResolver {
+        TunnelResolver::new(stack, pushed.dns_servers.clone(), pushed.search_domains.clone())
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    ///Called after every successful lookup, for example with `TunnelRouter::record_resolution`
+    pub fn set_on_resolved(&mut self, on_resolved: OnResolved) {
+        self.on_resolved = Some(on_resolved);
+    }
+
+    ///A and AAAA records of `host`, after applying the search domains
+    pub fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        if self.servers.is_empty() {
+            return Err(io::Error::new(ErrorKind::NotFound, "no DNS servers were pushed"));
+        }
+        let mut last_error = io::Error::new(ErrorKind::NotFound, format!("could not resolve {}", host));
+        for name in self.candidates(host) {
+            let mut addrs = Vec::new();
+            for qtype in [TYPE_A, TYPE_AAAA].iter() {
+                match self.lookup_type(&name, *qtype) {
+                    Ok(found) => addrs.extend(found),
+                    Err(e) => last_error = e
+                }
+            }
+            if !addrs.is_empty() {
+                if let Some(on_resolved) = self.on_resolved.as_ref() {
+                    on_resolved(host, &addrs);
+                }
+                return Ok(addrs);
+            }
+        }
+        Err(last_error)
+    }
+
+    //Fully qualified names to try for `host`, in order
+    fn candidates(&self, host: &str) -> Vec<String> {
+        if host.ends_with('.') {
+            return vec![host.trim_end_matches('.').to_owned()];
+        }
+        let searched = self.search_domains.iter().map(|d| format!("{}.{}", host, d.trim_matches('.')));
+        if host.matches('.').count() < NDOTS {
+            searched.chain(std::iter::once(host.to_owned())).collect()
+        } else {
+            std::iter::once(host.to_owned()).chain(searched).collect()
+        }
+    }
+
+    fn lookup_type(&self, name: &str, qtype: u16) -> io::Result<Vec<IpAddr>> {
+        let key = (name.to_ascii_lowercase(), qtype);
+        if let Some(entry) = self.cache.lock().unwrap().get(&key) {
+            if entry.expires > Instant::now() {
+                return Ok(entry.addrs.clone());
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let query = build_query(id, name, qtype)?;
+        let mut last_error = io::Error::new(ErrorKind::TimedOut, "DNS query timed out");
+        for _ in 0..self.attempts {
+            for server in self.servers.iter() {
+                let response = match self.exchange_udp(&query, *server) {
+                    Ok(response) if truncated(&response) => self.exchange_tcp(&query, *server),
+                    r => r
+                };
+                match response.and_then(|r| parse_response(&r, id, qtype)) {
+                    Ok((addrs, ttl)) => {
+                        //An empty answer has no TTL to keep it by
+                        if !addrs.is_empty() && ttl > 0 {
+                            self.cache.lock().unwrap().insert(key, CacheEntry {
+                                addrs: addrs.clone(),
+                                expires: Instant::now() + Duration::from_secs(ttl as u64),
+                            });
+                        }
+                        return Ok(addrs);
+                    },
+                    //The name doesn't exist, other servers won't say otherwise
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Err(e),
+                    Err(e) => last_error = e
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    fn exchange_udp(&self, query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut socket = stack::UdpSocket::bind(&self.stack, 0)?;
+        socket.set_read_timeout(Some(self.timeout));
+        socket.send_to(query, server)?;
+        let mut buffer = vec![0u8; 4096];
+        loop {
+            let (size, from) = socket.recv_from(&mut buffer)?;
+            //Ignore stray datagrams and answers to other queries
+            if from == server && size >= 2 && buffer[..2] == query[..2] {
+                buffer.truncate(size);
+                return Ok(buffer);
+            }
+        }
+    }
+
+    fn exchange_tcp(&self, query: &[u8], server: SocketAddr) -> io::Result<Vec<u8>> {
+        let mut stream = stack::TcpStream::connect_timeout(&self.stack, server, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout));
+        stream.set_write_timeout(Some(self.timeout));
+        let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(query);
+        stream.write_all(&framed)?;
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len)?;
+        let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl Resolver for TunnelResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => Ok(vec![ip]),
+            Err(_) => self.lookup(host)
+        }
+    }
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    if name.split('.').any(|label| label.is_empty()) {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("empty label in {:?}", name)));
+    }
+    //Each label's length byte and the root label's add up to two more than the dotted name
+    if name.len() + 2 > MAX_NAME_LENGTH {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("name too long: {}", name)));
+    }
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+    //One question, no answer/authority/additional records
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        if label.len() > 63 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, format!("label too long in {}", name)));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+fn truncated(response: &[u8]) -> bool {
+    response.len() >= 4 && u16::from_be_bytes([response[2], response[3]]) & FLAG_TRUNCATED != 0
+}
+
+//Offset right after the (possibly compressed) name starting at `offset`
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        //A pointer ends the name
+        if len & 0xc0 == 0xc0 {
+            return Some(offset + 2);
+        }
+        offset += 1 + len;
+    }
+}
+
+//Addresses of type `qtype` in the answer section, and the lowest TTL among them
+fn parse_response(response: &[u8], id: u16, qtype: u16) -> io::Result<(Vec<IpAddr>, u32)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "malformed DNS response");
+    if response.len() < 12 || response[..2] != id.to_be_bytes() {
+        return Err(invalid());
+    }
+    let flags = u16::from_be_bytes([response[2], response[3]]);
+    match flags & 0x000f {
+        0 => {},
+        RCODE_NXDOMAIN => return Err(io::Error::new(ErrorKind::NotFound, "no such domain")),
+        rcode => return Err(io::Error::other(format!("DNS server error {}", rcode)))
+    }
+    let questions = u16::from_be_bytes([response[4], response[5]]);
+    let answers = u16::from_be_bytes([response[6], response[7]]);
+    let mut offset = 12;
+    for _ in 0..questions {
+        offset = skip_name(response, offset).ok_or_else(invalid)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        offset = skip_name(response, offset).ok_or_else(invalid)?;
+        let record = response.get(offset..offset + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let record_ttl = u32::from_be_bytes([record[4], record[5], record[6], record[7]]);
+        let rdlength = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let rdata = response.get(offset + 10..offset + 10 + rdlength).ok_or_else(invalid)?;
+        offset += 10 + rdlength;
+        //CNAMEs are skipped, servers send the records they point to along with them
+        let addr = match (rtype, rdlength) {
+            (TYPE_A, 4) if qtype == TYPE_A => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            },
+            _ => continue
+        };
+        addrs.push(addr);
+        ttl = std::cmp::min(ttl, record_ttl);
+    }
+    if addrs.is_empty() {
+        ttl = 0;
+    }
+    Ok((addrs, ttl))
+}
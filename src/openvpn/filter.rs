@@ -0,0 +1,210 @@
+//Packet filtering on the data path of an OVPNClient
+//
+//Rules see outbound packets before they reach C++ (and before NAT) and inbound packets
+//after decryption (and after NAT), so addresses are always the local ones. They're
+//checked in order: the first rule that allows or drops decides, rewrites replace the
+//packet for the rules after them, and packets no rule decided on get the default verdict.
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use super::packet::Packet;
+use super::pushed::IpPrefix;
+
+pub type FilterFn = Arc<dyn Fn(Direction, &[u8]) -> Verdict + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ///From us into the tunnel
+    Outbound,
+    ///From the tunnel to us
+    Inbound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    ///No opinion, ask the next rule
+    Continue,
+    Allow,
+    Drop,
+    ///Replace the packet and keep going
+    Rewrite(Vec<u8>),
+}
+
+///What built-in rules match on. Addresses and ports are the remote end's: the destination
+///of outbound packets, the source of inbound ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterMatch {
+    Prefix(IpPrefix),
+    ///TCP or UDP port in the inclusive range
+    Ports(u16, u16),
+    ///IP protocol number, like `packet::PROTOCOL_UDP`
+    Protocol(u8),
+    ///Every one of the matches
+    All(Vec<FilterMatch>),
+    Any,
+}
+
+impl FilterMatch {
+    fn matches(&self, direction: Direction, packet: &Packet) -> bool {
+        match self {
+            FilterMatch::Prefix(prefix) => prefix.contains(&match direction {
+                Direction::Outbound => packet.destination(),
+                Direction::Inbound => packet.source(),
+            }),
+            FilterMatch::Ports(first, last) => {
+                let port = match direction {
+                    Direction::Outbound => packet.destination_port(),
+                    Direction::Inbound => packet.source_port(),
+                };
+                port.map(|port| *first <= port && port <= *last).unwrap_or(false)
+            },
+            FilterMatch::Protocol(protocol) => packet.protocol() == *protocol,
+            FilterMatch::All(matches) => matches.iter().all(|m| m.matches(direction, packet)),
+            FilterMatch::Any => true,
+        }
+    }
+}
+
+enum RuleKind {
+    Match(FilterMatch, bool),
+    Custom(FilterFn),
+}
+
+pub struct FilterRule {
+    name: String,
+    kind: RuleKind,
+    //None means both directions
+    direction: Option<Direction>,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl FilterRule {
+    fn new(name: &str, kind: RuleKind) -> FilterRule {
+        FilterRule {
+            name: name.to_owned(),
+            kind: kind,
+            direction: None,
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    ///Drops matching packets. Packets that don't parse never match.
+    pub fn block(name: &str, matcher: FilterMatch) -> FilterRule {
+        FilterRule::new(name, RuleKind::Match(matcher, false))
+    }
+
+    ///Lets matching packets through, skipping the rules after it
+    pub fn allow(name: &str, matcher: FilterMatch) -> FilterRule {
+        FilterRule::new(name, RuleKind::Match(matcher, true))
+    }
+
+    ///Decides with `f`, which gets the raw packet
+    pub fn custom(name: &str, f: FilterFn) -> FilterRule {
+        FilterRule::new(name, RuleKind::Custom(f))
+    }
+
+    ///Applies the rule to one direction only
+    pub fn direction(mut self, direction: Direction) -> FilterRule {
+        self.direction = Some(direction);
+        self
+    }
+
+    fn check(&self, direction: Direction, packet: &[u8]) -> Verdict {
+        if self.direction.map(|d| d != direction).unwrap_or(false) {
+            return Verdict::Continue;
+        }
+        match &self.kind {
+            RuleKind::Match(matcher, allow) => {
+                match Packet::parse(packet) {
+                    Ok(parsed) if matcher.matches(direction, &parsed) => if *allow { Verdict::Allow } else { Verdict::Drop },
+                    _ => Verdict::Continue
+                }
+            },
+            RuleKind::Custom(f) => f(direction, packet),
+        }
+    }
+}
+
+///How many packets a rule decided on or rewrote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleStats {
+    pub name: String,
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+///Ordered rules plus a default verdict. Share it with `OVPNClient::set_filter` and keep a
+///clone to change rules or read counters while the client runs.
+#[derive(Default)]
+pub struct PacketFilter {
+    //Rules are checked on a copy of the list, so custom ones can change the filter
+    rules: Mutex<Vec<Arc<FilterRule>>>,
+    drop_by_default: AtomicBool,
+}
+
+impl PacketFilter {
+    ///No rules, everything allowed
+    pub fn new() -> PacketFilter {
+        PacketFilter::default()
+    }
+
+    pub fn add_rule(&self, rule: FilterRule) {
+        self.rules.lock().unwrap().push(Arc::new(rule));
+    }
+
+    ///Removes every rule called `name`, returning whether there was any
+    pub fn remove_rule(&self, name: &str) -> bool {
+        let mut rules = self.rules.lock().unwrap();
+        let before = rules.len();
+        rules.retain(|r| r.name != name);
+        rules.len() != before
+    }
+
+    ///Drops packets no rule allowed, like a kill switch that only lets listed traffic out
+    pub fn set_drop_by_default(&self, drop: bool) {
+        self.drop_by_default.store(drop, Ordering::Relaxed);
+    }
+
+    ///Runs the rules over `packet`. Returns `Allow`, `Drop`, or `Rewrite` with the packet
+    ///to send instead.
+    pub fn check(&self, direction: Direction, packet: &[u8]) -> Verdict {
+        let rules = self.rules.lock().unwrap().clone();
+        let mut rewritten: Option<Vec<u8>> = None;
+        let mut allowed = false;
+        for rule in rules.iter() {
+            let current = rewritten.as_deref().unwrap_or(packet);
+            let verdict = rule.check(direction, current);
+            if verdict != Verdict::Continue {
+                rule.packets.fetch_add(1, Ordering::Relaxed);
+                rule.bytes.fetch_add(current.len() as u64, Ordering::Relaxed);
+            }
+            match verdict {
+                Verdict::Continue => {},
+                Verdict::Rewrite(packet) => rewritten = Some(packet),
+                Verdict::Drop => return Verdict::Drop,
+                Verdict::Allow => {
+                    allowed = true;
+                    break;
+                },
+            }
+        }
+        if !allowed && self.drop_by_default.load(Ordering::Relaxed) {
+            return Verdict::Drop;
+        }
+        match rewritten {
+            Some(packet) => Verdict::Rewrite(packet),
+            None => Verdict::Allow
+        }
+    }
+
+    pub fn stats(&self) -> Vec<RuleStats> {
+        self.rules.lock().unwrap().iter()
+            .map(|r| RuleStats {
+                name: r.name.clone(),
+                packets: r.packets.load(Ordering::Relaxed),
+                bytes: r.bytes.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
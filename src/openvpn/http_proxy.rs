@@ -0,0 +1,255 @@
+//Local HTTP/1.1 forward proxy whose connections go through the tunnel (`http-proxy` feature)
+//
+//CONNECT requests (TLS) are tunneled as-is. Plain `http://` requests are forwarded
+//with the request line rewritten to origin form, one request per connection. Each
+//server is bound to one Stack, so running several profiles side by side means one
+//listener port per profile. Destinations the stack's route table keeps off the tunnel
+//are connected to directly. Hosts are resolved with the server's Resolver.
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use base64::Engine;
+use super::dns::{self, Resolver, SystemResolver};
+use super::stack::{self, Stack};
+
+//Longest request head we accept
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+//Headers meant for us, not for the origin server (RFC 2616 13.5.1), along with the ones
+//the request's `Connection` header names
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "proxy-connection",
+    "te", "trailer", "transfer-encoding", "upgrade",
+];
+
+pub struct HttpProxyServer {
+    listener: std::net::TcpListener,
+    stack: Arc<Stack>,
+    resolver: Arc<dyn Resolver>,
+    //`username:password` clients must send as Basic proxy credentials
+    credentials: Option<Vec<u8>>,
+}
+
+impl HttpProxyServer {
+    pub fn bind(stack: Arc<Stack>, addr: SocketAddr) -> io::Result<HttpProxyServer> {
+        Ok(HttpProxyServer {
+            listener: std::net::TcpListener::bind(addr)?,
+            stack: stack,
+            resolver: Arc::new(SystemResolver),
+            credentials: None,
+        })
+    }
+
+    ///Requires clients to send Basic proxy credentials
+    pub fn with_credentials(mut self, username: &str, password: &str) -> HttpProxyServer {
+        self.credentials = Some(format!("{}:{}", username, password).into_bytes());
+        self
+    }
+
+    ///Resolves the hosts clients ask for with `resolver` instead of the system resolver
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> HttpProxyServer {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    ///Accepts clients forever, each on its own thread
+    pub fn run(&self) -> io::Result<()> {
+        for client in self.listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(_) => continue
+            };
+            let stack = self.stack.clone();
+            let resolver = self.resolver.clone();
+            let credentials = self.credentials.clone();
+            std::thread::spawn(move || {
+                let _ = handle_client(client, stack, resolver, credentials);
+            });
+        }
+        Ok(())
+    }
+}
+
+struct RequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    //Whether a header is hop-by-hop, by name or because `Connection` lists it
+    fn is_hop_by_hop(&self, name: &str) -> bool {
+        HOP_BY_HOP_HEADERS.iter().any(|h| name.eq_ignore_ascii_case(h))
+            || self.headers.iter()
+                .filter(|(n, _)| n.eq_ignore_ascii_case("Connection"))
+                .flat_map(|(_, v)| v.split(','))
+                .any(|token| token.trim().eq_ignore_ascii_case(name))
+    }
+
+    //`Proxy-Authorization: Basic <base64 of username:password>`, the scheme in any case
+    fn has_credentials(&self, credentials: &[u8]) -> bool {
+        let value = match self.header("Proxy-Authorization") {
+            Some(value) => value.trim(),
+            None => return false
+        };
+        let (scheme, encoded) = match value.find(' ') {
+            Some(i) => (&value[..i], value[i + 1..].trim()),
+            None => return false
+        };
+        scheme.eq_ignore_ascii_case("Basic")
+            && base64::engine::general_purpose::STANDARD.decode(encoded).map(|decoded| decoded == credentials).unwrap_or(false)
+    }
+}
+
+//Reads up to the end of the head. Returns the head and whatever body bytes came with it.
+fn read_head(client: &mut std::net::TcpStream) -> io::Result<(RequestHead, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let end = loop {
+        if let Some(i) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+        if buffer.len() > MAX_HEAD_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "request head too large"));
+        }
+        let n = client.read(&mut chunk)?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed before request head"));
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buffer[..end]).into_owned();
+    let mut lines = head.split("\r\n");
+    let request_line: Vec<&str> = lines.next().unwrap_or("").split_whitespace().collect();
+    if request_line.len() != 3 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "malformed request line"));
+    }
+    let headers = lines
+        .filter_map(|line| {
+            let i = line.find(':')?;
+            Some((line[..i].trim().to_owned(), line[i + 1..].trim().to_owned()))
+        })
+        .collect();
+    let head = RequestHead {
+        method: request_line[0].to_owned(),
+        target: request_line[1].to_owned(),
+        version: request_line[2].to_owned(),
+        headers: headers,
+    };
+    Ok((head, buffer[end + 4..].to_vec()))
+}
+
+fn respond(client: &mut std::net::TcpStream, status: &str) -> io::Result<()> {
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\nContent-Length: 0\r\n", status);
+    if status.starts_with("407") {
+        response.push_str("Proxy-Authenticate: Basic realm=\"openvpn\"\r\n");
+    }
+    response.push_str("\r\n");
+    client.write_all(response.as_bytes())
+}
+
+fn error_status(e: &io::Error) -> &'static str {
+    match e.kind() {
+        ErrorKind::TimedOut => "504 Gateway Timeout",
+        _ => "502 Bad Gateway"
+    }
+}
+
+//Splits `host:port`, `[v6]:port` or `host`
+fn split_authority(authority: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let invalid = || io::Error::new(ErrorKind::InvalidInput, format!("invalid authority {}", authority));
+    if let Some(rest) = authority.strip_prefix('[') {
+        let end = rest.find(']').ok_or_else(invalid)?;
+        let port = match rest[end + 1..].strip_prefix(':') {
+            Some(port) => port.parse().map_err(|_| invalid())?,
+            None => default_port
+        };
+        return Ok((rest[..end].to_owned(), port));
+    }
+    match authority.rfind(':') {
+        Some(i) => Ok((authority[..i].to_owned(), authority[i + 1..].parse().map_err(|_| invalid())?)),
+        None => Ok((authority.to_owned(), default_port))
+    }
+}
+
+fn handle_client(mut client: std::net::TcpStream, stack: Arc<Stack>, resolver: Arc<dyn Resolver>, credentials: Option<Vec<u8>>) -> io::Result<()> {
+    let (head, body) = read_head(&mut client)?;
+    if let Some(credentials) = credentials {
+        if !head.has_credentials(&credentials) {
+            return respond(&mut client, "407 Proxy Authentication Required");
+        }
+    }
+    if head.method.eq_ignore_ascii_case("CONNECT") {
+        connect(client, &stack, &*resolver, &head, body)
+    } else {
+        forward(client, &stack, &*resolver, &head, body)
+    }
+}
+
+fn connect(mut client: std::net::TcpStream, stack: &Stack, resolver: &dyn Resolver, head: &RequestHead, body: Vec<u8>) -> io::Result<()> {
+    let (host, port) = match split_authority(&head.target, 443) {
+        Ok(authority) => authority,
+        Err(_) => return respond(&mut client, "400 Bad Request")
+    };
+    let mut remote = match dns::resolve_addr(resolver, &host, port).and_then(|addr| stack::dial(stack, addr)) {
+        Ok(remote) => remote,
+        Err(e) => return respond(&mut client, error_status(&e))
+    };
+    client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")?;
+    //Clients may pipeline the TLS hello right after the CONNECT head
+    if !body.is_empty() {
+        remote.write_all(&body)?;
+    }
+    stack::relay(client, remote)
+}
+
+fn forward(mut client: std::net::TcpStream, stack: &Stack, resolver: &dyn Resolver, head: &RequestHead, body: Vec<u8>) -> io::Result<()> {
+    let scheme = "http://";
+    let rest = match head.target.get(..scheme.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(scheme) => &head.target[scheme.len()..],
+        //https:// must come through CONNECT, and origin-form targets mean we're not being used as a proxy
+        _ => return respond(&mut client, "400 Bad Request")
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/")
+    };
+    let (host, port) = match split_authority(authority, 80) {
+        Ok(authority) => authority,
+        Err(_) => return respond(&mut client, "400 Bad Request")
+    };
+    let mut remote = match dns::resolve_addr(resolver, &host, port).and_then(|addr| stack::dial(stack, addr)) {
+        Ok(remote) => remote,
+        Err(e) => return respond(&mut client, error_status(&e))
+    };
+    let mut request = format!("{} {} {}\r\n", head.method, path, head.version);
+    if head.header("Host").is_none() {
+        request.push_str(&format!("Host: {}\r\n", authority));
+    }
+    for (name, value) in head.headers.iter() {
+        if !head.is_hop_by_hop(name) {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+    }
+    //The body is relayed as it came, so the next hop needs the same framing
+    if let Some(transfer_encoding) = head.header("Transfer-Encoding") {
+        request.push_str(&format!("Transfer-Encoding: {}\r\n", transfer_encoding));
+    }
+    //One request per connection keeps us from having to parse responses
+    request.push_str("Connection: close\r\n\r\n");
+    remote.write_all(request.as_bytes())?;
+    if !body.is_empty() {
+        remote.write_all(&body)?;
+    }
+    stack::relay(client, remote)
+}
@@ -0,0 +1,337 @@
+//Runs many OVPNClients side by side, one per profile, under string ids
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::compression::CompressionPolicy;
+use super::proxy::ProxyConfig;
+use super::openvpn::{OVPNClient, OVPNCreationError, OVPNEvent, OnVpnEvent, OnVpnLog, OpenVpnConnectionError, OpenVpnDisconnectionError};
+#[cfg(feature = "mock")]
+use super::mock::MockConfig;
+use super::stats::OVPNStats;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type OnTunnelEvent = Arc<Mutex<dyn Fn(TunnelEvent) + Send + Sync>>;
+
+///An OpenVPN event, tagged with the tunnel that emitted it
+#[derive(Debug, Clone)]
+pub struct TunnelEvent {
+    pub tunnel: String,
+    pub event: OVPNEvent,
+}
+
+impl std::fmt::Display for TunnelEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.tunnel, self.event)
+    }
+}
+
+///Where a tunnel is, as far as its events tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TunnelState {
+    Stopped,
+    Connecting,
+    Connected,
+    Reconnecting,
+    Disconnected,
+    ///A fatal event arrived, with its name and info
+    Failed(String),
+}
+
+impl TunnelState {
+    fn is_running(&self) -> bool {
+        matches!(self, TunnelState::Connecting | TunnelState::Connected | TunnelState::Reconnecting)
+    }
+}
+
+///What's needed to create a tunnel's OVPNClient
+pub struct TunnelConfig {
+    pub profile: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    ///Addresses the tunnel addresses are rewritten to, see `OVPNClient::new`
+    pub replacement_ipv4: Option<Ipv4Addr>,
+    pub replacement_ipv6: Option<Ipv6Addr>,
+    pub on_vpn_log: Option<OnVpnLog>,
+    pub compression: CompressionPolicy,
+    pub proxy: Option<ProxyConfig>,
+    ///Runs the tunnel on the mock backend instead of the C++ library
+    #[cfg(feature = "mock")]
+    pub mock: Option<MockConfig>,
+}
+
+impl TunnelConfig {
+    pub fn new(profile: String) -> TunnelConfig {
+        TunnelConfig {
+            profile: profile,
+            username: None,
+            password: None,
+            replacement_ipv4: None,
+            replacement_ipv6: None,
+            on_vpn_log: None,
+            compression: CompressionPolicy::default(),
+            proxy: None,
+            #[cfg(feature = "mock")]
+            mock: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ManagerLimits {
+    ///Most tunnels the manager holds, running or not
+    pub max_tunnels: Option<usize>,
+    ///Most tunnels connecting or connected at the same time
+    pub max_running: Option<usize>,
+}
+
+#[derive(Debug)]
+pub enum ManagerError {
+    AlreadyExists(String),
+    NotFound(String),
+    LimitReached(String),
+    ShuttingDown,
+    Creation(OVPNCreationError),
+    Connection(String),
+    Disconnection(String),
+}
+
+impl std::fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ManagerError::AlreadyExists(id) => write!(f, "tunnel {} already exists", id),
+            ManagerError::NotFound(id) => write!(f, "no tunnel {}", id),
+            ManagerError::LimitReached(s) => write!(f, "limit reached: {}", s),
+            ManagerError::ShuttingDown => write!(f, "manager is shutting down"),
+            ManagerError::Creation(e) => write!(f, "could not create client: {:?}", e),
+            ManagerError::Connection(s) => write!(f, "could not connect: {}", s),
+            ManagerError::Disconnection(s) => write!(f, "could not disconnect: {}", s),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Tunnel {
+    client: Arc<Mutex<OVPNClient>>,
+    state: Arc<Mutex<TunnelState>>,
+}
+
+pub struct TunnelManager {
+    tunnels: Mutex<HashMap<String, Tunnel>>,
+    limits: ManagerLimits,
+    on_event: Arc<Mutex<Option<OnTunnelEvent>>>,
+    shutting_down: AtomicBool,
+}
+
+impl TunnelManager {
+    pub fn new(limits: ManagerLimits) -> TunnelManager {
+        TunnelManager {
+            tunnels: Mutex::new(HashMap::new()),
+            limits: limits,
+            on_event: Arc::new(Mutex::new(None)),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    ///Receives the events of every tunnel. Without it, events are printed.
+    pub fn set_on_event(&self, on_event: OnTunnelEvent) {
+        *self.on_event.lock().unwrap() = Some(on_event);
+    }
+
+    ///Creates the client for tunnel `id`, without connecting it
+    pub fn add(&self, id: &str, config: TunnelConfig) -> Result<(), ManagerError> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Err(ManagerError::ShuttingDown);
+        }
+        let mut tunnels = self.tunnels.lock().unwrap();
+        if tunnels.contains_key(id) {
+            return Err(ManagerError::AlreadyExists(id.to_owned()));
+        }
+        if let Some(max_tunnels) = self.limits.max_tunnels {
+            if tunnels.len() >= max_tunnels {
+                return Err(ManagerError::LimitReached(format!("at most {} tunnels", max_tunnels)));
+            }
+        }
+        let state = Arc::new(Mutex::new(TunnelState::Stopped));
+        let on_vpn_event: OnVpnEvent = {
+            let tunnel = id.to_owned();
+            let state = state.clone();
+            let on_event = self.on_event.clone();
+            Arc::new(Mutex::new(move |event: OVPNEvent| {
+                update_state(&mut state.lock().unwrap(), &event);
+                let event = TunnelEvent {
+                    tunnel: tunnel.clone(),
+                    event: event,
+                };
+                match on_event.lock().unwrap().as_ref() {
+                    Some(on_event) => (on_event.lock().unwrap())(event),
+                    None => println!("EVENT: {}", event)
+                }
+            }))
+        };
+        let client = create_client(config, on_vpn_event).map_err(ManagerError::Creation)?;
+        tunnels.insert(id.to_owned(), Tunnel {
+            client: Arc::new(Mutex::new(client)),
+            state: state,
+        });
+        Ok(())
+    }
+
+    ///Stops and drops tunnel `id`
+    pub fn remove(&self, id: &str) -> Result<(), ManagerError> {
+        let tunnel = self.tunnels.lock().unwrap().remove(id)
+            .ok_or_else(|| ManagerError::NotFound(id.to_owned()))?;
+        if tunnel.state.lock().unwrap().is_running() {
+            let _ = tunnel.client.lock().unwrap().disconnect();
+        }
+        Ok(())
+    }
+
+    pub fn start(&self, id: &str) -> Result<(), ManagerError> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return Err(ManagerError::ShuttingDown);
+        }
+        let (client, state) = {
+            let tunnels = self.tunnels.lock().unwrap();
+            let tunnel = tunnels.get(id).ok_or_else(|| ManagerError::NotFound(id.to_owned()))?;
+            if tunnel.state.lock().unwrap().is_running() {
+                return Ok(());
+            }
+            if let Some(max_running) = self.limits.max_running {
+                let running = tunnels.values().filter(|t| t.state.lock().unwrap().is_running()).count();
+                if running >= max_running {
+                    return Err(ManagerError::LimitReached(format!("at most {} running tunnels", max_running)));
+                }
+            }
+            //Counts as running from here, so the limit holds while we connect
+            *tunnel.state.lock().unwrap() = TunnelState::Connecting;
+            (tunnel.client.clone(), tunnel.state.clone())
+        };
+        //Connecting can block, the other tunnels stay usable meanwhile
+        let r = client.lock().unwrap().connect();
+        if let Err(OpenVpnConnectionError::Unknown(e)) = r {
+            *state.lock().unwrap() = TunnelState::Failed(e.clone());
+            return Err(ManagerError::Connection(e));
+        }
+        Ok(())
+    }
+
+    pub fn stop(&self, id: &str) -> Result<(), ManagerError> {
+        //Cloned out, so disconnecting doesn't hold up the other tunnels
+        let tunnel = self.tunnels.lock().unwrap().get(id).cloned()
+            .ok_or_else(|| ManagerError::NotFound(id.to_owned()))?;
+        let r = tunnel.client.lock().unwrap().disconnect();
+        if let Err(OpenVpnDisconnectionError::Unknown(e)) = r {
+            return Err(ManagerError::Disconnection(e));
+        }
+        *tunnel.state.lock().unwrap() = TunnelState::Stopped;
+        Ok(())
+    }
+
+    ///Starts every tunnel, returning the ones that failed
+    pub fn start_all(&self) -> Vec<(String, ManagerError)> {
+        self.ids().into_iter()
+            .filter_map(|id| self.start(&id).err().map(|e| (id, e)))
+            .collect()
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.tunnels.lock().unwrap().keys().cloned().collect()
+    }
+
+    ///The client of tunnel `id`, to send and receive through it
+    pub fn client(&self, id: &str) -> Option<Arc<Mutex<OVPNClient>>> {
+        self.tunnels.lock().unwrap().get(id).map(|t| t.client.clone())
+    }
+
+    pub fn state(&self, id: &str) -> Option<TunnelState> {
+        self.tunnels.lock().unwrap().get(id).map(|t| t.state.lock().unwrap().clone())
+    }
+
+    pub fn stats(&self) -> HashMap<String, OVPNStats> {
+        self.tunnels.lock().unwrap().iter()
+            .map(|(id, t)| (id.clone(), t.client.lock().unwrap().stats()))
+            .collect()
+    }
+
+    ///Sum of the stats of every tunnel
+    pub fn total_stats(&self) -> OVPNStats {
+        self.stats().values().fold(OVPNStats::default(), |total, s| total + *s)
+    }
+
+    ///Disconnects every tunnel, waits up to `timeout` for them to report DISCONNECTED, and
+    ///drops them. No tunnel can be added or started afterwards.
+    pub fn shutdown(&self, timeout: Duration) -> Vec<(String, ManagerError)> {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        let mut errors = Vec::new();
+        let tunnels: Vec<(String, Tunnel)> = self.tunnels.lock().unwrap().drain().collect();
+        for (id, tunnel) in tunnels.iter() {
+            if !tunnel.state.lock().unwrap().is_running() {
+                continue;
+            }
+            if let Err(OpenVpnDisconnectionError::Unknown(e)) = tunnel.client.lock().unwrap().disconnect() {
+                errors.push((id.clone(), ManagerError::Disconnection(e)));
+            }
+        }
+        let deadline = std::time::Instant::now() + timeout;
+        while std::time::Instant::now() < deadline
+            && tunnels.iter().any(|(_, t)| t.state.lock().unwrap().is_running()) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        errors
+    }
+}
+
+impl Drop for TunnelManager {
+    fn drop(&mut self) {
+        self.shutdown(DEFAULT_SHUTDOWN_TIMEOUT);
+    }
+}
+
+fn update_state(state: &mut TunnelState, event: &OVPNEvent) {
+    if event.is_fatal() {
+        *state = TunnelState::Failed(format!("{}: {}", event.name, event.info));
+        return;
+    }
+    match event.name.as_str() {
+        "CONNECTED" => *state = TunnelState::Connected,
+        "RECONNECTING" => *state = TunnelState::Reconnecting,
+        //Keep the reason of a failure around
+        "DISCONNECTED" if !matches!(state, TunnelState::Failed(_)) => *state = TunnelState::Disconnected,
+        _ => {}
+    }
+}
+
+//The mock backend when the config asks for it, the C++ library otherwise
+#[allow(unused_mut)]
+fn create_client(mut config: TunnelConfig, on_vpn_event: OnVpnEvent) -> Result<OVPNClient, OVPNCreationError> {
+    #[cfg(feature = "mock")]
+    {
+        if let Some(mock) = config.mock.take() {
+            return Ok(OVPNClient::mock(mock, config.on_vpn_log, Some(on_vpn_event)).0);
+        }
+    }
+    #[cfg(feature = "native")]
+    {
+        OVPNClient::new(
+            config.profile,
+            config.username.as_deref(),
+            config.password.as_deref(),
+            None,
+            None,
+            config.on_vpn_log,
+            Some(on_vpn_event),
+            config.replacement_ipv4.as_ref(),
+            config.replacement_ipv6.as_ref(),
+            config.compression,
+            config.proxy.as_ref(),
+        )
+    }
+    #[cfg(not(feature = "native"))]
+    {
+        let _ = (config, on_vpn_event);
+        Err(OVPNCreationError::BackendUnavailable("built without the native feature".into()))
+    }
+}
@@ -0,0 +1,211 @@
+//Pure-Rust backend for tests (`mock` feature)
+//
+//Needs neither the C++ library nor a server: connecting plays a script of log lines and
+//events (the default one pushes addresses and ends in CONNECTED), sent packets are kept
+//and looped back, and a MockHandle injects packets, events and failures from the test.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::backend::{Backend, EventSink};
+use super::openvpn::OVPNEvent;
+
+#[derive(Debug, Clone)]
+pub enum MockStep {
+    Sleep(Duration),
+    Log(String),
+    ///`CONNECTED` lets packets through, `DISCONNECTED` and fatal events stop them
+    Event{name: String, info: String, error: bool, fatal: bool},
+}
+
+impl MockStep {
+    pub fn event(name: &str, info: &str) -> MockStep {
+        MockStep::Event{name: name.to_owned(), info: info.to_owned(), error: false, fatal: false}
+    }
+
+    pub fn fatal(name: &str, info: &str) -> MockStep {
+        MockStep::Event{name: name.to_owned(), info: info.to_owned(), error: true, fatal: true}
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    ///Played on a thread by `connect`
+    pub connect_script: Vec<MockStep>,
+    ///Sent packets are queued to be received back
+    pub loopback: bool,
+    ///Every `receive` takes this long, like a slow link
+    pub read_delay: Option<Duration>,
+    ///`connect` fails right away with it
+    pub connect_error: Option<String>,
+}
+
+impl Default for MockConfig {
+    ///Connects in a few steps, pushing 10.8.0.2/24 with the gateway 10.8.0.1 as DNS server
+    fn default() -> MockConfig {
+        MockConfig {
+            connect_script: vec![
+                MockStep::event("RESOLVE", ""),
+                MockStep::event("CONNECTING", ""),
+                MockStep::Log("Received control message: PUSH_REPLY,route-gateway 10.8.0.1,topology subnet,ping 10,ping-restart 120,ifconfig 10.8.0.2 255.255.255.0,dhcp-option DNS 10.8.0.1,tun-mtu 1500".into()),
+                MockStep::event("ASSIGN_IP", ""),
+                MockStep::event("CONNECTED", "user@mock:1194 (127.0.0.1) via UDPv4 on tun/10.8.0.2/ gw=[10.8.0.1/]"),
+            ],
+            loopback: true,
+            read_delay: None,
+            connect_error: None,
+        }
+    }
+}
+
+impl MockConfig {
+    ///The server rejects our credentials
+    pub fn auth_failure() -> MockConfig {
+        MockConfig {
+            connect_script: vec![
+                MockStep::event("RESOLVE", ""),
+                MockStep::event("CONNECTING", ""),
+                MockStep::Log("AUTH_FAILED".into()),
+                MockStep::fatal("AUTH_FAILED", "mock: wrong username or password"),
+                MockStep::event("DISCONNECTED", ""),
+            ],
+            ..MockConfig::default()
+        }
+    }
+}
+
+#[derive(Default)]
+struct MockState {
+    connected: bool,
+    incoming: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+}
+
+struct MockShared {
+    config: MockConfig,
+    events: EventSink,
+    state: Mutex<MockState>,
+    //Bumped by connect and disconnect, so a script from an older connect stops playing
+    generation: AtomicUsize,
+}
+
+impl MockShared {
+    fn event(&self, event: OVPNEvent) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if event.name == "CONNECTED" {
+                state.connected = true;
+            } else if event.name == "DISCONNECTED" || event.is_fatal() {
+                state.connected = false;
+            }
+        }
+        self.events.event(event);
+    }
+}
+
+pub struct MockBackend {
+    shared: Arc<MockShared>,
+}
+
+///Drives a MockBackend from the test side
+#[derive(Clone)]
+pub struct MockHandle {
+    shared: Arc<MockShared>,
+}
+
+impl MockBackend {
+    pub fn new(config: MockConfig, events: EventSink) -> (MockBackend, MockHandle) {
+        let shared = Arc::new(MockShared {
+            config: config,
+            events: events,
+            state: Mutex::new(MockState::default()),
+            generation: AtomicUsize::new(0),
+        });
+        (MockBackend{shared: shared.clone()}, MockHandle{shared: shared})
+    }
+}
+
+impl Backend for MockBackend {
+    fn connect(&self) -> Result<(), String> {
+        if let Some(e) = &self.shared.config.connect_error {
+            return Err(e.clone());
+        }
+        let generation = self.shared.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let shared = self.shared.clone();
+        std::thread::spawn(move || {
+            for step in shared.config.connect_script.iter() {
+                if shared.generation.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                match step {
+                    MockStep::Sleep(duration) => std::thread::sleep(*duration),
+                    MockStep::Log(line) => shared.events.log(line.clone()),
+                    MockStep::Event{name, info, error, fatal} => shared.event(OVPNEvent::new(name, info, *error, *fatal)),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn disconnect(&self) -> Result<(), String> {
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        self.shared.event(OVPNEvent::new("DISCONNECTED", "", false, false));
+        Ok(())
+    }
+
+    fn send(&self, packet: &[u8]) -> Result<(), String> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.connected {
+            return Err("mock: not connected".into());
+        }
+        state.sent.push(packet.to_vec());
+        if self.shared.config.loopback {
+            state.incoming.push_back(packet.to_vec());
+        }
+        Ok(())
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> Result<Option<usize>, String> {
+        if let Some(read_delay) = self.shared.config.read_delay {
+            std::thread::sleep(read_delay);
+        }
+        match self.shared.state.lock().unwrap().incoming.pop_front() {
+            Some(packet) => {
+                let len = std::cmp::min(packet.len(), buffer.len());
+                buffer[..len].copy_from_slice(&packet[..len]);
+                Ok(Some(len))
+            },
+            None => Ok(None)
+        }
+    }
+}
+
+impl MockHandle {
+    ///Queues `packet` as if it came from the server
+    pub fn inject(&self, packet: &[u8]) {
+        self.shared.state.lock().unwrap().incoming.push_back(packet.to_vec());
+    }
+
+    ///Packets that reached the backend, after the client's filter and NAT
+    pub fn sent(&self) -> Vec<Vec<u8>> {
+        self.shared.state.lock().unwrap().sent.clone()
+    }
+
+    pub fn emit_event(&self, event: OVPNEvent) {
+        self.shared.event(event);
+    }
+
+    pub fn log(&self, line: &str) {
+        self.shared.events.log(line.to_owned());
+    }
+
+    ///The server goes away: sends start failing and a DISCONNECTED event is emitted
+    pub fn drop_connection(&self) {
+        self.shared.generation.fetch_add(1, Ordering::SeqCst);
+        self.shared.event(OVPNEvent::new("DISCONNECTED", "mock: connection dropped", true, false));
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.shared.state.lock().unwrap().connected
+    }
+}
@@ -1,4 +1,51 @@
 //mod interface;
 //mod openvpn;
 mod openvpn;
-pub use openvpn::*;
\ No newline at end of file
+pub mod backend;
+pub mod capture;
+pub mod cli;
+pub mod compression;
+pub mod filter;
+#[cfg(feature = "native")]
+pub mod native;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod nat;
+pub mod packet;
+#[cfg(feature = "pki")]
+pub mod pki;
+pub mod proxy;
+pub mod pushed;
+pub mod stats;
+#[cfg(feature = "validate")]
+pub mod validate;
+pub mod manager;
+pub mod router;
+pub mod routes;
+#[cfg(feature = "stack")]
+pub mod stack;
+#[cfg(feature = "stack")]
+pub mod dns;
+#[cfg(feature = "socks5")]
+pub mod socks5;
+#[cfg(feature = "http-proxy")]
+pub mod http_proxy;
+#[cfg(all(feature = "tun", target_os = "linux"))]
+pub mod tun;
+pub use openvpn::*;
+pub use backend::{Backend, EventSink};
+pub use capture::{CaptureFormat, CaptureLimits, PacketCapture};
+pub use compression::{CompressionAlgorithm, CompressionPolicy};
+pub use proxy::{ProxyConfig, ProxyCredentials, ProxyServer};
+pub use filter::{Direction, FilterMatch, FilterRule, PacketFilter, Verdict};
+#[cfg(feature = "native")]
+pub use native::{crypto_backend, Callbacks, CryptoBackend, NativeBackend};
+#[cfg(feature = "mock")]
+pub use mock::{MockConfig, MockHandle, MockStep};
+pub use nat::{Nat, NatMapping};
+pub use packet::{Packet, PacketBuilder, PacketError};
+pub use pushed::{IpPrefix, PushedConfig};
+pub use stats::OVPNStats;
+pub use manager::{TunnelManager, TunnelConfig, TunnelState, ManagerLimits};
+pub use routes::RouteTable;
+pub use router::{TunnelRouter, RouteRule, RouteMatch, Strategy};
@@ -0,0 +1,185 @@
+//Address translation between the addresses a local stack uses and the ones the server
+//assigned us on the tunnel
+//
+//Lets a userspace stack keep fixed addresses (like 10.255.0.2) no matter what the server
+//hands out. Outbound packets get their source rewritten from the local address to the
+//tunnel one, inbound packets their destination the other way around. IPv4 header,
+//TCP, UDP and ICMPv6 checksums are fixed up incrementally (RFC 1624), and so are the
+//packets quoted inside ICMP errors, so errors about our own packets still reach the
+//socket that sent them. The transport checksums of quoted packets are left as they are.
+use std::net::{Ipv4Addr, Ipv6Addr};
+use super::packet::{adjust_checksum, Packet, Transport};
+use super::pushed::PushedConfig;
+
+///A local address and the tunnel address it stands for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatMapping<A> {
+    pub local: A,
+    pub tunnel: A,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nat {
+    enabled: bool,
+    ipv4: Option<NatMapping<Ipv4Addr>>,
+    ipv6: Option<NatMapping<Ipv6Addr>>,
+}
+
+impl Default for Nat {
+    fn default() -> Nat {
+        Nat::new()
+    }
+}
+
+impl Nat {
+    ///Enabled, but with nothing to map yet
+    pub fn new() -> Nat {
+        Nat {
+            enabled: true,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    ///Maps `local_ipv4`/`local_ipv6` to the addresses the server pushed. Families the server
+    ///assigned no address for are left unmapped.
+    pub fn from_pushed(local_ipv4: Option<Ipv4Addr>, local_ipv6: Option<Ipv6Addr>, pushed: &PushedConfig) -> Nat {
+        let mut nat = Nat::new();
+        if let (Some(local), Some(std::net::IpAddr::V4(tunnel))) = (local_ipv4, pushed.ipv4.map(|p| p.addr)) {
+            nat.map_ipv4(local, tunnel);
+        }
+        if let (Some(local), Some(std::net::IpAddr::V6(tunnel))) = (local_ipv6, pushed.ipv6.map(|p| p.addr)) {
+            nat.map_ipv6(local, tunnel);
+        }
+        nat
+    }
+
+    pub fn map_ipv4(&mut self, local: Ipv4Addr, tunnel: Ipv4Addr) {
+        self.ipv4 = Some(NatMapping {
+            local: local,
+            tunnel: tunnel,
+        });
+    }
+
+    pub fn map_ipv6(&mut self, local: Ipv6Addr, tunnel: Ipv6Addr) {
+        self.ipv6 = Some(NatMapping {
+            local: local,
+            tunnel: tunnel,
+        });
+    }
+
+    pub fn ipv4(&self) -> Option<NatMapping<Ipv4Addr>> {
+        self.ipv4
+    }
+
+    pub fn ipv6(&self) -> Option<NatMapping<Ipv6Addr>> {
+        self.ipv6
+    }
+
+    ///A disabled Nat leaves every packet untouched
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    ///Rewrites the source of a packet about to be sent, local to tunnel address.
+    ///Returns whether the packet changed.
+    pub fn outbound(&self, packet: &mut [u8]) -> bool {
+        match self.addresses(packet) {
+            Some((local, tunnel)) => rewrite(packet, &local, &tunnel, true),
+            None => false
+        }
+    }
+
+    ///Rewrites the destination of a received packet, tunnel to local address.
+    ///Returns whether the packet changed.
+    pub fn inbound(&self, packet: &mut [u8]) -> bool {
+        match self.addresses(packet) {
+            Some((local, tunnel)) => rewrite(packet, &tunnel, &local, false),
+            None => false
+        }
+    }
+
+    //Local and tunnel address, as bytes, for the packet's IP version
+    fn addresses(&self, packet: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+        if !self.enabled {
+            return None;
+        }
+        match packet.first()? >> 4 {
+            4 => self.ipv4.map(|m| (m.local.octets().to_vec(), m.tunnel.octets().to_vec())),
+            6 => self.ipv6.map(|m| (m.local.octets().to_vec(), m.tunnel.octets().to_vec())),
+            _ => None
+        }
+    }
+}
+
+//Replaces `from` with `to` in the source (or destination) address and fixes the checksums.
+//Packets that don't parse are left alone.
+fn rewrite(packet: &mut [u8], from: &[u8], to: &[u8], source: bool) -> bool {
+    let (v6, transport_offset, transport) = match Packet::parse(packet) {
+        Ok(parsed) => (parsed.is_ipv6(), parsed.transport_offset(), parsed.transport().copied()),
+        Err(_) => return false
+    };
+    let (address, header_checksum) = match (v6, source) {
+        (false, true) => (12, Some(10)),
+        (false, false) => (16, Some(10)),
+        (true, true) => (8, None),
+        (true, false) => (24, None),
+    };
+    if &packet[address..address + from.len()] != from {
+        return false;
+    }
+    packet[address..address + to.len()].copy_from_slice(to);
+    if let Some(at) = header_checksum {
+        adjust_checksum(packet, at, from, to);
+    }
+    let offset = match transport_offset {
+        Some(offset) => offset,
+        None => return true
+    };
+    match transport {
+        Some(Transport::Tcp(_)) => adjust_checksum(packet, offset + 16, from, to),
+        //Zero means no checksum, which IPv4 allows
+        Some(Transport::Udp(udp)) if udp.checksum != 0 || v6 => {
+            adjust_checksum(packet, offset + 6, from, to);
+            if packet[offset + 6..offset + 8] == [0, 0] {
+                packet[offset + 6..offset + 8].copy_from_slice(&[0xff, 0xff]);
+            }
+        },
+        Some(Transport::Icmp(icmp)) if icmp.is_error(false) => rewrite_quoted(packet, offset, from, to, !source),
+        Some(Transport::Icmpv6(icmp)) => {
+            if icmp.is_error(true) {
+                rewrite_quoted(packet, offset, from, to, !source);
+            }
+            //ICMPv6 checksums cover the pseudo header
+            adjust_checksum(packet, offset + 2, from, to);
+        },
+        _ => {}
+    }
+    true
+}
+
+//ICMP errors quote the packet that caused them, which travelled the other way, so the
+//address to rewrite is the opposite one
+fn rewrite_quoted(packet: &mut [u8], icmp: usize, from: &[u8], to: &[u8], source: bool) {
+    let quoted = icmp + 8;
+    //Span of the quoted header covering its addresses (and checksum), and the address to rewrite
+    let (start, end, address, header_checksum) = match packet.get(quoted).map(|b| b >> 4) {
+        Some(4) if packet.len() >= quoted + 20 => (quoted + 10, quoted + 20, quoted + if source { 12 } else { 16 }, Some(quoted + 10)),
+        Some(6) if packet.len() >= quoted + 40 => (quoted + 8, quoted + 40, quoted + if source { 8 } else { 24 }, None),
+        _ => return
+    };
+    if &packet[address..address + from.len()] != from {
+        return;
+    }
+    let before = packet[start..end].to_vec();
+    packet[address..address + to.len()].copy_from_slice(to);
+    if let Some(at) = header_checksum {
+        adjust_checksum(packet, at, from, to);
+    }
+    let after = packet[start..end].to_vec();
+    adjust_checksum(packet, icmp + 2, &before, &after);
+}
@@ -0,0 +1,300 @@
+//Backend over the C++ OpenVPN3 library (`native` feature)
+use std::io::Result;
+use std::ffi::CString;
+use libc::{c_int, c_void, size_t, c_char};
+use std::string::String;
+use super::backend::{Backend, EventSink};
+use super::openvpn::{OVPNCreationError, OVPNEvent, OnVpnRead, OnVpnWrite};
+
+pub struct NativeBackend {
+    openvpn_client: *mut c_void,
+}
+
+unsafe impl Send for NativeBackend {}
+
+impl NativeBackend {
+    ///See `OVPNClient::new`
+    pub fn new(profile: String,
+        username: Option<&str>,
+        password: Option<&str>,
+        on_vpn_read: Option<OnVpnRead>,
+        on_vpn_write: Option<OnVpnWrite>,
+        events: EventSink) -> std::result::Result<NativeBackend, OVPNCreationError> {
+        let profile_cstring = CString::new(profile).map_err(|_|OVPNCreationError::CStringError("CString::new failed for profile_cstring".into()))?;
+        let username_cstring = CString::new(username.unwrap_or("")).map_err(|_|OVPNCreationError::CStringError("CString::new failed for username_cstring".into()))?;
+        let password_cstring = CString::new(password.unwrap_or("")).map_err(|_|OVPNCreationError::CStringError("CString::new failed for password_cstring".into()))?;
+        //OVPNClient's Nat rewrites packets. true_libopenvpn3's own rewriting is done with
+        //libtins, which build.rs leaves out with USE_TINS=OFF, so these addresses only fill
+        //openvpn_client_new's arguments: its C signature has no way to say "none"
+        let replacement_ipv4_cstring = CString::default();
+        let replacement_ipv6_cstring = CString::default();
+        let inner = OVPNClientInner{
+            on_vpn_read: on_vpn_read,
+            on_vpn_write: on_vpn_write,
+            events: events,
+        };
+        let callbacks = Callbacks {
+            user_data: Box::into_raw(Box::new(inner)) as *mut c_void,
+            on_read_allocate: on_read_allocate_trampoline,
+            on_write: on_write_trampoline,
+            on_log: on_log_trampoline,
+            on_event: on_event_trampoline,
+            destroy: destroy_trampoline::<OVPNClientInner>,
+        };
+        Ok(NativeBackend {
+            openvpn_client: unsafe{openvpn_client_new((&profile_cstring).as_ptr(), (&username_cstring).as_ptr(), (&password_cstring).as_ptr(), callbacks, (&replacement_ipv4_cstring).as_ptr(), (&replacement_ipv6_cstring).as_ptr())},
+        })
+    }
+}
+
+impl Backend for NativeBackend {
+    fn connect(&self) -> std::result::Result<(), String> {
+        let r = unsafe{openvpn_client_connect(self.openvpn_client)};
+        if r==0 {
+            Ok(())
+        } else {
+            Err("".into())
+        }
+    }
+
+    fn disconnect(&self) -> std::result::Result<(), String> {
+        let r = unsafe{openvpn_client_disconnect(self.openvpn_client)};
+        if r==0 {
+            Ok(())
+        } else {
+            Err("".into())
+        }
+    }
+
+    fn send(&self, packet: &[u8]) -> std::result::Result<(), String> {
+        let r = unsafe{openvpn_client_send(packet.as_ptr(), packet.len(), self.openvpn_client)};
+        if r==0 {
+            Ok(())
+        } else {
+            Err(format!("openvpn send unknown error: {}", r))
+        }
+    }
+
+    fn receive(&self, buffer: &mut [u8]) -> std::result::Result<Option<usize>, String> {
+        //number of bytes written in buffer in the C++ side
+        let mut written_size: size_t = 0;
+        let r = unsafe{openvpn_client_receive_just(buffer.as_mut_ptr(), buffer.len(), &mut written_size, self.openvpn_client)};
+        if r==0 {
+            Ok(Some(written_size))
+        } else if r==2 {
+            //Error 2 means there was no data avaliable at the time
+            Ok(None)
+        } else {
+            Err("openvpn_client_receive_just unknown error (we shouldn't have arrived here)".to_string())
+        }
+    }
+
+    //Deprecated
+    fn run(&self) -> std::result::Result<(), String> {
+        let r = unsafe{openvpn_client_run(self.openvpn_client)};
+        if r==0 {
+            Ok(())
+        } else {
+            Err("".into())
+        }
+    }
+}
+
+///Crypto library the C++ library is built with, picked by the `openssl` and `mbedtls` features
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoBackend {
+    OpenSsl,
+    MbedTls,
+}
+
+impl std::fmt::Display for CryptoBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            CryptoBackend::OpenSsl => "OpenSSL",
+            CryptoBackend::MbedTls => "mbed TLS",
+        })
+    }
+}
+
+//build.rs makes the same choice, and refuses both features at once
+pub fn crypto_backend() -> CryptoBackend {
+    if cfg!(feature = "mbedtls") {
+        CryptoBackend::MbedTls
+    } else {
+        CryptoBackend::OpenSsl
+    }
+}
+
+impl Drop for NativeBackend {
+    fn drop(&mut self) {
+        unsafe{openvpn_client_free(self.openvpn_client)};
+    }
+}
+
+struct OVPNClientInner {
+    on_vpn_read: Option<OnVpnRead>,
+    on_vpn_write: Option<OnVpnWrite>,
+    //Logs and events go through here to OVPNClient
+    events: EventSink,
+}
+
+impl OVPNClientInner {
+    //Gets data from Rust through on_vpn_read and passes to C++
+    fn read_allocate(&mut self, buffer: *mut *mut u8) -> Result<usize> {
+        let vpn_buffer = (self.on_vpn_read.as_ref().unwrap())();
+        match vpn_buffer {
+            Some(vpn_buffer) => {
+                let s = vpn_buffer.len();
+                let b: *mut u8 = unsafe{openvpn_client_allocate(s as usize)};
+                //fill buffer
+                unsafe{
+                    for i in 0..s {
+                        *b.offset(i as isize) = vpn_buffer[i];
+                    }
+                    *buffer = b
+                };
+                Ok(s as usize)
+            },
+            None => {
+                Err(std::io::Error::from_raw_os_error(1))
+            }
+        }
+    }
+
+    //Writes data from C++ to Rust
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        (self.on_vpn_write.as_ref().unwrap())(buf).unwrap();
+        Ok(buf.len())
+    }
+
+    //Writes data from C++ to Rust
+    fn log(&mut self, c_buffer: *const c_char) -> Result<()> {
+        let c_str: &std::ffi::CStr = unsafe { std::ffi::CStr::from_ptr(c_buffer) };
+        let str_slice: &str = c_str.to_str().unwrap();
+        let str_buf: String = str_slice.to_owned();  
+        self.events.log(str_buf);
+        Ok(())
+    }
+
+    //Writes data from C++ to Rust
+    fn event(&mut self, name: *const c_char, info: *const c_char, error: bool, fatal: bool) -> Result<()> {
+        let str = |c_buffer: *const c_char|->String {
+            let c_str: &std::ffi::CStr = unsafe { std::ffi::CStr::from_ptr(c_buffer) };
+            let str_slice: &str = c_str.to_str().unwrap();
+            let str_buf: String = str_slice.to_owned();  
+            str_buf
+        };
+        self.events.event(OVPNEvent::new(&str(name), &str(info), error, fatal));
+        Ok(())
+    }
+}
+
+extern "C" {
+    /// Creates a new OpenVPN C++ client, giving it ownership of the object
+    /// inside [`Callbacks`].
+    fn openvpn_client_new(profile: *const c_char, username: *const c_char, password: *const c_char, callbacks: Callbacks, replacementIpv4: *const c_char, replacementIpv6: *const c_char) -> *mut OpenVpnClient;
+    /// Sends data to the VPN
+    fn openvpn_client_send(buffer: *const u8, size: size_t, client: *mut OpenVpnClient) -> u8;
+    /// Receives data from the VPN
+    //fn openvpn_client_receive(buffer: *mut u8, buffer_size: size_t, written_size: *mut size_t, client: *mut OpenVpnClient) -> u8;
+    /// Receives data from the VPN, reading just buffer_size from the client
+    fn openvpn_client_receive_just(buffer: *mut u8, buffer_size: size_t, written_size: *mut size_t, client: *mut OpenVpnClient) -> u8;
+    /// Launches the connect thread of openvpn
+    fn openvpn_client_connect(client: *mut OpenVpnClient) -> u8;
+    /// Disconnects the connect threaf of openvpn
+    fn openvpn_client_disconnect(client: *mut OpenVpnClient) -> u8;
+    /// Tell the OpenVPN client to keep running until the VPN is shut down.
+    fn openvpn_client_run(client: *mut OpenVpnClient) -> u8;
+    /// Destroy the OpenVPN client.
+    fn openvpn_client_free(client: *mut OpenVpnClient);
+    /// Allocates, on C++, a uint8_t* buffer with size `size`
+    fn openvpn_client_allocate(size: size_t) -> *mut u8;
+    // Deallocates, on C++, a uint8_t* buffer
+    //fn openvpn_client_deallocate(buffer: *mut u8);
+}
+
+/// An opaque type representing the C++ OpenVPN client.
+type OpenVpnClient = c_void;
+
+#[repr(C)]
+pub struct Callbacks {
+    /// A pointer to some user-defined state.
+    pub user_data: *mut c_void,
+    /// Callback fired when the OpenVPN client wants to read data but does not know the data size
+    /// so it leaves to Rust the task of allocating. Returns 0 on success, -1 on failure
+    pub on_read_allocate: unsafe extern "C" fn(*mut *mut u8, *mut size_t, *mut c_void) -> c_int,
+    /// Callback fired when the OpenVPN client wants to write some data.
+    pub on_write: unsafe extern "C" fn(*const u8, size_t, *mut c_void) -> c_int,
+    /// Callback fired when the OpenVPN client wants to log some data.
+    pub on_log: unsafe extern "C" fn(*const c_char, *mut c_void) -> c_int,
+    /// Callback fired when the OpenVPN client sends some OpenvVPN event
+    pub on_event: unsafe extern "C" fn(*const c_char, *const c_char, bool, bool, *mut c_void) -> c_int,
+    /// A function for destroying the user-defined state.
+    pub destroy: unsafe extern "C" fn(*mut c_void),
+}
+
+unsafe extern "C" fn on_read_allocate_trampoline(
+    buffer: *mut *mut u8,
+    len: *mut size_t,
+    user_data: *mut c_void,
+) -> c_int {
+    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
+    match ovpn_client_inner.read_allocate(buffer) {
+        Ok(allocated_size) => {
+            *len = allocated_size;
+            0 as c_int
+        },
+        Err(_) => {
+            -1
+        },
+    }
+}
+
+unsafe extern "C" fn on_write_trampoline(
+    buffer: *const u8,
+    len: size_t,
+    user_data: *mut c_void,
+) -> c_int {
+    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
+    let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+
+    match ovpn_client_inner.write(buffer) {
+        Ok(bytes_written) => bytes_written as c_int,
+        Err(_) => -1,
+    }
+}
+
+
+unsafe extern "C" fn on_log_trampoline(
+    buffer: *const c_char,
+    user_data: *mut c_void,
+) -> c_int {
+    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
+    //let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+
+    match ovpn_client_inner.log(buffer) {
+        Ok(_) => 0,
+        Err(_) => 1
+    }
+}
+
+unsafe extern "C" fn on_event_trampoline(
+    name: *const c_char,
+    info: *const c_char,
+    error: bool,
+    fatal: bool,
+    user_data: *mut c_void,
+) -> c_int {
+    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
+    //let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+
+    match ovpn_client_inner.event(name, info, error, fatal) {
+        Ok(_) => 0,
+        Err(_) => 1
+    }
+}
+
+unsafe extern "C" fn destroy_trampoline<P>(user_data: *mut c_void) {
+    let user_data = Box::from_raw(user_data as *mut P);
+    drop(user_data);
+}
\ No newline at end of file
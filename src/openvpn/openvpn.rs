@@ -1,14 +1,30 @@
 use std::io::Result;
-use std::ffi::CString;
 use std::sync::{Arc, Mutex};
 //use std::collections::VecDeque;
 //use core::task::Waker;
-use libc::{c_int, c_void, size_t, c_char};
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::string::String;
 use std::time::Duration;
 use simple_vpn::{VpnClient, VpnConnectionError, VpnDisconnectionError, PhySendError, PhyReceiveError};
+use super::backend::{Backend, EventSink};
+use super::capture::PacketCapture;
+#[cfg(feature = "native")]
+use super::compression::CompressionPolicy;
+use super::filter::{Direction, PacketFilter, Verdict};
+#[cfg(feature = "mock")]
+use super::mock::{MockBackend, MockConfig, MockHandle};
+#[cfg(feature = "native")]
+use super::native::NativeBackend;
+use super::nat::Nat;
+use super::packet::Packet;
+#[cfg(feature = "native")]
+use super::proxy::ProxyConfig;
+use super::pushed::PushedConfig;
+use super::stats::{OVPNStats, StatsCounters};
 
 const MAX_BYTES_TRANSPORT: usize = 1518;
+//OpenVPN's tun-mtu when the server pushes none
+const DEFAULT_MTU: usize = 1500;
 
 ///Bridge between Rust and OpenVpn3's C++ (wrapped) library
 
@@ -20,10 +36,20 @@ pub type OnVpnLog = Arc<Mutex<dyn Fn(String) + Send + Sync>>;
 pub type OnVpnEvent = Arc<Mutex<dyn Fn(OVPNEvent) + Send + Sync>>;
 
 pub struct OVPNClient {
-    openvpn_client: *mut c_void,
+    backend: Box<dyn Backend>,
+    //Only the tun device logs through it for now
+    #[cfg_attr(not(feature = "tun"), allow(dead_code))]
+    events: EventSink,
+    pushed_config: Arc<Mutex<Option<PushedConfig>>>,
+    stats: StatsCounters,
+    nat: Mutex<Option<Nat>>,
+    //Local addresses from the replacement IPs. While set, `nat` follows the pushed addresses.
+    replacement: Mutex<Option<(Option<Ipv4Addr>, Option<Ipv6Addr>)>>,
+    filter: Mutex<Option<Arc<PacketFilter>>>,
+    capture: Mutex<Option<Arc<PacketCapture>>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OVPNEvent {
     pub name: String,
     pub info: String,
@@ -31,6 +57,88 @@ pub struct OVPNEvent {
     fatal: bool
 }
 
+///Events of the OpenVPN3 core, by name. Names we don't list end up in `Other`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OVPNEventKind {
+    Resolve,
+    Wait,
+    Connecting,
+    GetConfig,
+    AssignIp,
+    AddRoutes,
+    Connected,
+    Reconnecting,
+    Pause,
+    Resume,
+    Disconnected,
+    AuthFailed,
+    CertVerifyFail,
+    TlsVersionMin,
+    ClientHalt,
+    ClientRestart,
+    ConnectionTimeout,
+    InactiveTimeout,
+    TransportError,
+    ///The proxy in front of the server wants credentials, see `ProxyConfig`
+    ProxyNeedCreds,
+    Info,
+    Warn,
+    Other(String),
+}
+
+impl OVPNEventKind {
+    pub fn from_name(name: &str) -> OVPNEventKind {
+        match name {
+            "RESOLVE" => OVPNEventKind::Resolve,
+            "WAIT" => OVPNEventKind::Wait,
+            "CONNECTING" => OVPNEventKind::Connecting,
+            "GET_CONFIG" => OVPNEventKind::GetConfig,
+            "ASSIGN_IP" => OVPNEventKind::AssignIp,
+            "ADD_ROUTES" => OVPNEventKind::AddRoutes,
+            "CONNECTED" => OVPNEventKind::Connected,
+            "RECONNECTING" => OVPNEventKind::Reconnecting,
+            "PAUSE" => OVPNEventKind::Pause,
+            "RESUME" => OVPNEventKind::Resume,
+            "DISCONNECTED" => OVPNEventKind::Disconnected,
+            "AUTH_FAILED" => OVPNEventKind::AuthFailed,
+            "CERT_VERIFY_FAIL" => OVPNEventKind::CertVerifyFail,
+            "TLS_VERSION_MIN" => OVPNEventKind::TlsVersionMin,
+            "CLIENT_HALT" => OVPNEventKind::ClientHalt,
+            "CLIENT_RESTART" => OVPNEventKind::ClientRestart,
+            "CONNECTION_TIMEOUT" => OVPNEventKind::ConnectionTimeout,
+            "INACTIVE_TIMEOUT" => OVPNEventKind::InactiveTimeout,
+            "TRANSPORT_ERROR" => OVPNEventKind::TransportError,
+            "PROXY_NEED_CREDS" => OVPNEventKind::ProxyNeedCreds,
+            "INFO" => OVPNEventKind::Info,
+            "WARN" => OVPNEventKind::Warn,
+            other => OVPNEventKind::Other(other.to_owned()),
+        }
+    }
+}
+
+impl OVPNEvent {
+    pub fn new(name: &str, info: &str, error: bool, fatal: bool) -> OVPNEvent {
+        OVPNEvent {
+            name: name.to_owned(),
+            info: info.to_owned(),
+            error: error,
+            fatal: fatal
+        }
+    }
+
+    pub fn kind(&self) -> OVPNEventKind {
+        OVPNEventKind::from_name(&self.name)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+
+    pub fn is_fatal(&self) -> bool {
+        self.fatal
+    }
+}
+
 impl std::fmt::Display for OVPNEvent {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if !self.error {
@@ -55,6 +163,8 @@ pub enum OpenVpnReceiveError {
     Unknown(String)
 }
 pub enum OpenVpnSendError {
+    ///The packet is bigger than the tunnel MTU and wasn't sent
+    TooBig{size: usize, mtu: usize},
     Unknown(String)
 }
 pub enum OpenVpnConnectionError {
@@ -76,6 +186,7 @@ impl From<OpenVpnReceiveError> for PhyReceiveError {
 impl From<OpenVpnSendError> for PhySendError {
     fn from(e: OpenVpnSendError) -> PhySendError {
         match e {
+            OpenVpnSendError::TooBig{size, mtu} => PhySendError::Unknown(format!("packet of {} bytes is bigger than the MTU of {}", size, mtu)),
             OpenVpnSendError::Unknown(s) => PhySendError::Unknown(s)
         }
     }
@@ -97,90 +208,15 @@ impl From<OpenVpnDisconnectionError> for VpnDisconnectionError {
     }
 }
 
-unsafe impl Send for OVPNClient {}
-
-struct OVPNClientInner {
-    on_vpn_read: Option<OnVpnRead>,
-    on_vpn_write: Option<OnVpnWrite>,
-    on_vpn_log: Option<OnVpnLog>,
-    on_vpn_event: Option<OnVpnEvent>,
-    //replacement_ip: String
-}
-
-impl OVPNClientInner {
-    //Gets data from Rust through on_vpn_read and passes to C++
-    fn read_allocate(&mut self, buffer: *mut *mut u8) -> Result<usize> {
-        let vpn_buffer = (self.on_vpn_read.as_ref().unwrap())();
-        match vpn_buffer {
-            Some(vpn_buffer) => {
-                let s = vpn_buffer.len();
-                let b: *mut u8 = unsafe{openvpn_client_allocate(s as usize)};
-                //fill buffer
-                unsafe{
-                    for i in 0..s {
-                        *b.offset(i as isize) = vpn_buffer[i];
-                    }
-                    *buffer = b
-                };
-                Ok(s as usize)
-            },
-            None => {
-                Err(std::io::Error::from_raw_os_error(1))
-            }
-        }
-    }
-
-    //Writes data from C++ to Rust
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        (self.on_vpn_write.as_ref().unwrap())(buf).unwrap();
-        Ok(buf.len())
-    }
-
-    //Writes data from C++ to Rust
-    fn log(&mut self, c_buffer: *const c_char) -> Result<()> {
-        let c_str: &std::ffi::CStr = unsafe { std::ffi::CStr::from_ptr(c_buffer) };
-        let str_slice: &str = c_str.to_str().unwrap();
-        let str_buf: String = str_slice.to_owned();  
-        match self.on_vpn_log.clone() {
-            Some(on_vpn_log) => {
-                (on_vpn_log.lock().unwrap())(str_buf);
-            },
-            None => {
-                println!("OpenVPN: {}", str_buf);
-            }
-        }
-        Ok(())
-    }
-
-    //Writes data from C++ to Rust
-    fn event(&mut self, name: *const c_char, info: *const c_char, error: bool, fatal: bool) -> Result<()> {
-        let str = |c_buffer: *const c_char|->String {
-            let c_str: &std::ffi::CStr = unsafe { std::ffi::CStr::from_ptr(c_buffer) };
-            let str_slice: &str = c_str.to_str().unwrap();
-            let str_buf: String = str_slice.to_owned();  
-            str_buf
-        };
-        let event = OVPNEvent{
-            name: str(name),
-            info: str(info),
-            error: error,
-            fatal: fatal
-        };
-        match self.on_vpn_event.clone() {
-            Some(on_vpn_event) => {
-                (on_vpn_event.lock().unwrap())(event);
-            },
-            None => {
-                println!("EVENT: {}", event);
-            }
-        }
-        Ok(())
-    }
-}
-
 #[derive(Debug)]
 pub enum OVPNCreationError {
-    CStringError(String)
+    CStringError(String),
+    ///The crate was built without the backend that was asked for
+    BackendUnavailable(String),
+    ///The profile asks for compression the policy refuses or the build doesn't have
+    Compression(String),
+    ///The proxy config can't be applied to the profile
+    Proxy(String)
 }
 
 impl VpnClient for OVPNClient {
@@ -212,6 +248,13 @@ impl VpnClient for OVPNClient {
 }
 
 impl OVPNClient {
+    ///`replacement_ipv4`/`replacement_ipv6` rewrite our tunnel addresses to them in received
+    ///packets, and back in sent ones, whatever addresses the server pushes. It's a `Nat` that
+    ///follows the pushed config, see `set_replacement_ips`. Pass `None` to leave packets as they are.
+    ///`compression` is how much compression the client does, whatever the profile says.
+    ///`proxy` is the HTTP or SOCKS proxy to reach the server through, replacing the profile's
+    ///or giving it credentials. `None` uses the profile's as it is.
+    #[cfg(feature = "native")]
     pub fn new(profile: String, 
         username: Option<&str>, 
         password: Option<&str>, 
@@ -219,208 +262,235 @@ impl OVPNClient {
         on_vpn_write: Option<OnVpnWrite>,
         on_vpn_log: Option<OnVpnLog>,
         on_vpn_event: Option<OnVpnEvent>,
-        replacement_ipv4: &std::net::Ipv4Addr,
-        replacement_ipv6: &std::net::Ipv6Addr) -> std::result::Result<OVPNClient, OVPNCreationError> {
-        let inner = OVPNClientInner{
-            on_vpn_read: on_vpn_read,
-            on_vpn_write: on_vpn_write,
-            on_vpn_log: on_vpn_log,
-            on_vpn_event: on_vpn_event
-        };
-        let callbacks = Callbacks {
-            user_data: Box::into_raw(Box::new(inner)) as *mut c_void,
-            on_read_allocate: on_read_allocate_trampoline,
-            on_write: on_write_trampoline,
-            on_log: on_log_trampoline,
-            on_event: on_event_trampoline,
-            destroy: destroy_trampoline::<OVPNClientInner>,
+        replacement_ipv4: Option<&Ipv4Addr>,
+        replacement_ipv6: Option<&Ipv6Addr>,
+        compression: CompressionPolicy,
+        proxy: Option<&ProxyConfig>) -> std::result::Result<OVPNClient, OVPNCreationError> {
+        let profile = compression.apply(&profile).map_err(OVPNCreationError::Compression)?;
+        let profile = match proxy {
+            Some(proxy) => proxy.apply(&profile).map_err(OVPNCreationError::Proxy)?,
+            None => profile
         };
-        let profile_cstring = CString::new(profile).map_err(|_|OVPNCreationError::CStringError("CString::new failed for profile_cstring".into()))?;
-        let username_cstring = CString::new(username.unwrap_or("")).map_err(|_|OVPNCreationError::CStringError("CString::new failed for username_cstring".into()))?;
-        let password_cstring = CString::new(password.unwrap_or("")).map_err(|_|OVPNCreationError::CStringError("CString::new failed for password_cstring".into()))?;
-        let replacement_ipv4_addr_string:&str = &replacement_ipv4.to_string();
-        let replacement_ipv6_addr_string:&str = &replacement_ipv6.to_string();
-        let replacement_ipv4_cstring = CString::new(replacement_ipv4_addr_string).map_err(|_|OVPNCreationError::CStringError("CString::new failed for replacementIpv4".into()))?;
-        let replacement_ipv6_cstring = CString::new(replacement_ipv6_addr_string).map_err(|_|OVPNCreationError::CStringError("CString::new failed for replacementIpv6".into()))?;
+        let events = EventSink::new(on_vpn_log, on_vpn_event);
+        let backend = NativeBackend::new(profile, username, password, on_vpn_read, on_vpn_write, events.clone())?;
+        let client = OVPNClient::from_parts(Box::new(backend), events);
+        if replacement_ipv4.is_some() || replacement_ipv6.is_some() {
+            client.set_replacement_ips(replacement_ipv4.copied(), replacement_ipv6.copied());
+        }
+        Ok(client)
+    }
 
-        Ok(OVPNClient {
-            openvpn_client: unsafe{openvpn_client_new((&profile_cstring).as_ptr(), (&username_cstring).as_ptr(), (&password_cstring).as_ptr(), callbacks, (&replacement_ipv4_cstring).as_ptr(), (&replacement_ipv6_cstring).as_ptr())},
-        })
+    ///A client over a mock backend that loops packets back and plays `config`'s script on
+    ///connect. The handle injects packets, events and failures.
+    #[cfg(feature = "mock")]
+    pub fn mock(config: MockConfig, on_vpn_log: Option<OnVpnLog>, on_vpn_event: Option<OnVpnEvent>) -> (OVPNClient, MockHandle) {
+        let events = EventSink::new(on_vpn_log, on_vpn_event);
+        let (backend, handle) = MockBackend::new(config, events.clone());
+        (OVPNClient::from_parts(Box::new(backend), events), handle)
     }
 
-    //Deprecated
-    pub fn run(&self) -> std::result::Result<(), ()>  {
-        let r = unsafe{openvpn_client_run(self.openvpn_client)};
-        if r==0 {
-            Ok(())
-        } else {
-            Err(())
-        }
+    ///A client over a backend of your own. `create` gets the sink the backend reports its
+    ///logs and events to.
+    pub fn with_backend<F>(on_vpn_log: Option<OnVpnLog>, on_vpn_event: Option<OnVpnEvent>, create: F) -> OVPNClient
+        where F: FnOnce(EventSink) -> Box<dyn Backend> {
+        let events = EventSink::new(on_vpn_log, on_vpn_event);
+        let backend = create(events.clone());
+        OVPNClient::from_parts(backend, events)
     }
 
-    // Sends data to the VPN
-    pub fn send(&self, data: &[u8]) -> std::result::Result<usize, OpenVpnSendError> {
-        let size = data.len();
-        let r = unsafe{openvpn_client_send(data.as_ptr(), size, self.openvpn_client)};
-        if r==0 {
-            //we always return the full size because the C++ openvpn implementation is always able to receive the full size
-            Ok(size)
-        } else {
-            Err(OpenVpnSendError::Unknown(format!("openvpn send unknown error: {}", r)))
+    fn from_parts(backend: Box<dyn Backend>, events: EventSink) -> OVPNClient {
+        OVPNClient {
+            backend: backend,
+            pushed_config: events.pushed_config(),
+            events: events,
+            stats: StatsCounters::default(),
+            nat: Mutex::new(None),
+            replacement: Mutex::new(None),
+            filter: Mutex::new(None),
+            capture: Mutex::new(None),
         }
     }
 
-    pub fn connect(&self) -> std::result::Result<(), OpenVpnConnectionError> {
-        let r = unsafe{openvpn_client_connect(self.openvpn_client)};
-        if r==0 {
-            Ok(())
-        } else {
-            Err(OpenVpnConnectionError::Unknown("".into()))
-        }
+    ///A log line of our own, to `on_vpn_log` like the backend's
+    #[cfg_attr(not(feature = "tun"), allow(dead_code))]
+    pub(crate) fn log(&self, line: String) {
+        self.events.log(line);
     }
 
-    pub fn disconnect(&self) -> std::result::Result<(), OpenVpnDisconnectionError>{
-        let r = unsafe{openvpn_client_disconnect(self.openvpn_client)};
-        if r==0 {
-            Ok(())
-        } else {
-            Err(OpenVpnDisconnectionError::Unknown("".into()))
-        }
+    ///Translates addresses of sent and received packets with `nat`, or stops translating
+    ///with `None`. Replaces the replacement IPs' translation for good.
+    pub fn set_nat(&self, nat: Option<Nat>) {
+        *self.replacement.lock().unwrap() = None;
+        *self.nat.lock().unwrap() = nat;
     }
 
-    // Receives data from the VPN
-    pub fn receive(&mut self, f: &mut dyn FnMut(&[u8])) -> std::result::Result<usize, OpenVpnReceiveError> {
-        //TODO: https://en.wikipedia.org/wiki/Jumbo_frame
-        let mut buffer = [0u8; MAX_BYTES_TRANSPORT];
-        //number of bytes written in buffer in the C++ side
-        let mut written_size: size_t = 0;
-        let r = unsafe{openvpn_client_receive_just(buffer.as_mut_ptr(), buffer.len(), &mut written_size, self.openvpn_client)};
-        if r==0 {
-            let buffer_slice = &buffer[0..written_size];
-            f(buffer_slice);
-            Ok(written_size)
-        } else if r==2 {
-            //Error 2 means there was no data avaliable at the time
-            Err(OpenVpnReceiveError::NoDataAvailable)
-        } else {
-            Err(OpenVpnReceiveError::Unknown("openvpn_client_receive_just unknown error (we shouldn't have arrived here)".to_string()))
+    ///Rewrites our tunnel addresses to `ipv4`/`ipv6` in received packets, and back in sent
+    ///ones, following the addresses the server pushes. Replaces any `set_nat`.
+    pub fn set_replacement_ips(&self, ipv4: Option<Ipv4Addr>, ipv6: Option<Ipv6Addr>) {
+        *self.replacement.lock().unwrap() = Some((ipv4, ipv6));
+        *self.nat.lock().unwrap() = None;
+        self.follow_pushed_addresses();
+    }
+
+    //With replacement IPs, keeps the NAT on the addresses the server pushed last. A reconnect
+    //can get us other ones.
+    fn follow_pushed_addresses(&self) {
+        let (local_ipv4, local_ipv6) = match *self.replacement.lock().unwrap() {
+            Some(replacement) => replacement,
+            None => return
+        };
+        let nat = match self.pushed_config.lock().unwrap().as_ref() {
+            Some(pushed) => Nat::from_pushed(local_ipv4, local_ipv6, pushed),
+            None => return
+        };
+        let mut current = self.nat.lock().unwrap();
+        if current.as_ref() != Some(&nat) {
+            *current = Some(nat);
         }
     }
-    
-}
 
-impl Drop for OVPNClient {
-    fn drop(&mut self) {
-        unsafe{openvpn_client_free(self.openvpn_client)};
+    pub fn nat(&self) -> Option<Nat> {
+        self.nat.lock().unwrap().clone()
     }
-}
 
-extern "C" {
-    /// Creates a new OpenVPN C++ client, giving it ownership of the object
-    /// inside [`Callbacks`].
-    fn openvpn_client_new(profile: *const c_char, username: *const c_char, password: *const c_char, callbacks: Callbacks, replacementIpv4: *const c_char, replacementIpv6: *const c_char) -> *mut OpenVpnClient;
-    /// Sends data to the VPN
-    fn openvpn_client_send(buffer: *const u8, size: size_t, client: *mut OpenVpnClient) -> u8;
-    /// Receives data from the VPN
-    //fn openvpn_client_receive(buffer: *mut u8, buffer_size: size_t, written_size: *mut size_t, client: *mut OpenVpnClient) -> u8;
-    /// Receives data from the VPN, reading just buffer_size from the client
-    fn openvpn_client_receive_just(buffer: *mut u8, buffer_size: size_t, written_size: *mut size_t, client: *mut OpenVpnClient) -> u8;
-    /// Launches the connect thread of openvpn
-    fn openvpn_client_connect(client: *mut OpenVpnClient) -> u8;
-    /// Disconnects the connect threaf of openvpn
-    fn openvpn_client_disconnect(client: *mut OpenVpnClient) -> u8;
-    /// Tell the OpenVPN client to keep running until the VPN is shut down.
-    fn openvpn_client_run(client: *mut OpenVpnClient) -> u8;
-    /// Destroy the OpenVPN client.
-    fn openvpn_client_free(client: *mut OpenVpnClient);
-    /// Allocates, on C++, a uint8_t* buffer with size `size`
-    fn openvpn_client_allocate(size: size_t) -> *mut u8;
-    // Deallocates, on C++, a uint8_t* buffer
-    //fn openvpn_client_deallocate(buffer: *mut u8);
-}
+    ///Runs every packet sent or received through `filter`, or stops filtering with `None`.
+    ///Dropped outbound packets are reported as sent, dropped inbound ones are skipped.
+    pub fn set_filter(&self, filter: Option<Arc<PacketFilter>>) {
+        *self.filter.lock().unwrap() = filter;
+    }
 
-/// An opaque type representing the C++ OpenVPN client.
-type OpenVpnClient = c_void;
-
-#[repr(C)]
-pub struct Callbacks {
-    /// A pointer to some user-defined state.
-    pub user_data: *mut c_void,
-    /// Callback fired when the OpenVPN client wants to read data but does not know the data size
-    /// so it leaves to Rust the task of allocating. Returns 0 on success, -1 on failure
-    pub on_read_allocate: unsafe extern "C" fn(*mut *mut u8, *mut size_t, *mut c_void) -> c_int,
-    /// Callback fired when the OpenVPN client wants to write some data.
-    pub on_write: unsafe extern "C" fn(*const u8, size_t, *mut c_void) -> c_int,
-    /// Callback fired when the OpenVPN client wants to log some data.
-    pub on_log: unsafe extern "C" fn(*const c_char, *mut c_void) -> c_int,
-    /// Callback fired when the OpenVPN client sends some OpenvVPN event
-    pub on_event: unsafe extern "C" fn(*const c_char, *const c_char, bool, bool, *mut c_void) -> c_int,
-    /// A function for destroying the user-defined state.
-    pub destroy: unsafe extern "C" fn(*mut c_void),
-}
+    pub fn filter(&self) -> Option<Arc<PacketFilter>> {
+        self.filter.lock().unwrap().clone()
+    }
 
-unsafe extern "C" fn on_read_allocate_trampoline(
-    buffer: *mut *mut u8,
-    len: *mut size_t,
-    user_data: *mut c_void,
-) -> c_int {
-    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
-    match ovpn_client_inner.read_allocate(buffer) {
-        Ok(allocated_size) => {
-            *len = allocated_size;
-            0 as c_int
-        },
-        Err(_) => {
-            -1
-        },
+    ///Writes the packets exchanged with the tunnel to `capture`, or stops capturing with `None`
+    pub fn set_capture(&self, capture: Option<Arc<PacketCapture>>) {
+        *self.capture.lock().unwrap() = capture;
     }
-}
 
-unsafe extern "C" fn on_write_trampoline(
-    buffer: *const u8,
-    len: size_t,
-    user_data: *mut c_void,
-) -> c_int {
-    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
-    let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+    ///Options pushed by the server (addresses, routes, DNS, MTU). `None` until the
+    ///server answers our PUSH_REQUEST.
+    pub fn pushed_config(&self) -> Option<PushedConfig> {
+        self.pushed_config.lock().unwrap().clone()
+    }
 
-    match ovpn_client_inner.write(buffer) {
-        Ok(bytes_written) => bytes_written as c_int,
-        Err(_) => -1,
+    ///Biggest packet `send` takes: the pushed `tun-mtu`, or 1500
+    pub fn mtu(&self) -> usize {
+        self.pushed_config.lock().unwrap().as_ref()
+            .and_then(|c| c.mtu)
+            .unwrap_or(DEFAULT_MTU)
+    }
+
+    pub fn stats(&self) -> OVPNStats {
+        self.stats.snapshot()
+    }
+
+    ///Blocks until the server pushed its options, or `timeout` passes. Call after `connect`.
+    pub fn wait_pushed_config(&self, timeout: Duration) -> Option<PushedConfig> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if let Some(pushed_config) = self.pushed_config() {
+                return Some(pushed_config);
+            }
+            if std::time::Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
     }
-}
 
+    //Deprecated
+    pub fn run(&self) -> std::result::Result<(), ()>  {
+        self.backend.run().map_err(|_| ())
+    }
 
-unsafe extern "C" fn on_log_trampoline(
-    buffer: *const c_char,
-    user_data: *mut c_void,
-) -> c_int {
-    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
-    //let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+    // Sends data to the VPN
+    pub fn send(&self, data: &[u8]) -> std::result::Result<usize, OpenVpnSendError> {
+        let size = data.len();
+        self.follow_pushed_addresses();
+        //Set when the filter or NAT changed the packet
+        let mut changed: Option<Vec<u8>> = None;
+        if let Some(filter) = self.filter() {
+            match filter.check(Direction::Outbound, data) {
+                Verdict::Drop => return Ok(size),
+                Verdict::Rewrite(packet) => changed = Some(packet),
+                _ => {}
+            }
+        }
+        let packet = changed.as_deref().unwrap_or(data);
+        //What the IP header says, trailing padding doesn't count
+        let packet_size = Packet::parse(packet).map(|p| p.len()).unwrap_or(packet.len());
+        let mtu = self.mtu();
+        if packet_size > mtu {
+            self.stats.send_error();
+            return Err(OpenVpnSendError::TooBig{size: packet_size, mtu: mtu});
+        }
+        if let Some(nat) = self.nat.lock().unwrap().as_ref().filter(|nat| nat.is_enabled()) {
+            let mut packet = changed.take().unwrap_or_else(|| data.to_vec());
+            nat.outbound(&mut packet);
+            changed = Some(packet);
+        }
+        let data = changed.as_deref().unwrap_or(data);
+        if let Some(capture) = self.capture.lock().unwrap().as_ref() {
+            capture.write(Direction::Outbound, data);
+        }
+        match self.backend.send(data) {
+            Ok(()) => {
+                self.stats.sent(data.len());
+                //we always return the full size because the C++ openvpn implementation is always able to receive the full size
+                Ok(size)
+            },
+            Err(e) => {
+                self.stats.send_error();
+                Err(OpenVpnSendError::Unknown(e))
+            }
+        }
+    }
 
-    match ovpn_client_inner.log(buffer) {
-        Ok(_) => 0,
-        Err(_) => 1
+    pub fn connect(&self) -> std::result::Result<(), OpenVpnConnectionError> {
+        self.backend.connect().map_err(OpenVpnConnectionError::Unknown)
     }
-}
 
-unsafe extern "C" fn on_event_trampoline(
-    name: *const c_char,
-    info: *const c_char,
-    error: bool,
-    fatal: bool,
-    user_data: *mut c_void,
-) -> c_int {
-    let ovpn_client_inner = &mut *(user_data as *mut OVPNClientInner);
-    //let buffer = std::slice::from_raw_parts(buffer as *const u8, len as usize);
+    pub fn disconnect(&self) -> std::result::Result<(), OpenVpnDisconnectionError>{
+        self.backend.disconnect().map_err(OpenVpnDisconnectionError::Unknown)
+    }
 
-    match ovpn_client_inner.event(name, info, error, fatal) {
-        Ok(_) => 0,
-        Err(_) => 1
+    // Receives data from the VPN
+    pub fn receive(&mut self, f: &mut dyn FnMut(&[u8])) -> std::result::Result<usize, OpenVpnReceiveError> {
+        //TODO: https://en.wikipedia.org/wiki/Jumbo_frame
+        let mut buffer = [0u8; MAX_BYTES_TRANSPORT];
+        //Packets the filter drops are skipped, so keep reading until one gets through
+        loop {
+            let written_size = match self.backend.receive(&mut buffer) {
+                Ok(Some(written_size)) => written_size,
+                Ok(None) => return Err(OpenVpnReceiveError::NoDataAvailable),
+                Err(e) => {
+                    self.stats.receive_error();
+                    return Err(OpenVpnReceiveError::Unknown(e));
+                }
+            };
+            self.stats.received(written_size);
+            self.follow_pushed_addresses();
+            if let Some(capture) = self.capture.lock().unwrap().as_ref() {
+                capture.write(Direction::Inbound, &buffer[0..written_size]);
+            }
+            if let Some(nat) = self.nat.lock().unwrap().as_ref() {
+                nat.inbound(&mut buffer[0..written_size]);
+            }
+            let buffer_slice = &buffer[0..written_size];
+            match self.filter().map(|filter| filter.check(Direction::Inbound, buffer_slice)) {
+                Some(Verdict::Drop) => continue,
+                Some(Verdict::Rewrite(packet)) => {
+                    f(&packet);
+                    return Ok(packet.len());
+                },
+                _ => {
+                    f(buffer_slice);
+                    return Ok(written_size);
+                }
+            }
+        }
     }
+    
 }
 
-unsafe extern "C" fn destroy_trampoline<P>(user_data: *mut c_void) {
-    let user_data = Box::from_raw(user_data as *mut P);
-    drop(user_data);
-}
\ No newline at end of file
@@ -0,0 +1,620 @@
+//Parsing and building of the IP packets that go through the tunnel
+//
+//Packet::parse reads the IPv4/IPv6 header, walks IPv6 extension headers and reads the
+//TCP, UDP or ICMP header after them, checking that every length adds up.
+//PacketBuilder crafts packets with lengths and checksums filled in.
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+pub const PROTOCOL_ICMPV6: u8 = 58;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    ///Shorter than its headers say it is
+    Truncated,
+    UnknownVersion(u8),
+    InvalidHeader(String),
+    ///Which checksum didn't verify
+    BadChecksum(String),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PacketError::Truncated => write!(f, "truncated packet"),
+            PacketError::UnknownVersion(v) => write!(f, "unknown IP version {}", v),
+            PacketError::InvalidHeader(s) => write!(f, "invalid header: {}", s),
+            PacketError::BadChecksum(s) => write!(f, "bad {} checksum", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Header {
+    ///In bytes
+    pub header_len: usize,
+    pub tos: u8,
+    pub total_len: u16,
+    pub identification: u16,
+    pub dont_fragment: bool,
+    pub more_fragments: bool,
+    ///In 8 byte units
+    pub fragment_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub checksum: u16,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv6Header {
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_len: u16,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub source: Ipv6Addr,
+    pub destination: Ipv6Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpHeader {
+    V4(Ipv4Header),
+    V6(Ipv6Header),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub sequence: u32,
+    pub acknowledgment: u32,
+    ///In bytes
+    pub header_len: usize,
+    pub flags: u8,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent_pointer: u16,
+}
+
+impl TcpHeader {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpHeader {
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub length: u16,
+    pub checksum: u16,
+}
+
+///ICMP or ICMPv6 header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IcmpHeader {
+    pub icmp_type: u8,
+    pub code: u8,
+    pub checksum: u16,
+    ///Type specific: identifier and sequence for echoes, unused or MTU for errors
+    pub rest: [u8; 4],
+}
+
+impl IcmpHeader {
+    ///Whether the message quotes the packet that caused it
+    pub fn is_error(&self, v6: bool) -> bool {
+        if v6 {
+            (1..=4).contains(&self.icmp_type)
+        } else {
+            matches!(self.icmp_type, 3 | 4 | 5 | 11 | 12)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp(TcpHeader),
+    Udp(UdpHeader),
+    Icmp(IcmpHeader),
+    Icmpv6(IcmpHeader),
+    ///Protocols we don't parse
+    Other(u8),
+}
+
+///A parsed packet, borrowing its bytes
+#[derive(Debug, Clone)]
+pub struct Packet<'a> {
+    //Cut to the length the IP header says, without link padding
+    data: &'a [u8],
+    ip: IpHeader,
+    protocol: u8,
+    //None for fragments other than the first, which carry no transport header
+    transport_offset: Option<usize>,
+    transport: Option<Transport>,
+    payload_offset: usize,
+    fragmented: bool,
+}
+
+impl<'a> Packet<'a> {
+    pub fn parse(data: &'a [u8]) -> std::result::Result<Packet<'a>, PacketError> {
+        let version = data.first().ok_or(PacketError::Truncated)? >> 4;
+        let (data, ip, protocol, transport_offset, fragmented) = match version {
+            4 => {
+                if data.len() < 20 {
+                    return Err(PacketError::Truncated);
+                }
+                let header_len = ((data[0] & 0x0f) as usize) * 4;
+                let total_len = u16::from_be_bytes([data[2], data[3]]);
+                if header_len < 20 || (total_len as usize) < header_len {
+                    return Err(PacketError::InvalidHeader(format!("IPv4 header length {}, total length {}", header_len, total_len)));
+                }
+                if data.len() < total_len as usize {
+                    return Err(PacketError::Truncated);
+                }
+                let flags = u16::from_be_bytes([data[6], data[7]]);
+                let header = Ipv4Header {
+                    header_len: header_len,
+                    tos: data[1],
+                    total_len: total_len,
+                    identification: u16::from_be_bytes([data[4], data[5]]),
+                    dont_fragment: flags & 0x4000 != 0,
+                    more_fragments: flags & 0x2000 != 0,
+                    fragment_offset: flags & 0x1fff,
+                    ttl: data[8],
+                    protocol: data[9],
+                    checksum: u16::from_be_bytes([data[10], data[11]]),
+                    source: Ipv4Addr::new(data[12], data[13], data[14], data[15]),
+                    destination: Ipv4Addr::new(data[16], data[17], data[18], data[19]),
+                };
+                let transport_offset = if header.fragment_offset == 0 { Some(header_len) } else { None };
+                let fragmented = header.more_fragments || header.fragment_offset != 0;
+                (&data[..total_len as usize], IpHeader::V4(header), header.protocol, transport_offset, fragmented)
+            },
+            6 => {
+                if data.len() < 40 {
+                    return Err(PacketError::Truncated);
+                }
+                let payload_len = u16::from_be_bytes([data[4], data[5]]);
+                if data.len() < 40 + payload_len as usize {
+                    return Err(PacketError::Truncated);
+                }
+                let header = Ipv6Header {
+                    traffic_class: (data[0] << 4) | (data[1] >> 4),
+                    flow_label: u32::from_be_bytes([0, data[1] & 0x0f, data[2], data[3]]),
+                    payload_len: payload_len,
+                    next_header: data[6],
+                    hop_limit: data[7],
+                    source: Ipv6Addr::from(octets16(&data[8..24])),
+                    destination: Ipv6Addr::from(octets16(&data[24..40])),
+                };
+                let data = &data[..40 + payload_len as usize];
+                let (protocol, offset, fragment) = walk_extension_headers(data, header.next_header)?;
+                let transport_offset = match fragment {
+                    Some((fragment_offset, _)) if fragment_offset != 0 => None,
+                    _ => Some(offset)
+                };
+                let fragmented = fragment.map(|(fragment_offset, more)| fragment_offset != 0 || more).unwrap_or(false);
+                (data, IpHeader::V6(header), protocol, transport_offset, fragmented)
+            },
+            v => return Err(PacketError::UnknownVersion(v))
+        };
+        let (transport, payload_offset) = match transport_offset {
+            Some(offset) => {
+                let (transport, len) = parse_transport(&data[offset..], protocol)?;
+                (Some(transport), offset + len)
+            },
+            None => (None, data.len())
+        };
+        Ok(Packet {
+            data: data,
+            ip: ip,
+            protocol: protocol,
+            transport_offset: transport_offset,
+            transport: transport,
+            payload_offset: payload_offset,
+            fragmented: fragmented,
+        })
+    }
+
+    ///The packet's bytes, as long as its IP header says
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn ip(&self) -> &IpHeader {
+        &self.ip
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        matches!(self.ip, IpHeader::V6(_))
+    }
+
+    pub fn source(&self) -> IpAddr {
+        match self.ip {
+            IpHeader::V4(h) => IpAddr::V4(h.source),
+            IpHeader::V6(h) => IpAddr::V6(h.source),
+        }
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        match self.ip {
+            IpHeader::V4(h) => IpAddr::V4(h.destination),
+            IpHeader::V6(h) => IpAddr::V6(h.destination),
+        }
+    }
+
+    ///Transport protocol number, after IPv6 extension headers
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    ///Whether this is one fragment of a bigger packet
+    pub fn is_fragment(&self) -> bool {
+        self.fragmented
+    }
+
+    ///None for fragments other than the first
+    pub fn transport(&self) -> Option<&Transport> {
+        self.transport.as_ref()
+    }
+
+    ///Where the transport header starts
+    pub fn transport_offset(&self) -> Option<usize> {
+        self.transport_offset
+    }
+
+    ///What comes after the transport header
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[self.payload_offset..]
+    }
+
+    pub fn source_port(&self) -> Option<u16> {
+        match self.transport {
+            Some(Transport::Tcp(h)) => Some(h.source_port),
+            Some(Transport::Udp(h)) => Some(h.source_port),
+            _ => None
+        }
+    }
+
+    pub fn destination_port(&self) -> Option<u16> {
+        match self.transport {
+            Some(Transport::Tcp(h)) => Some(h.destination_port),
+            Some(Transport::Udp(h)) => Some(h.destination_port),
+            _ => None
+        }
+    }
+
+    ///Checks the IPv4 header checksum and, for whole packets, the TCP, UDP or ICMP one
+    pub fn verify_checksums(&self) -> std::result::Result<(), PacketError> {
+        if let IpHeader::V4(h) = self.ip {
+            if checksum(&self.data[..h.header_len]) != 0 {
+                return Err(PacketError::BadChecksum("IPv4 header".into()));
+            }
+        }
+        //Fragments don't have the whole transport payload to check against
+        let offset = match self.transport_offset {
+            Some(offset) if !self.is_fragment() => offset,
+            _ => return Ok(())
+        };
+        let segment = &self.data[offset..];
+        let pseudo_header = pseudo_header_sum(&self.source(), &self.destination(), self.protocol, segment.len());
+        let (name, sum) = match self.transport {
+            Some(Transport::Tcp(_)) => ("TCP", sum(segment, pseudo_header)),
+            Some(Transport::Udp(h)) if h.checksum == 0 && !self.is_ipv6() => return Ok(()),
+            Some(Transport::Udp(_)) => ("UDP", sum(segment, pseudo_header)),
+            Some(Transport::Icmp(_)) => ("ICMP", sum(segment, 0)),
+            Some(Transport::Icmpv6(_)) => ("ICMPv6", sum(segment, pseudo_header)),
+            _ => return Ok(())
+        };
+        if fold(sum) != 0 {
+            return Err(PacketError::BadChecksum(name.into()));
+        }
+        Ok(())
+    }
+}
+
+fn octets16(bytes: &[u8]) -> [u8; 16] {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(bytes);
+    octets
+}
+
+//Offset and more fragments flag of an IPv6 fragment header
+type Fragment = (u16, bool);
+
+//Follows IPv6 extension headers. Returns the transport protocol, where it starts and the
+//fragment header if there's one.
+fn walk_extension_headers(data: &[u8], mut next: u8) -> std::result::Result<(u8, usize, Option<Fragment>), PacketError> {
+    let mut offset = 40;
+    let mut fragment = None;
+    loop {
+        match next {
+            //Hop-by-hop, routing and destination options
+            0 | 43 | 60 => {
+                let header = data.get(offset..offset + 2).ok_or(PacketError::Truncated)?;
+                next = header[0];
+                offset += (header[1] as usize + 1) * 8;
+            },
+            //Fragment
+            44 => {
+                let header = data.get(offset..offset + 8).ok_or(PacketError::Truncated)?;
+                next = header[0];
+                let flags = u16::from_be_bytes([header[2], header[3]]);
+                fragment = Some((flags >> 3, flags & 1 != 0));
+                offset += 8;
+            },
+            //Authentication header, whose length is in 4-octet units minus 2
+            51 => {
+                let header = data.get(offset..offset + 2).ok_or(PacketError::Truncated)?;
+                next = header[0];
+                offset += (header[1] as usize + 2) * 4;
+            },
+            _ => {
+                if offset > data.len() {
+                    return Err(PacketError::Truncated);
+                }
+                return Ok((next, offset, fragment));
+            }
+        }
+    }
+}
+
+//The transport header at the start of `data`, and its length
+fn parse_transport(data: &[u8], protocol: u8) -> std::result::Result<(Transport, usize), PacketError> {
+    let be16 = |at: usize| u16::from_be_bytes([data[at], data[at + 1]]);
+    match protocol {
+        PROTOCOL_TCP => {
+            if data.len() < 20 {
+                return Err(PacketError::Truncated);
+            }
+            let header_len = ((data[12] >> 4) as usize) * 4;
+            if header_len < 20 {
+                return Err(PacketError::InvalidHeader(format!("TCP header length {}", header_len)));
+            }
+            if data.len() < header_len {
+                return Err(PacketError::Truncated);
+            }
+            Ok((Transport::Tcp(TcpHeader {
+                source_port: be16(0),
+                destination_port: be16(2),
+                sequence: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                acknowledgment: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+                header_len: header_len,
+                flags: data[13],
+                window: be16(14),
+                checksum: be16(16),
+                urgent_pointer: be16(18),
+            }), header_len))
+        },
+        PROTOCOL_UDP => {
+            if data.len() < 8 {
+                return Err(PacketError::Truncated);
+            }
+            Ok((Transport::Udp(UdpHeader {
+                source_port: be16(0),
+                destination_port: be16(2),
+                length: be16(4),
+                checksum: be16(6),
+            }), 8))
+        },
+        PROTOCOL_ICMP | PROTOCOL_ICMPV6 => {
+            if data.len() < 8 {
+                return Err(PacketError::Truncated);
+            }
+            let header = IcmpHeader {
+                icmp_type: data[0],
+                code: data[1],
+                checksum: be16(2),
+                rest: [data[4], data[5], data[6], data[7]],
+            };
+            if protocol == PROTOCOL_ICMP {
+                Ok((Transport::Icmp(header), 8))
+            } else {
+                Ok((Transport::Icmpv6(header), 8))
+            }
+        },
+        other => Ok((Transport::Other(other), 0))
+    }
+}
+
+//Adds the 16 bit words of `data` to `initial`, without folding
+fn sum(data: &[u8], initial: u32) -> u32 {
+    data.chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .fold(initial, |a, b| a.wrapping_add(b))
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+///Internet checksum (RFC 1071) of `data`. Zero when `data` includes a correct checksum.
+pub fn checksum(data: &[u8]) -> u16 {
+    fold(sum(data, 0))
+}
+
+fn pseudo_header_sum(source: &IpAddr, destination: &IpAddr, protocol: u8, len: usize) -> u32 {
+    let address_sum = |ip: &IpAddr| match ip {
+        IpAddr::V4(ip) => sum(&ip.octets(), 0),
+        IpAddr::V6(ip) => sum(&ip.octets(), 0),
+    };
+    address_sum(source) + address_sum(destination) + protocol as u32 + len as u32
+}
+
+//Updates the checksum at `at` after the words in `old` became `new` (RFC 1624, eqn. 3).
+//Both must have the same, even, length.
+pub(crate) fn adjust_checksum(packet: &mut [u8], at: usize, old: &[u8], new: &[u8]) {
+    let mut sum = !u16::from_be_bytes([packet[at], packet[at + 1]]) as u32;
+    for word in old.chunks(2) {
+        sum += !u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    for word in new.chunks(2) {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    packet[at..at + 2].copy_from_slice(&(!(sum as u16)).to_be_bytes());
+}
+
+///Crafts a packet, filling in lengths and checksums. Without a transport it builds an
+///empty packet of protocol 0.
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    source: IpAddr,
+    destination: IpAddr,
+    ttl: u8,
+    identification: u16,
+    protocol: u8,
+    //Transport header with its checksum zeroed, and where the checksum goes
+    transport_header: Vec<u8>,
+    checksum_at: Option<usize>,
+    payload: Vec<u8>,
+}
+
+impl PacketBuilder {
+    pub fn ipv4(source: Ipv4Addr, destination: Ipv4Addr) -> PacketBuilder {
+        PacketBuilder::new(IpAddr::V4(source), IpAddr::V4(destination))
+    }
+
+    pub fn ipv6(source: Ipv6Addr, destination: Ipv6Addr) -> PacketBuilder {
+        PacketBuilder::new(IpAddr::V6(source), IpAddr::V6(destination))
+    }
+
+    fn new(source: IpAddr, destination: IpAddr) -> PacketBuilder {
+        PacketBuilder {
+            source: source,
+            destination: destination,
+            ttl: 64,
+            identification: 0,
+            protocol: 0,
+            transport_header: Vec::new(),
+            checksum_at: None,
+            payload: Vec::new(),
+        }
+    }
+
+    ///TTL, or hop limit for IPv6
+    pub fn ttl(mut self, ttl: u8) -> PacketBuilder {
+        self.ttl = ttl;
+        self
+    }
+
+    ///IPv4 identification, ignored for IPv6
+    pub fn identification(mut self, identification: u16) -> PacketBuilder {
+        self.identification = identification;
+        self
+    }
+
+    pub fn tcp(mut self, source_port: u16, destination_port: u16, sequence: u32, acknowledgment: u32, flags: u8) -> PacketBuilder {
+        let mut header = Vec::with_capacity(20);
+        header.extend_from_slice(&source_port.to_be_bytes());
+        header.extend_from_slice(&destination_port.to_be_bytes());
+        header.extend_from_slice(&sequence.to_be_bytes());
+        header.extend_from_slice(&acknowledgment.to_be_bytes());
+        header.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        self.protocol = PROTOCOL_TCP;
+        self.transport_header = header;
+        self.checksum_at = Some(16);
+        self
+    }
+
+    pub fn udp(mut self, source_port: u16, destination_port: u16) -> PacketBuilder {
+        let mut header = Vec::with_capacity(8);
+        header.extend_from_slice(&source_port.to_be_bytes());
+        header.extend_from_slice(&destination_port.to_be_bytes());
+        //Length is filled in by build
+        header.extend_from_slice(&[0, 0, 0, 0]);
+        self.protocol = PROTOCOL_UDP;
+        self.transport_header = header;
+        self.checksum_at = Some(6);
+        self
+    }
+
+    ///ICMP, or ICMPv6 for IPv6 packets
+    pub fn icmp(mut self, icmp_type: u8, code: u8, rest: [u8; 4]) -> PacketBuilder {
+        let mut header = vec![icmp_type, code, 0, 0];
+        header.extend_from_slice(&rest);
+        self.protocol = if self.source.is_ipv6() { PROTOCOL_ICMPV6 } else { PROTOCOL_ICMP };
+        self.transport_header = header;
+        self.checksum_at = Some(2);
+        self
+    }
+
+    ///Any other protocol. The payload is sent as is, with no transport header.
+    pub fn protocol(mut self, protocol: u8) -> PacketBuilder {
+        self.protocol = protocol;
+        self.transport_header.clear();
+        self.checksum_at = None;
+        self
+    }
+
+    pub fn payload(mut self, payload: &[u8]) -> PacketBuilder {
+        self.payload = payload.to_vec();
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut segment = self.transport_header.clone();
+        segment.extend_from_slice(&self.payload);
+        if self.protocol == PROTOCOL_UDP {
+            let len = segment.len() as u16;
+            segment[4..6].copy_from_slice(&len.to_be_bytes());
+        }
+        if let Some(at) = self.checksum_at {
+            let pseudo_header = match self.protocol {
+                PROTOCOL_ICMP => 0,
+                _ => pseudo_header_sum(&self.source, &self.destination, self.protocol, segment.len())
+            };
+            let mut checksum = fold(sum(&segment, pseudo_header));
+            //Zero would mean no checksum for UDP
+            if checksum == 0 && self.protocol == PROTOCOL_UDP {
+                checksum = 0xffff;
+            }
+            segment[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+        }
+        let mut packet = match (self.source, self.destination) {
+            (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                let mut header = vec![0x45, 0];
+                header.extend_from_slice(&((20 + segment.len()) as u16).to_be_bytes());
+                header.extend_from_slice(&self.identification.to_be_bytes());
+                header.extend_from_slice(&[0x40, 0, self.ttl, self.protocol, 0, 0]);
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&destination.octets());
+                let checksum = checksum(&header);
+                header[10..12].copy_from_slice(&checksum.to_be_bytes());
+                header
+            },
+            (IpAddr::V6(source), IpAddr::V6(destination)) => {
+                let mut header = vec![0x60, 0, 0, 0];
+                header.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+                header.extend_from_slice(&[self.protocol, self.ttl]);
+                header.extend_from_slice(&source.octets());
+                header.extend_from_slice(&destination.octets());
+                header
+            },
+            //The constructors only take addresses of one family
+            _ => unreachable!()
+        };
+        packet.extend_from_slice(&segment);
+        packet
+    }
+}
@@ -0,0 +1,217 @@
+//Throwaway PKI and profiles for tests and local demos (`pki` feature)
+//
+//Generates a CA with server and client certificates (ECDSA P-256), the static key that
+//tls-auth and tls-crypt use, and renders server and client profiles with everything
+//inline, so a working setup is two files.
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose};
+
+//Certificates are valid from the start of this year for this many years
+const VALIDITY_YEARS: i32 = 2;
+const STATIC_KEY_BYTES: usize = 256;
+
+#[derive(Debug)]
+pub enum PkiError {
+    Certificate(String),
+    Random(String),
+}
+
+impl std::fmt::Display for PkiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PkiError::Certificate(s) => write!(f, "could not create certificate: {}", s),
+            PkiError::Random(s) => write!(f, "could not get random bytes: {}", s),
+        }
+    }
+}
+
+impl From<rcgen::Error> for PkiError {
+    fn from(e: rcgen::Error) -> PkiError {
+        PkiError::Certificate(e.to_string())
+    }
+}
+
+///A certificate and its private key, both PEM
+#[derive(Debug, Clone)]
+pub struct CertifiedKey {
+    pub name: String,
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Proto {
+    Udp,
+    Tcp,
+}
+
+///Extra protection of the TLS handshake with the static key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsWrap {
+    None,
+    Auth,
+    Crypt,
+}
+
+///What the rendered profiles say, beyond the keys
+#[derive(Debug, Clone)]
+pub struct ProfileOptions {
+    ///Where clients connect. The server listens on it too when it's an IP address.
+    pub remote: String,
+    pub port: u16,
+    pub proto: Proto,
+    pub tls_wrap: TlsWrap,
+    ///Tunnel network the server hands out addresses from, keeping the first for itself
+    pub network: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    ///Command the server checks usernames and passwords with, given a file holding them on
+    ///two lines. Clients are asked for credentials when it's set.
+    pub auth_user_pass_verify: Option<String>,
+}
+
+impl Default for ProfileOptions {
+    fn default() -> ProfileOptions {
+        ProfileOptions {
+            remote: "127.0.0.1".into(),
+            port: 1194,
+            proto: Proto::Udp,
+            tls_wrap: TlsWrap::None,
+            network: Ipv4Addr::new(10, 8, 0, 0),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            auth_user_pass_verify: None,
+        }
+    }
+}
+
+pub struct Pki {
+    ca_cert: Certificate,
+    ca_key: KeyPair,
+    pub ca: CertifiedKey,
+    pub server: CertifiedKey,
+    ///For tls-auth and tls-crypt, in the `openvpn --genkey secret` format
+    pub static_key: String,
+}
+
+impl Pki {
+    ///A new CA, with a server certificate for `server_name`
+    pub fn generate(server_name: &str) -> Result<Pki, PkiError> {
+        let ca_key = KeyPair::generate()?;
+        let mut params = params("libopenvpn3 CA")?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+        let ca_cert = params.self_signed(&ca_key)?;
+        let ca = CertifiedKey {
+            name: "ca".into(),
+            cert_pem: ca_cert.pem(),
+            key_pem: ca_key.serialize_pem(),
+        };
+        let server = issue(&ca_cert, &ca_key, server_name, ExtendedKeyUsagePurpose::ServerAuth)?;
+        Ok(Pki {
+            ca_cert: ca_cert,
+            ca_key: ca_key,
+            ca: ca,
+            server: server,
+            static_key: static_key()?,
+        })
+    }
+
+    ///A client certificate signed by the CA
+    pub fn client(&self, name: &str) -> Result<CertifiedKey, PkiError> {
+        issue(&self.ca_cert, &self.ca_key, name, ExtendedKeyUsagePurpose::ClientAuth)
+    }
+
+    pub fn server_profile(&self, options: &ProfileOptions) -> String {
+        let mut profile = String::new();
+        if let Ok(ip) = options.remote.parse::<IpAddr>() {
+            profile.push_str(&format!("local {}\n", ip));
+        }
+        profile.push_str(&format!("port {}\n", options.port));
+        profile.push_str(match options.proto {
+            Proto::Udp => "proto udp\n",
+            Proto::Tcp => "proto tcp-server\n",
+        });
+        profile.push_str("dev tun\ntopology subnet\n");
+        profile.push_str(&format!("server {} {}\n", options.network, options.netmask));
+        profile.push_str("dh none\nkeepalive 10 60\nverb 3\n");
+        if let Some(command) = &options.auth_user_pass_verify {
+            profile.push_str(&format!("script-security 2\nauth-user-pass-verify {} via-file\n", command));
+        }
+        profile.push_str(&inline("ca", &self.ca.cert_pem));
+        profile.push_str(&inline("cert", &self.server.cert_pem));
+        profile.push_str(&inline("key", &self.server.key_pem));
+        profile.push_str(&self.tls_wrap(options.tls_wrap, 0));
+        profile
+    }
+
+    pub fn client_profile(&self, client: &CertifiedKey, options: &ProfileOptions) -> String {
+        let mut profile = String::from("client\ndev tun\n");
+        profile.push_str(match options.proto {
+            Proto::Udp => "proto udp\n",
+            Proto::Tcp => "proto tcp\n",
+        });
+        profile.push_str(&format!("remote {} {}\n", options.remote, options.port));
+        profile.push_str("nobind\nremote-cert-tls server\nverb 3\n");
+        if options.auth_user_pass_verify.is_some() {
+            profile.push_str("auth-user-pass\n");
+        }
+        profile.push_str(&inline("ca", &self.ca.cert_pem));
+        profile.push_str(&inline("cert", &client.cert_pem));
+        profile.push_str(&inline("key", &client.key_pem));
+        profile.push_str(&self.tls_wrap(options.tls_wrap, 1));
+        profile
+    }
+
+    //The server uses key direction 0, clients 1
+    fn tls_wrap(&self, tls_wrap: TlsWrap, key_direction: u8) -> String {
+        match tls_wrap {
+            TlsWrap::None => String::new(),
+            TlsWrap::Auth => format!("key-direction {}\n{}", key_direction, inline("tls-auth", &self.static_key)),
+            TlsWrap::Crypt => inline("tls-crypt", &self.static_key),
+        }
+    }
+}
+
+///2048 random bits in the `openvpn --genkey secret` format
+pub fn static_key() -> Result<String, PkiError> {
+    let mut bytes = [0u8; STATIC_KEY_BYTES];
+    getrandom::getrandom(&mut bytes).map_err(|e| PkiError::Random(e.to_string()))?;
+    let mut key = String::from("-----BEGIN OpenVPN Static key V1-----\n");
+    for line in bytes.chunks(16) {
+        key.extend(line.iter().map(|b| format!("{:02x}", b)));
+        key.push('\n');
+    }
+    key.push_str("-----END OpenVPN Static key V1-----\n");
+    Ok(key)
+}
+
+fn issue(ca_cert: &Certificate, ca_key: &KeyPair, name: &str, usage: ExtendedKeyUsagePurpose) -> Result<CertifiedKey, PkiError> {
+    let key = KeyPair::generate()?;
+    let mut params = params(name)?;
+    params.is_ca = IsCa::ExplicitNoCa;
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature, KeyUsagePurpose::KeyAgreement];
+    params.extended_key_usages = vec![usage];
+    let cert = params.signed_by(&key, ca_cert, ca_key)?;
+    Ok(CertifiedKey {
+        name: name.to_owned(),
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+    })
+}
+
+fn params(common_name: &str) -> Result<CertificateParams, PkiError> {
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    //Close enough to the current year without a date library
+    let year = 1970 + SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 31_556_952)
+        .unwrap_or(0) as i32;
+    params.not_before = rcgen::date_time_ymd(year, 1, 1);
+    params.not_after = rcgen::date_time_ymd(year + VALIDITY_YEARS, 1, 1);
+    Ok(params)
+}
+
+fn inline(tag: &str, pem: &str) -> String {
+    let newline = if pem.ends_with('\n') { "" } else { "\n" };
+    format!("<{}>\n{}{}</{}>\n", tag, pem, newline, tag)
+}
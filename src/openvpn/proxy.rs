@@ -0,0 +1,168 @@
+//Proxy the outer transport goes through
+//
+//Corporate networks often only let traffic out through an HTTP proxy. A ProxyConfig given to
+//`OVPNClient::new` reaches the C++ library as the profile's `http-proxy`, replacing the
+//profile's own proxy or giving it credentials. When the proxy wants credentials nobody gave,
+//the client gets a `PROXY_NEED_CREDS` event. OpenVPN 3 has no SOCKS client, so profiles with
+//`socks-proxy` are refused rather than connecting around the proxy.
+//
+//OpenVPN 3's ClientAPI::Config has proxyHost, proxyUsername and the like, which would keep
+//credentials out of the profile, but openvpn_client_new only takes the profile, username and
+//password. Until the C wrapper passes them on, they're written into the profile.
+
+const NO_SOCKS: &str = "SOCKS proxies are not supported, OpenVPN 3 only goes through HTTP proxies";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyServer {
+    pub host: String,
+    pub port: u16,
+}
+
+impl ProxyServer {
+    pub fn http(host: &str, port: u16) -> ProxyServer {
+        ProxyServer { host: host.to_owned(), port: port }
+    }
+}
+
+impl std::fmt::Display for ProxyServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "http://[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "http://{}:{}", self.host, self.port)
+        }
+    }
+}
+
+///`http://host:port`, with IPv6 hosts in brackets. `socks://` and `socks5://` are refused.
+impl std::str::FromStr for ProxyServer {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<ProxyServer, String> {
+        let invalid = || format!("invalid proxy {}, expected http://HOST:PORT", s);
+        let address = match s.strip_prefix("http://") {
+            Some(address) => address,
+            None if s.starts_with("socks://") || s.starts_with("socks5://") => return Err(NO_SOCKS.to_string()),
+            None => return Err(invalid())
+        };
+        let colon = address.rfind(':').ok_or_else(invalid)?;
+        let host = address[..colon].trim_start_matches('[').trim_end_matches(']');
+        let port = address[colon + 1..].parse().map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(ProxyServer::http(host, port))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+///How a client reaches the VPN server through a proxy, see `OVPNClient::new`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyConfig {
+    ///Replaces the profile's `http-proxy`, those in `<connection>` blocks too. `None` keeps
+    ///the profile's own, for `credentials` to log in to it.
+    pub server: Option<ProxyServer>,
+    ///Without them, a proxy asking for some makes the client report `PROXY_NEED_CREDS`
+    pub credentials: Option<ProxyCredentials>,
+    ///Lets credentials go to an HTTP proxy in the clear (Basic auth). Off, only Digest and
+    ///NTLM are used, so a proxy offering just Basic fails.
+    pub allow_cleartext_auth: bool,
+}
+
+impl ProxyConfig {
+    pub fn new(server: ProxyServer) -> ProxyConfig {
+        ProxyConfig {
+            server: Some(server),
+            ..ProxyConfig::default()
+        }
+    }
+
+    pub fn with_credentials(mut self, username: &str, password: &str) -> ProxyConfig {
+        self.credentials = Some(ProxyCredentials { username: username.to_owned(), password: password.to_owned() });
+        self
+    }
+
+    ///`profile` going through the proxy. Its own proxy directives are replaced, the server
+    ///kept when `server` is `None`. Fails without a proxy to go through, with a SOCKS proxy,
+    ///with `<connection>` blocks going through different proxies and no `server` for all of
+    ///them, or with a value that would break the profile.
+    pub fn apply(&self, profile: &str) -> std::result::Result<String, String> {
+        let mut applied = String::with_capacity(profile.len() + 64);
+        let mut profile_server = None;
+        //The proxy of each <connection> block, if it has its own
+        let mut block_servers: Vec<Option<ProxyServer>> = Vec::new();
+        let mut in_connection = false;
+        let mut in_user_pass = false;
+        for line in profile.lines() {
+            let trimmed = line.trim();
+            if in_user_pass {
+                in_user_pass = trimmed != "</http-proxy-user-pass>";
+                continue;
+            }
+            if trimmed == "<http-proxy-user-pass>" {
+                in_user_pass = true;
+                continue;
+            }
+            if trimmed == "<connection>" {
+                in_connection = true;
+                block_servers.push(None);
+            } else if trimmed == "</connection>" {
+                in_connection = false;
+            }
+            let words: Vec<&str> = trimmed.split_whitespace().collect();
+            match words.first().copied() {
+                Some("socks-proxy") => return Err(NO_SOCKS.to_string()),
+                Some("http-proxy") => {
+                    let server = match (words.get(1), words.get(2).and_then(|p| p.parse().ok())) {
+                        (Some(host), Some(port)) => ProxyServer::http(host, port),
+                        _ => return Err(format!("invalid proxy in the profile: {}", trimmed))
+                    };
+                    match block_servers.last_mut() {
+                        Some(block_server) if in_connection => *block_server = Some(server),
+                        _ => profile_server = Some(server),
+                    }
+                    continue;
+                },
+                //Tied to the profile's proxy: its credentials file and extra headers
+                Some("http-proxy-user-pass") | Some("http-proxy-option") => continue,
+                _ => {}
+            }
+            applied.push_str(line);
+            applied.push('\n');
+        }
+        let server = match &self.server {
+            Some(server) => server.clone(),
+            None => {
+                //A block without its own proxy goes through the profile's, or none. There's only
+                //one proxy once applied, so they all have to agree.
+                let servers: Vec<Option<&ProxyServer>> = if block_servers.is_empty() {
+                    vec![profile_server.as_ref()]
+                } else {
+                    block_servers.iter().map(|s| s.as_ref().or(profile_server.as_ref())).collect()
+                };
+                if servers.iter().any(|s| *s != servers[0]) {
+                    return Err("the profile's <connection> blocks don't all go through the same proxy, give one server for all of them".to_string());
+                }
+                servers[0].cloned().ok_or_else(|| "no proxy server given and the profile has none".to_string())?
+            }
+        };
+        if server.host.is_empty() || server.host.contains(char::is_whitespace) {
+            return Err(format!("invalid proxy host {:?}", server.host));
+        }
+        //At the top level, for every remote. auto-nct: ask for credentials when the proxy wants
+        //some, but never send them in the clear
+        applied.push_str(&format!("http-proxy {} {} {}\n", server.host, server.port,
+            if self.allow_cleartext_auth { "auto" } else { "auto-nct" }));
+        if let Some(credentials) = &self.credentials {
+            if credentials.username.contains('\n') || credentials.password.contains('\n') {
+                return Err("proxy username and password can't have line breaks".to_string());
+            }
+            applied.push_str(&format!("<http-proxy-user-pass>\n{}\n{}\n</http-proxy-user-pass>\n", credentials.username, credentials.password));
+        }
+        Ok(applied)
+    }
+}
@@ -0,0 +1,211 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+///An IPv4 or IPv6 network, like 10.8.0.0/24 or fd00::/64
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl IpPrefix {
+    pub fn new(addr: IpAddr, len: u8) -> IpPrefix {
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        IpPrefix {
+            addr: addr,
+            len: std::cmp::min(len, max),
+        }
+    }
+
+    //Builds a prefix from an OpenVPN style `address netmask` pair
+    pub fn from_netmask(addr: Ipv4Addr, netmask: Ipv4Addr) -> IpPrefix {
+        IpPrefix::new(IpAddr::V4(addr), u32::from(netmask).count_ones() as u8)
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = if self.len == 0 { 0 } else { u32::MAX << (32 - self.len as u32) };
+                u32::from(net) & mask == u32::from(*ip) & mask
+            },
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = if self.len == 0 { 0 } else { u128::MAX << (128 - self.len as u32) };
+                u128::from(net) & mask == u128::from(*ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+impl std::fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<IpPrefix, String> {
+        let (addr, len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None)
+        };
+        let addr = IpAddr::from_str(addr).map_err(|e| format!("invalid prefix {}: {}", s, e))?;
+        let len = match len {
+            Some(len) => len.parse::<u8>().map_err(|e| format!("invalid prefix {}: {}", s, e))?,
+            None => if addr.is_ipv4() { 32 } else { 128 }
+        };
+        Ok(IpPrefix::new(addr, len))
+    }
+}
+
+//Parses a `route network [netmask] [gateway]` or `route-ipv6 network/len [gateway]` directive.
+//The bool is set when the gateway is `net_gateway`, i.e. the route bypasses the tunnel.
+pub(crate) fn parse_route(args: &[&str]) -> Option<(IpPrefix, bool)> {
+    match args {
+        ["route", network, rest @ ..] => {
+            let network = Ipv4Addr::from_str(network).ok()?;
+            let netmask = rest.first()
+                .and_then(|m| Ipv4Addr::from_str(m).ok())
+                .unwrap_or(Ipv4Addr::new(255, 255, 255, 255));
+            Some((IpPrefix::from_netmask(network, netmask), rest.get(1) == Some(&"net_gateway")))
+        },
+        ["route-ipv6", network, rest @ ..] => {
+            Some((IpPrefix::from_str(network).ok()?, rest.first() == Some(&"net_gateway")))
+        },
+        _ => None
+    }
+}
+
+///Options the server pushed to us when connecting
+///
+///OpenVPN3 applies these to its own tun builder, which we don't have in userspace, so
+///we recover them from the `PUSH_REPLY` control message the core logs and from the
+///`CONNECTED` event.
+#[derive(Debug, Clone, Default)]
+pub struct PushedConfig {
+    ///Our address (and the tunnel network) on the VPN
+    pub ipv4: Option<IpPrefix>,
+    pub ipv6: Option<IpPrefix>,
+    pub gateway_ipv4: Option<Ipv4Addr>,
+    pub gateway_ipv6: Option<Ipv6Addr>,
+    ///`tun-mtu`, if the server pushed one
+    pub mtu: Option<usize>,
+    pub routes: Vec<IpPrefix>,
+    ///Routes through `net_gateway`, meaning they should bypass the tunnel
+    pub excluded_routes: Vec<IpPrefix>,
+    pub dns_servers: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    ///Set when the server pushed `redirect-gateway`, meaning all traffic should go through the tunnel
+    pub redirect_gateway: bool,
+    ///Every pushed option, as received
+    pub options: Vec<String>,
+}
+
+impl PushedConfig {
+    ///Parses a `PUSH_REPLY,opt1,opt2,...` message. Anything before `PUSH_REPLY` is ignored so
+    ///a whole log line can be passed.
+    pub fn parse(push_reply: &str) -> Option<PushedConfig> {
+        let start = push_reply.find("PUSH_REPLY")?;
+        let mut config = PushedConfig::default();
+        for option in push_reply[start..].trim().split(',').skip(1) {
+            config.apply(option.trim());
+        }
+        Some(config)
+    }
+
+    fn apply(&mut self, option: &str) {
+        if option.is_empty() {
+            return;
+        }
+        self.options.push(option.to_owned());
+        let args: Vec<&str> = option.split_whitespace().collect();
+        match args.as_slice() {
+            ["ifconfig", local, remote_or_mask, ..] => {
+                if let (Ok(local), Ok(other)) = (Ipv4Addr::from_str(local), Ipv4Addr::from_str(remote_or_mask)) {
+                    //topology subnet pushes a netmask, net30/p2p push the remote endpoint
+                    if other.octets()[0] == 255 {
+                        self.ipv4 = Some(IpPrefix::from_netmask(local, other));
+                    } else {
+                        self.ipv4 = Some(IpPrefix::new(IpAddr::V4(local), 32));
+                        if self.gateway_ipv4.is_none() {
+                            self.gateway_ipv4 = Some(other);
+                        }
+                    }
+                }
+            },
+            ["ifconfig-ipv6", local, rest @ ..] => {
+                if let Ok(prefix) = IpPrefix::from_str(local) {
+                    self.ipv6 = Some(prefix);
+                }
+                if let Some(Ok(gateway)) = rest.first().map(|g| Ipv6Addr::from_str(g)) {
+                    self.gateway_ipv6 = Some(gateway);
+                }
+            },
+            ["route-gateway", gateway, ..] => {
+                if let Ok(gateway) = Ipv4Addr::from_str(gateway) {
+                    self.gateway_ipv4 = Some(gateway);
+                }
+            },
+            ["route", ..] | ["route-ipv6", ..] => {
+                match parse_route(&args) {
+                    Some((prefix, false)) => self.routes.push(prefix),
+                    Some((prefix, true)) => self.excluded_routes.push(prefix),
+                    None => {}
+                }
+            },
+            ["dhcp-option", "DNS", server, ..] | ["dhcp-option", "DNS6", server, ..] => {
+                if let Ok(server) = IpAddr::from_str(server) {
+                    self.dns_servers.push(server);
+                }
+            },
+            ["dhcp-option", "DOMAIN", domain, ..] | ["dhcp-option", "DOMAIN-SEARCH", domain, ..] => {
+                self.search_domains.push(domain.to_string());
+            },
+            ["tun-mtu", mtu, ..] => {
+                self.mtu = mtu.parse().ok();
+            },
+            ["redirect-gateway", ..] => {
+                self.redirect_gateway = true;
+            },
+            _ => {}
+        }
+    }
+
+    ///Fills what's missing from the `CONNECTED` event info, which looks like
+    ///`user@host:port (server_ip) via UDPv4 on tun/10.8.0.2/fd00::2 gw=[10.8.0.1/fd00::1]`
+    pub fn apply_connected_info(&mut self, info: &str) {
+        let on = match info.rfind(" on ") {
+            Some(i) => &info[i + 4..],
+            None => return
+        };
+        let (tun, gateways) = match on.find(" gw=[") {
+            Some(i) => (&on[..i], on[i + 5..].trim_end_matches(']')),
+            None => (on, "")
+        };
+        //Both fields are read even when set, so the IPv6 one is always the second
+        let mut tun = tun.split('/').skip(1);
+        let (tun_ipv4, tun_ipv6) = (tun.next(), tun.next());
+        if self.ipv4.is_none() {
+            if let Some(Ok(ip)) = tun_ipv4.map(Ipv4Addr::from_str) {
+                self.ipv4 = Some(IpPrefix::new(IpAddr::V4(ip), 32));
+            }
+        }
+        if self.ipv6.is_none() {
+            if let Some(Ok(ip)) = tun_ipv6.map(Ipv6Addr::from_str) {
+                self.ipv6 = Some(IpPrefix::new(IpAddr::V6(ip), 128));
+            }
+        }
+        let mut gateways = gateways.split('/');
+        let (gateway_ipv4, gateway_ipv6) = (gateways.next(), gateways.next());
+        if self.gateway_ipv4.is_none() {
+            self.gateway_ipv4 = gateway_ipv4.and_then(|g| Ipv4Addr::from_str(g).ok());
+        }
+        if self.gateway_ipv6.is_none() {
+            self.gateway_ipv6 = gateway_ipv6.and_then(|g| Ipv6Addr::from_str(g).ok());
+        }
+    }
+}
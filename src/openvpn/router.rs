@@ -0,0 +1,187 @@
+//Picks which tunnel a packet or connection goes through when several are running
+//
+//Rules are checked in order. A rule matches by destination prefix, domain or port and
+//names one or more tunnels plus a strategy to pick among them. Tunnels that are down
+//are skipped, and when none of a rule's tunnels is up the next matching rule is tried,
+//so listing a fallback rule after a specific one gives failover.
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use super::manager::{TunnelManager, TunnelState};
+use super::packet::Packet;
+use super::pushed::IpPrefix;
+
+///What the router needs to know about tunnels. Implemented by TunnelManager; implement it
+///yourself to route over clients you manage by hand.
+pub trait TunnelStatus {
+    fn is_up(&self, tunnel: &str) -> bool;
+    ///Anything that grows with how busy the tunnel is
+    fn load(&self, tunnel: &str) -> u64;
+}
+
+impl TunnelStatus for TunnelManager {
+    fn is_up(&self, tunnel: &str) -> bool {
+        self.state(tunnel) == Some(TunnelState::Connected)
+    }
+
+    //Total bytes moved through the tunnel
+    fn load(&self, tunnel: &str) -> u64 {
+        self.client(tunnel)
+            .map(|client| {
+                let stats = client.lock().unwrap().stats();
+                stats.bytes_sent + stats.bytes_received
+            })
+            .unwrap_or(u64::MAX)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteMatch {
+    ///Destination address inside the prefix
+    Prefix(IpPrefix),
+    ///Destination is the domain or one of its subdomains
+    Domain(String),
+    ///Destination port in the inclusive range
+    Ports(u16, u16),
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    ///First tunnel that's up, in the order given
+    Failover,
+    RoundRobin,
+    ///Tunnel with the lowest `TunnelStatus::load`
+    LeastLoaded,
+}
+
+#[derive(Debug)]
+pub struct RouteRule {
+    pub matcher: RouteMatch,
+    pub tunnels: Vec<String>,
+    pub strategy: Strategy,
+    //Round robin position
+    next: AtomicUsize,
+}
+
+impl RouteRule {
+    pub fn new(matcher: RouteMatch, tunnels: Vec<String>, strategy: Strategy) -> RouteRule {
+        RouteRule {
+            matcher: matcher,
+            tunnels: tunnels,
+            strategy: strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn matches(&self, destination: &Destination) -> bool {
+        match &self.matcher {
+            RouteMatch::Prefix(prefix) => destination.ip.map(|ip| prefix.contains(&ip)).unwrap_or(false),
+            RouteMatch::Domain(domain) => destination.domain.as_ref()
+                .map(|d| domain_matches(d, domain))
+                .unwrap_or(false),
+            RouteMatch::Ports(first, last) => destination.port
+                .map(|port| *first <= port && port <= *last)
+                .unwrap_or(false),
+            RouteMatch::Any => true,
+        }
+    }
+
+    fn pick<S: TunnelStatus + ?Sized>(&self, status: &S) -> Option<String> {
+        let up: Vec<&String> = self.tunnels.iter().filter(|t| status.is_up(t)).collect();
+        if up.is_empty() {
+            return None;
+        }
+        let picked = match self.strategy {
+            Strategy::Failover => up[0],
+            Strategy::RoundRobin => up[self.next.fetch_add(1, Ordering::Relaxed) % up.len()],
+            Strategy::LeastLoaded => up.iter().min_by_key(|t| status.load(t)).unwrap(),
+        };
+        Some(picked.clone())
+    }
+}
+
+fn domain_matches(domain: &str, rule: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    let rule = rule.trim_end_matches('.');
+    domain.eq_ignore_ascii_case(rule)
+        || (domain.len() > rule.len()
+            && domain.as_bytes()[domain.len() - rule.len() - 1] == b'.'
+            && domain[domain.len() - rule.len()..].eq_ignore_ascii_case(rule))
+}
+
+//Everything we know about where something is going
+struct Destination {
+    ip: Option<IpAddr>,
+    domain: Option<String>,
+    port: Option<u16>,
+}
+
+#[derive(Default)]
+pub struct TunnelRouter {
+    rules: Vec<RouteRule>,
+    //Filled by the DNS hook so domain rules also apply to packets
+    resolved: Mutex<HashMap<IpAddr, String>>,
+}
+
+impl TunnelRouter {
+    pub fn new() -> TunnelRouter {
+        TunnelRouter::default()
+    }
+
+    pub fn add_rule(&mut self, rule: RouteRule) {
+        self.rules.push(rule);
+    }
+
+    ///DNS hook: remembers that `domain` resolved to `addrs`, so packets to those
+    ///addresses match rules for `domain`
+    pub fn record_resolution(&self, domain: &str, addrs: &[IpAddr]) {
+        let mut resolved = self.resolved.lock().unwrap();
+        for addr in addrs {
+            resolved.insert(*addr, domain.to_owned());
+        }
+    }
+
+    ///Tunnel for a connection to `addr`
+    pub fn route_addr<S: TunnelStatus + ?Sized>(&self, status: &S, addr: &SocketAddr) -> Option<String> {
+        let domain = self.resolved.lock().unwrap().get(&addr.ip()).cloned();
+        self.route(status, &Destination {
+            ip: Some(addr.ip()),
+            domain: domain,
+            port: Some(addr.port()),
+        })
+    }
+
+    ///Tunnel for a connection to `domain`, before it's resolved (proxies know the name)
+    pub fn route_domain<S: TunnelStatus + ?Sized>(&self, status: &S, domain: &str, port: u16) -> Option<String> {
+        self.route(status, &Destination {
+            ip: None,
+            domain: Some(domain.to_owned()),
+            port: Some(port),
+        })
+    }
+
+    ///Tunnel for an IP packet, judging by its destination address and TCP/UDP port
+    pub fn route_packet<S: TunnelStatus + ?Sized>(&self, status: &S, packet: &[u8]) -> Option<String> {
+        let (ip, port) = packet_destination(packet)?;
+        let domain = self.resolved.lock().unwrap().get(&ip).cloned();
+        self.route(status, &Destination {
+            ip: Some(ip),
+            domain: domain,
+            port: port,
+        })
+    }
+
+    fn route<S: TunnelStatus + ?Sized>(&self, status: &S, destination: &Destination) -> Option<String> {
+        self.rules.iter()
+            .filter(|rule| rule.matches(destination))
+            .find_map(|rule| rule.pick(status))
+    }
+}
+
+//Destination address and, for TCP/UDP, port
+fn packet_destination(packet: &[u8]) -> Option<(IpAddr, Option<u16>)> {
+    let packet = Packet::parse(packet).ok()?;
+    Some((packet.destination(), packet.destination_port()))
+}
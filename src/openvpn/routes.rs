@@ -0,0 +1,111 @@
+//Split tunneling: decides which destinations go through the tunnel
+//
+//In userspace nobody applies `route` and `redirect-gateway`, so this table collects
+//them from the profile and the pushed options and answers `should_tunnel` for the
+//stack and the proxies. User includes/excludes take precedence over everything else;
+//within each group the longest matching prefix wins.
+use std::net::IpAddr;
+use super::pushed::{parse_route, IpPrefix, PushedConfig};
+
+#[derive(Debug, Clone, Default)]
+pub struct RouteTable {
+    //From the profile and the server
+    routes: Vec<IpPrefix>,
+    excluded_routes: Vec<IpPrefix>,
+    redirect_gateway: bool,
+    //`route-nopull`: ignore routes the server pushes
+    no_pull: bool,
+    //User overrides
+    included: Vec<IpPrefix>,
+    excluded: Vec<IpPrefix>,
+}
+
+impl RouteTable {
+    pub fn new() -> RouteTable {
+        RouteTable::default()
+    }
+
+    ///Collects `route`, `route-ipv6`, `redirect-gateway` and `route-nopull` from a profile
+    pub fn from_profile(profile: &str) -> RouteTable {
+        let mut table = RouteTable::new();
+        let mut inline_block = false;
+        for line in profile.lines() {
+            let line = line.trim();
+            //Skip inline certificates and keys
+            if line.starts_with("</") {
+                inline_block = false;
+                continue;
+            }
+            if line.starts_with('<') {
+                inline_block = true;
+                continue;
+            }
+            if inline_block || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            let args: Vec<&str> = line.split_whitespace().collect();
+            match args.as_slice() {
+                ["redirect-gateway", ..] => table.redirect_gateway = true,
+                ["route-nopull"] => table.no_pull = true,
+                _ => table.add_route(parse_route(&args))
+            }
+        }
+        table
+    }
+
+    ///Adds what the server pushed, unless the profile has `route-nopull`. The tunnel's own
+    ///network always goes through the tunnel.
+    pub fn apply_pushed(&mut self, pushed: &PushedConfig) {
+        for own_network in pushed.ipv4.iter().chain(pushed.ipv6.iter()) {
+            self.routes.push(*own_network);
+        }
+        if self.no_pull {
+            return;
+        }
+        self.routes.extend(pushed.routes.iter().cloned());
+        self.excluded_routes.extend(pushed.excluded_routes.iter().cloned());
+        self.redirect_gateway |= pushed.redirect_gateway;
+    }
+
+    fn add_route(&mut self, route: Option<(IpPrefix, bool)>) {
+        match route {
+            Some((prefix, false)) => self.routes.push(prefix),
+            Some((prefix, true)) => self.excluded_routes.push(prefix),
+            None => {}
+        }
+    }
+
+    ///Forces `prefix` through the tunnel
+    pub fn include(&mut self, prefix: IpPrefix) {
+        self.included.push(prefix);
+    }
+
+    ///Keeps `prefix` off the tunnel, like the LAN or corporate ranges
+    pub fn exclude(&mut self, prefix: IpPrefix) {
+        self.excluded.push(prefix);
+    }
+
+    pub fn should_tunnel(&self, dst: &IpAddr) -> bool {
+        if let Some(tunnel) = longest_match(dst, &self.included, &self.excluded) {
+            return tunnel;
+        }
+        if let Some(tunnel) = longest_match(dst, &self.routes, &self.excluded_routes) {
+            return tunnel;
+        }
+        self.redirect_gateway
+    }
+}
+
+//Some(true) if the longest prefix containing `dst` is in `tunneled`, Some(false) if it's
+//in `bypassed`, None if neither contains it. Ties go to the bypass.
+fn longest_match(dst: &IpAddr, tunneled: &[IpPrefix], bypassed: &[IpPrefix]) -> Option<bool> {
+    let longest = |prefixes: &[IpPrefix]| prefixes.iter()
+        .filter(|p| p.contains(dst))
+        .map(|p| p.len as i16)
+        .max()
+        .unwrap_or(-1);
+    match (longest(tunneled), longest(bypassed)) {
+        (-1, -1) => None,
+        (tunneled, bypassed) => Some(tunneled > bypassed)
+    }
+}
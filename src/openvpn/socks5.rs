@@ -0,0 +1,329 @@
+//Local SOCKS5 server whose connections go through the tunnel (`socks5` feature)
+//
+//Lets apps that can't link this crate (browsers, anything non-Rust on Android) use a
+//per-profile VPN: they talk SOCKS5 to a local port and we dial out through the
+//userspace stack. Supports CONNECT and UDP ASSOCIATE (RFC 1928), with optional
+//username/password authentication (RFC 1929). Destinations the stack's route table keeps
+//off the tunnel are reached directly. Domains are resolved with the server's Resolver;
+//give it a TunnelResolver so lookups don't leak outside the tunnel.
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use super::dns::{self, Resolver, SystemResolver};
+use super::stack::{self, Stack};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USER_PASS: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+const REPLY_GENERAL_FAILURE: u8 = 1;
+const REPLY_HOST_UNREACHABLE: u8 = 4;
+const REPLY_CONNECTION_REFUSED: u8 = 5;
+const REPLY_TTL_EXPIRED: u8 = 6;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 7;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 8;
+//How often UDP relay threads check whether the association ended
+const UDP_RELAY_TICK: Duration = Duration::from_secs(1);
+
+///Destination requested by a SOCKS client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Target {
+    fn resolve(&self, resolver: &dyn Resolver) -> io::Result<SocketAddr> {
+        match self {
+            Target::Ip(addr) => Ok(*addr),
+            Target::Domain(domain, port) => dns::resolve_addr(resolver, domain, *port)
+        }
+    }
+
+    fn read_from<R: Read>(reader: &mut R, atyp: u8) -> io::Result<Target> {
+        match atyp {
+            ATYP_IPV4 => {
+                let mut b = [0u8; 6];
+                reader.read_exact(&mut b)?;
+                let ip = Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+                Ok(Target::Ip(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([b[4], b[5]]))))
+            },
+            ATYP_IPV6 => {
+                let mut b = [0u8; 18];
+                reader.read_exact(&mut b)?;
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&b[..16]);
+                let ip = Ipv6Addr::from(octets);
+                Ok(Target::Ip(SocketAddr::new(IpAddr::V6(ip), u16::from_be_bytes([b[16], b[17]]))))
+            },
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                reader.read_exact(&mut len)?;
+                let mut domain = vec![0u8; len[0] as usize];
+                reader.read_exact(&mut domain)?;
+                let mut port = [0u8; 2];
+                reader.read_exact(&mut port)?;
+                let domain = String::from_utf8(domain).map_err(|_| io::Error::new(ErrorKind::InvalidData, "domain is not utf8"))?;
+                Ok(Target::Domain(domain, u16::from_be_bytes(port)))
+            },
+            _ => Err(io::Error::new(ErrorKind::InvalidData, format!("unknown address type {}", atyp)))
+        }
+    }
+}
+
+//ATYP, address and port, as used in replies and UDP headers
+fn encode_addr(addr: &SocketAddr, out: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        },
+        IpAddr::V6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+    }
+    out.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn reply<W: Write>(writer: &mut W, code: u8, bound: &SocketAddr) -> io::Result<()> {
+    let mut r = vec![VERSION, code, 0];
+    encode_addr(bound, &mut r);
+    writer.write_all(&r)
+}
+
+fn reply_code(e: &io::Error) -> u8 {
+    match e.kind() {
+        ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REPLY_TTL_EXPIRED,
+        ErrorKind::NotFound | ErrorKind::AddrNotAvailable => REPLY_HOST_UNREACHABLE,
+        _ => REPLY_GENERAL_FAILURE
+    }
+}
+
+pub struct Socks5Server {
+    listener: std::net::TcpListener,
+    stack: Arc<Stack>,
+    resolver: Arc<dyn Resolver>,
+    credentials: Option<(String, String)>,
+}
+
+impl Socks5Server {
+    pub fn bind(stack: Arc<Stack>, addr: SocketAddr) -> io::Result<Socks5Server> {
+        Ok(Socks5Server {
+            listener: std::net::TcpListener::bind(addr)?,
+            stack: stack,
+            resolver: Arc::new(SystemResolver),
+            credentials: None,
+        })
+    }
+
+    ///Requires clients to authenticate with `username` and `password`
+    pub fn with_credentials(mut self, username: &str, password: &str) -> Socks5Server {
+        self.credentials = Some((username.to_owned(), password.to_owned()));
+        self
+    }
+
+    ///Resolves the domains clients ask for with `resolver` instead of the system resolver
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Socks5Server {
+        self.resolver = resolver;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    ///Accepts clients forever, each on its own thread
+    pub fn run(&self) -> io::Result<()> {
+        for client in self.listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(_) => continue
+            };
+            let stack = self.stack.clone();
+            let resolver = self.resolver.clone();
+            let credentials = self.credentials.clone();
+            std::thread::spawn(move || {
+                let _ = handle_client(client, stack, resolver, credentials);
+            });
+        }
+        Ok(())
+    }
+}
+
+fn handle_client(mut client: std::net::TcpStream, stack: Arc<Stack>, resolver: Arc<dyn Resolver>, credentials: Option<(String, String)>) -> io::Result<()> {
+    authenticate(&mut client, &credentials)?;
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    if request[0] != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a SOCKS5 request"));
+    }
+    let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let target = match Target::read_from(&mut client, request[3]) {
+        Ok(target) => target,
+        Err(e) => {
+            reply(&mut client, REPLY_ADDRESS_NOT_SUPPORTED, &unspecified)?;
+            return Err(e);
+        }
+    };
+    match request[1] {
+        CMD_CONNECT => connect(client, &stack, &*resolver, &target),
+        CMD_UDP_ASSOCIATE => udp_associate(client, stack, resolver),
+        _ => reply(&mut client, REPLY_COMMAND_NOT_SUPPORTED, &unspecified)
+    }
+}
+
+fn authenticate(client: &mut std::net::TcpStream, credentials: &Option<(String, String)>) -> io::Result<()> {
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods)?;
+    let wanted = if credentials.is_some() { METHOD_USER_PASS } else { METHOD_NO_AUTH };
+    if !methods.contains(&wanted) {
+        client.write_all(&[VERSION, METHOD_NONE_ACCEPTABLE])?;
+        return Err(io::Error::new(ErrorKind::PermissionDenied, "no acceptable authentication method"));
+    }
+    client.write_all(&[VERSION, wanted])?;
+    if let Some((username, password)) = credentials {
+        let mut version = [0u8; 2];
+        client.read_exact(&mut version)?;
+        let mut given_username = vec![0u8; version[1] as usize];
+        client.read_exact(&mut given_username)?;
+        let mut len = [0u8; 1];
+        client.read_exact(&mut len)?;
+        let mut given_password = vec![0u8; len[0] as usize];
+        client.read_exact(&mut given_password)?;
+        if given_username != username.as_bytes() || given_password != password.as_bytes() {
+            client.write_all(&[1, 1])?;
+            return Err(io::Error::new(ErrorKind::PermissionDenied, "wrong SOCKS credentials"));
+        }
+        client.write_all(&[1, 0])?;
+    }
+    Ok(())
+}
+
+fn connect(mut client: std::net::TcpStream, stack: &Stack, resolver: &dyn Resolver, target: &Target) -> io::Result<()> {
+    let unspecified = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+    let remote = match target.resolve(resolver).and_then(|addr| stack::dial(stack, addr)) {
+        Ok(remote) => remote,
+        Err(e) => {
+            reply(&mut client, reply_code(&e), &unspecified)?;
+            return Err(e);
+        }
+    };
+    reply(&mut client, REPLY_SUCCEEDED, &remote.local_addr().unwrap_or(unspecified))?;
+    stack::relay(client, remote)
+}
+
+fn udp_associate(mut client: std::net::TcpStream, stack: Arc<Stack>, resolver: Arc<dyn Resolver>) -> io::Result<()> {
+    let local = std::net::UdpSocket::bind(SocketAddr::new(client.local_addr()?.ip(), 0))?;
+    local.set_read_timeout(Some(UDP_RELAY_TICK))?;
+    let mut remote = stack::UdpSocket::bind(&stack, 0)?;
+    remote.set_read_timeout(Some(UDP_RELAY_TICK));
+    //For destinations split tunneling keeps off the tunnel
+    let direct = std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0))
+        .or_else(|_| std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0)))?;
+    direct.set_read_timeout(Some(UDP_RELAY_TICK))?;
+    reply(&mut client, REPLY_SUCCEEDED, &local.local_addr()?)?;
+
+    let local = Arc::new(local);
+    let remote = Arc::new(remote);
+    let direct = Arc::new(direct);
+    let done = Arc::new(AtomicBool::new(false));
+    //Where the SOCKS client sends datagrams from, learned from its first datagram
+    let client_udp_addr: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+    let client_ip = client.peer_addr()?.ip();
+
+    let upload = {
+        let (local, remote, direct, done, client_udp_addr) = (local.clone(), remote.clone(), direct.clone(), done.clone(), client_udp_addr.clone());
+        std::thread::spawn(move || {
+            let mut buffer = vec![0u8; 65536];
+            while !done.load(Ordering::Relaxed) {
+                let (size, from) = match local.recv_from(&mut buffer) {
+                    Ok(r) => r,
+                    Err(_) => continue
+                };
+                if from.ip() != client_ip {
+                    continue;
+                }
+                *client_udp_addr.lock().unwrap() = Some(from);
+                //RSV RSV FRAG ATYP ..., fragmented datagrams are dropped
+                if size < 4 || buffer[2] != 0 {
+                    continue;
+                }
+                let mut header = &buffer[4..size];
+                let target = match Target::read_from(&mut header, buffer[3]).and_then(|t| t.resolve(&*resolver)) {
+                    Ok(target) => target,
+                    Err(_) => continue
+                };
+                if stack.should_tunnel(&target.ip()) {
+                    let _ = remote.send_to(header, target);
+                } else {
+                    let _ = direct.send_to(header, target);
+                }
+            }
+        })
+    };
+    let download_tunnel = {
+        let (local, remote, done, client_udp_addr) = (local.clone(), remote.clone(), done.clone(), client_udp_addr.clone());
+        std::thread::spawn(move || download(|b| remote.recv_from(b), &local, &done, &client_udp_addr))
+    };
+    let download_direct = {
+        let (local, direct, done, client_udp_addr) = (local.clone(), direct.clone(), done.clone(), client_udp_addr.clone());
+        std::thread::spawn(move || download(|b| direct.recv_from(b), &local, &done, &client_udp_addr))
+    };
+    //The association lasts as long as the TCP connection that asked for it
+    let mut discard = [0u8; 64];
+    while let Ok(n) = client.read(&mut discard) {
+        if n == 0 {
+            break;
+        }
+    }
+    done.store(true, Ordering::Relaxed);
+    let _ = upload.join();
+    let _ = download_tunnel.join();
+    let _ = download_direct.join();
+    Ok(())
+}
+
+//Wraps datagrams from `recv_from` in SOCKS UDP headers and sends them to the client
+fn download<F>(mut recv_from: F, local: &std::net::UdpSocket, done: &AtomicBool, client_udp_addr: &Mutex<Option<SocketAddr>>)
+where
+    F: FnMut(&mut [u8]) -> io::Result<(usize, SocketAddr)>,
+{
+    let mut buffer = vec![0u8; 65536];
+    while !done.load(Ordering::Relaxed) {
+        let (size, from) = match recv_from(&mut buffer) {
+            Ok(r) => r,
+            Err(_) => continue
+        };
+        let to = match *client_udp_addr.lock().unwrap() {
+            Some(to) => to,
+            None => continue
+        };
+        //The direct socket is dual-stack, IPv4 peers come back v4-mapped
+        let from = match from {
+            SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+                Some(ip) => SocketAddr::new(IpAddr::V4(ip), v6.port()),
+                None => from
+            },
+            from => from
+        };
+        let mut datagram = vec![0, 0, 0];
+        encode_addr(&from, &mut datagram);
+        datagram.extend_from_slice(&buffer[..size]);
+        let _ = local.send_to(&datagram, to);
+    }
+}
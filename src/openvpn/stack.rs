@@ -0,0 +1,677 @@
+//Userspace TCP/IP stack over an OVPNClient (`stack` feature)
+//
+//Wraps the client in a smoltcp interface so applications can open TCP and UDP sockets
+//that go through the tunnel, without a tun device. A background thread polls the
+//interface; `TcpStream` and `UdpSocket` block on a condvar until it makes progress.
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::socket::{tcp, udp, AnySocket};
+use smoltcp::time::Instant;
+use smoltcp::wire::{HardwareAddress, IpCidr, IpEndpoint};
+use super::openvpn::{OVPNClient, OpenVpnReceiveError, OpenVpnSendError};
+use super::pushed::{IpPrefix, PushedConfig};
+use super::routes::RouteTable;
+
+const DEFAULT_MTU: usize = 1500;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+//Longest the poll thread sleeps, since OVPNClient has no way to wake us when a packet arrives
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+const EPHEMERAL_PORTS: std::ops::Range<u16> = 49152..65535;
+//The poll thread empties a full UDP send buffer within a few polls, longer means it's gone
+const UDP_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct StackConfig {
+    ///Addresses of the stack. When the client was created with replacement IPs, these
+    ///should be the replacement IPs, since that's what its NAT rewrites to and from.
+    pub ipv4: Option<IpPrefix>,
+    pub ipv6: Option<IpPrefix>,
+    pub gateway_ipv4: Option<Ipv4Addr>,
+    pub gateway_ipv6: Option<Ipv6Addr>,
+    pub mtu: usize,
+    ///Size of each TCP socket's receive and send buffers
+    pub tcp_buffer_size: usize,
+    ///Size of each UDP socket's receive and send buffers
+    pub udp_buffer_size: usize,
+    ///Split tunneling. The proxies connect directly to destinations it keeps off the
+    ///tunnel. None sends everything through the tunnel.
+    pub routes: Option<RouteTable>,
+}
+
+impl StackConfig {
+    ///Configuration matching what the server pushed
+    pub fn from_pushed(pushed: &PushedConfig) -> StackConfig {
+        StackConfig {
+            ipv4: pushed.ipv4,
+            ipv6: pushed.ipv6,
+            gateway_ipv4: pushed.gateway_ipv4,
+            gateway_ipv6: pushed.gateway_ipv6,
+            mtu: pushed.mtu.unwrap_or(DEFAULT_MTU),
+            ..StackConfig::default()
+        }
+    }
+}
+
+impl Default for StackConfig {
+    fn default() -> StackConfig {
+        StackConfig {
+            ipv4: None,
+            ipv6: None,
+            gateway_ipv4: None,
+            gateway_ipv6: None,
+            mtu: DEFAULT_MTU,
+            tcp_buffer_size: 64 * 1024,
+            udp_buffer_size: 16 * 1024,
+            routes: None,
+        }
+    }
+}
+
+//smoltcp device that sends and receives through the OpenVPN client
+struct TunnelDevice {
+    client: OVPNClient,
+    mtu: usize,
+    rx_queue: VecDeque<Vec<u8>>,
+    //Backend failures, counted so a waiting socket can tell one happened while it waited
+    errors: u64,
+    last_error: Option<String>,
+}
+
+impl TunnelDevice {
+    fn failed(&mut self, error: String) {
+        self.errors += 1;
+        self.last_error = Some(error);
+    }
+}
+
+impl Device for TunnelDevice {
+    type RxToken<'a> = TunnelRxToken where Self: 'a;
+    type TxToken<'a> = TunnelTxToken<'a> where Self: 'a;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        if self.rx_queue.is_empty() {
+            let rx_queue = &mut self.rx_queue;
+            //To smoltcp an error is nothing to receive, the sockets waiting hear about it
+            if let Err(OpenVpnReceiveError::Unknown(e)) = self.client.receive(&mut |packet| rx_queue.push_back(packet.to_vec())) {
+                self.failed(e);
+            }
+        }
+        let buffer = self.rx_queue.pop_front()?;
+        let TunnelDevice { client, errors, last_error, .. } = self;
+        Some((TunnelRxToken { buffer: buffer }, TunnelTxToken { client: client, errors: errors, last_error: last_error }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        let TunnelDevice { client, errors, last_error, .. } = self;
+        Some(TunnelTxToken { client: client, errors: errors, last_error: last_error })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = self.mtu;
+        capabilities
+    }
+}
+
+struct TunnelRxToken {
+    buffer: Vec<u8>,
+}
+
+impl phy::RxToken for TunnelRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.buffer)
+    }
+}
+
+struct TunnelTxToken<'a> {
+    client: &'a OVPNClient,
+    errors: &'a mut u64,
+    last_error: &'a mut Option<String>,
+}
+
+impl<'a> phy::TxToken for TunnelTxToken<'a> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0u8; len];
+        let r = f(&mut buffer);
+        //A packet bigger than the MTU is lost like any other and TCP retransmits it, but a
+        //backend failure is reported to the sockets waiting
+        if let Err(OpenVpnSendError::Unknown(e)) = self.client.send(&buffer) {
+            *self.errors += 1;
+            *self.last_error = Some(e);
+        }
+        r
+    }
+}
+
+struct StackInner {
+    iface: Interface,
+    device: TunnelDevice,
+    sockets: SocketSet<'static>,
+    //Dropped TCP sockets still finishing their close handshake
+    closing: Vec<SocketHandle>,
+    next_port: u16,
+    tcp_buffer_size: usize,
+    udp_buffer_size: usize,
+}
+
+impl StackInner {
+    fn poll(&mut self) -> bool {
+        let StackInner { iface, device, sockets, .. } = self;
+        let changed = iface.poll(Instant::now(), device, sockets);
+        let sockets = &mut self.sockets;
+        self.closing.retain(|handle| {
+            let state = sockets.get::<tcp::Socket>(*handle).state();
+            if state == tcp::State::Closed || state == tcp::State::TimeWait {
+                sockets.remove(*handle);
+                false
+            } else {
+                true
+            }
+        });
+        changed
+    }
+
+    //The next ephemeral port no socket is using
+    fn ephemeral_port(&mut self) -> io::Result<u16> {
+        for _ in EPHEMERAL_PORTS {
+            let port = self.next_port;
+            self.next_port = if port + 1 == EPHEMERAL_PORTS.end { EPHEMERAL_PORTS.start } else { port + 1 };
+            if !self.tcp_port_in_use(port) && !self.udp_port_in_use(port) {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(ErrorKind::AddrInUse, "no ephemeral port left"))
+    }
+
+    fn tcp_port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().filter_map(|(_, socket)| tcp::Socket::downcast(socket))
+            .any(|socket| socket.local_endpoint().map(|e| e.port) == Some(port))
+    }
+
+    fn udp_port_in_use(&self, port: u16) -> bool {
+        self.sockets.iter().filter_map(|(_, socket)| udp::Socket::downcast(socket))
+            .any(|socket| socket.endpoint().port == port)
+    }
+}
+
+struct Shared {
+    inner: Mutex<StackInner>,
+    //Notified after every poll that changed something, and by sockets that queued data
+    changed: Condvar,
+    stop: AtomicBool,
+}
+
+impl Shared {
+    //Runs `f` until it returns Some, waiting for the poll thread in between
+    fn wait<T, F>(&self, timeout: Option<Duration>, mut f: F) -> io::Result<T>
+    where
+        F: FnMut(&mut StackInner) -> Option<io::Result<T>>,
+    {
+        let deadline = timeout.map(|timeout| std::time::Instant::now() + timeout);
+        let mut inner = self.inner.lock().unwrap();
+        let errors = inner.device.errors;
+        loop {
+            if let Some(r) = f(&mut inner) {
+                drop(inner);
+                self.changed.notify_all();
+                return r;
+            }
+            if inner.device.errors != errors {
+                let e = inner.device.last_error.clone().unwrap_or_default();
+                return Err(io::Error::other(format!("tunnel: {}", e)));
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                return Err(io::Error::new(ErrorKind::NotConnected, "stack stopped"));
+            }
+            inner = match deadline {
+                Some(deadline) => {
+                    let now = std::time::Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(ErrorKind::TimedOut, "timed out"));
+                    }
+                    self.changed.wait_timeout(inner, deadline - now).unwrap().0
+                },
+                None => self.changed.wait(inner).unwrap()
+            };
+        }
+    }
+}
+
+///A smoltcp interface bound to an OVPNClient
+pub struct Stack {
+    shared: Arc<Shared>,
+    config: StackConfig,
+    poll_thread: Option<JoinHandle<()>>,
+}
+
+impl Stack {
+    ///Takes ownership of a connected client. Use `with_client` to reach it afterwards.
+    pub fn new(client: OVPNClient, config: StackConfig) -> io::Result<Stack> {
+        if config.ipv4.is_none() && config.ipv6.is_none() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "stack needs an IPv4 or IPv6 address"));
+        }
+        let mut device = TunnelDevice {
+            client: client,
+            mtu: config.mtu,
+            rx_queue: VecDeque::new(),
+            errors: 0,
+            last_error: None,
+        };
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let mut iface_config = Config::new(HardwareAddress::Ip);
+        iface_config.random_seed = seed;
+        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addrs| {
+            for prefix in config.ipv4.iter().chain(config.ipv6.iter()) {
+                let _ = addrs.push(IpCidr::new(prefix.addr.into(), prefix.len));
+            }
+        });
+        //Everything goes out through the tunnel, so the gateway only has to be an address
+        //smoltcp accepts. Fall back to our own when none was pushed.
+        if let Some(IpPrefix { addr: IpAddr::V4(ip), .. }) = config.ipv4 {
+            let gateway = config.gateway_ipv4.unwrap_or(ip);
+            iface.routes_mut().add_default_ipv4_route(gateway.into())
+                .map_err(|_| io::Error::other("route table full"))?;
+        }
+        if let Some(IpPrefix { addr: IpAddr::V6(ip), .. }) = config.ipv6 {
+            let gateway = config.gateway_ipv6.unwrap_or(ip);
+            iface.routes_mut().add_default_ipv6_route(gateway.into())
+                .map_err(|_| io::Error::other("route table full"))?;
+        }
+        let inner = StackInner {
+            iface: iface,
+            device: device,
+            sockets: SocketSet::new(vec![]),
+            closing: Vec::new(),
+            next_port: EPHEMERAL_PORTS.start + (seed % (EPHEMERAL_PORTS.end - EPHEMERAL_PORTS.start) as u64) as u16,
+            tcp_buffer_size: config.tcp_buffer_size,
+            udp_buffer_size: config.udp_buffer_size,
+        };
+        let shared = Arc::new(Shared {
+            inner: Mutex::new(inner),
+            changed: Condvar::new(),
+            stop: AtomicBool::new(false),
+        });
+        let poll_shared = shared.clone();
+        let poll_thread = std::thread::Builder::new()
+            .name("openvpn-stack".into())
+            .spawn(move || poll_loop(poll_shared))?;
+        Ok(Stack {
+            shared: shared,
+            config: config,
+            poll_thread: Some(poll_thread),
+        })
+    }
+
+    pub fn config(&self) -> &StackConfig {
+        &self.config
+    }
+
+    pub fn should_tunnel(&self, dst: &IpAddr) -> bool {
+        self.config.routes.as_ref().map(|routes| routes.should_tunnel(dst)).unwrap_or(true)
+    }
+
+    ///Runs `f` with the underlying client, for example to disconnect it
+    pub fn with_client<R, F: FnOnce(&mut OVPNClient) -> R>(&self, f: F) -> R {
+        f(&mut self.shared.inner.lock().unwrap().device.client)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        self.shared.changed.notify_all();
+        if let Some(poll_thread) = self.poll_thread.take() {
+            let _ = poll_thread.join();
+        }
+    }
+}
+
+fn poll_loop(shared: Arc<Shared>) {
+    let mut inner = shared.inner.lock().unwrap();
+    while !shared.stop.load(Ordering::Relaxed) {
+        if inner.poll() {
+            shared.changed.notify_all();
+        }
+        let StackInner { iface, sockets, .. } = &mut *inner;
+        let delay = iface.poll_delay(Instant::now(), sockets)
+            .map(|d| Duration::from_micros(d.total_micros()))
+            .unwrap_or(POLL_INTERVAL);
+        inner = shared.changed.wait_timeout(inner, std::cmp::min(delay, POLL_INTERVAL)).unwrap().0;
+    }
+}
+
+///A TCP connection through the tunnel, used like `std::net::TcpStream`
+pub struct TcpStream {
+    socket: Arc<TcpHandle>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl TcpStream {
+    pub fn connect(stack: &Stack, addr: SocketAddr) -> io::Result<TcpStream> {
+        TcpStream::connect_timeout(stack, addr, DEFAULT_CONNECT_TIMEOUT)
+    }
+
+    pub fn connect_timeout(stack: &Stack, addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+        let handle = {
+            let mut inner = stack.shared.inner.lock().unwrap();
+            let socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0u8; inner.tcp_buffer_size]),
+                tcp::SocketBuffer::new(vec![0u8; inner.tcp_buffer_size]),
+            );
+            let handle = inner.sockets.add(socket);
+            let local_port = match inner.ephemeral_port() {
+                Ok(port) => port,
+                Err(e) => {
+                    inner.sockets.remove(handle);
+                    return Err(e);
+                }
+            };
+            let StackInner { iface, sockets, .. } = &mut *inner;
+            let r = sockets.get_mut::<tcp::Socket>(handle).connect(iface.context(), IpEndpoint::from(addr), local_port);
+            if let Err(e) = r {
+                sockets.remove(handle);
+                return Err(io::Error::new(ErrorKind::AddrNotAvailable, e.to_string()));
+            }
+            handle
+        };
+        //From here on Drop takes care of the socket
+        let stream = TcpStream {
+            socket: Arc::new(TcpHandle {
+                shared: stack.shared.clone(),
+                handle: handle,
+            }),
+            read_timeout: None,
+            write_timeout: None,
+        };
+        stream.socket.shared.wait(Some(timeout), |inner| {
+            match inner.sockets.get::<tcp::Socket>(handle).state() {
+                tcp::State::Established => Some(Ok(())),
+                tcp::State::Closed | tcp::State::TimeWait => Some(Err(io::Error::new(ErrorKind::ConnectionRefused, "connection refused"))),
+                _ => None
+            }
+        })?;
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        let inner = self.socket.shared.inner.lock().unwrap();
+        inner.sockets.get::<tcp::Socket>(self.socket.handle).remote_endpoint()
+            .map(|e| SocketAddr::new(e.addr.into(), e.port))
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "not connected"))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        let inner = self.socket.shared.inner.lock().unwrap();
+        inner.sockets.get::<tcp::Socket>(self.socket.handle).local_endpoint()
+            .map(|e| SocketAddr::new(e.addr.into(), e.port))
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "not connected"))
+    }
+
+    ///Another handle to the same connection, so reading and writing can happen on
+    ///different threads. The connection is closed when the last handle is dropped.
+    pub fn try_clone(&self) -> io::Result<TcpStream> {
+        Ok(TcpStream {
+            socket: self.socket.clone(),
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+        })
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    ///Sends a FIN. Reading is still possible until the peer closes its side.
+    pub fn shutdown(&self) {
+        self.socket.shared.inner.lock().unwrap().sockets.get_mut::<tcp::Socket>(self.socket.handle).close();
+        self.socket.shared.changed.notify_all();
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let handle = self.socket.handle;
+        self.socket.shared.wait(self.read_timeout, |inner| {
+            let socket = inner.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.can_recv() {
+                Some(socket.recv_slice(buf).map_err(|e| io::Error::other(e.to_string())))
+            } else if !socket.may_recv() {
+                Some(Ok(0))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let handle = self.socket.handle;
+        self.socket.shared.wait(self.write_timeout, |inner| {
+            let socket = inner.sockets.get_mut::<tcp::Socket>(handle);
+            if !socket.may_send() {
+                Some(Err(io::Error::new(ErrorKind::BrokenPipe, "connection closed")))
+            } else if socket.can_send() {
+                Some(socket.send_slice(buf).map_err(|e| io::Error::other(e.to_string())))
+            } else {
+                None
+            }
+        })
+    }
+
+    //Waits until everything written has been acknowledged
+    fn flush(&mut self) -> io::Result<()> {
+        let handle = self.socket.handle;
+        self.socket.shared.wait(self.write_timeout, |inner| {
+            let socket = inner.sockets.get::<tcp::Socket>(handle);
+            if socket.send_queue() == 0 || !socket.may_send() {
+                Some(Ok(()))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+//A proxied connection, through the tunnel or direct when split tunneling excludes the destination
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+pub(crate) enum Outbound {
+    Tunnel(TcpStream),
+    Direct(std::net::TcpStream),
+}
+
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+impl Outbound {
+    pub(crate) fn try_clone(&self) -> io::Result<Outbound> {
+        match self {
+            Outbound::Tunnel(stream) => stream.try_clone().map(Outbound::Tunnel),
+            Outbound::Direct(stream) => stream.try_clone().map(Outbound::Direct),
+        }
+    }
+
+    #[cfg(feature = "socks5")]
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Outbound::Tunnel(stream) => stream.local_addr(),
+            Outbound::Direct(stream) => stream.local_addr(),
+        }
+    }
+
+    fn shutdown(&self) {
+        match self {
+            Outbound::Tunnel(stream) => stream.shutdown(),
+            Outbound::Direct(stream) => {
+                let _ = stream.shutdown(std::net::Shutdown::Write);
+            }
+        }
+    }
+}
+
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+impl Read for Outbound {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Outbound::Tunnel(stream) => stream.read(buf),
+            Outbound::Direct(stream) => stream.read(buf),
+        }
+    }
+}
+
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+impl Write for Outbound {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Outbound::Tunnel(stream) => stream.write(buf),
+            Outbound::Direct(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Outbound::Tunnel(stream) => stream.flush(),
+            Outbound::Direct(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+pub(crate) fn dial(stack: &Stack, addr: SocketAddr) -> io::Result<Outbound> {
+    if stack.should_tunnel(&addr.ip()) {
+        TcpStream::connect(stack, addr).map(Outbound::Tunnel)
+    } else {
+        std::net::TcpStream::connect_timeout(&addr, DEFAULT_CONNECT_TIMEOUT).map(Outbound::Direct)
+    }
+}
+
+//Copies both ways between a local connection and an outbound one until either side closes.
+//Used by the proxies.
+#[cfg(any(feature = "socks5", feature = "http-proxy"))]
+pub(crate) fn relay(local: std::net::TcpStream, remote: Outbound) -> io::Result<()> {
+    let mut local_reader = local.try_clone()?;
+    let mut remote_writer = remote.try_clone()?;
+    let upload = std::thread::spawn(move || {
+        let _ = io::copy(&mut local_reader, &mut remote_writer);
+        remote_writer.shutdown();
+    });
+    let mut local_writer = local;
+    let mut remote_reader = remote;
+    let _ = io::copy(&mut remote_reader, &mut local_writer);
+    let _ = local_writer.shutdown(std::net::Shutdown::Both);
+    let _ = upload.join();
+    Ok(())
+}
+
+//Owns the smoltcp socket of a TcpStream and its clones
+struct TcpHandle {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+}
+
+impl Drop for TcpHandle {
+    fn drop(&mut self) {
+        let mut inner = self.shared.inner.lock().unwrap();
+        inner.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        inner.closing.push(self.handle);
+        drop(inner);
+        self.shared.changed.notify_all();
+    }
+}
+
+///A UDP socket through the tunnel, used like `std::net::UdpSocket`
+pub struct UdpSocket {
+    shared: Arc<Shared>,
+    handle: SocketHandle,
+    port: u16,
+    read_timeout: Option<Duration>,
+}
+
+impl UdpSocket {
+    ///Binds to `port` on the stack addresses, or to an ephemeral port if `port` is 0
+    pub fn bind(stack: &Stack, port: u16) -> io::Result<UdpSocket> {
+        let mut inner = stack.shared.inner.lock().unwrap();
+        let port = if port == 0 {
+            inner.ephemeral_port()?
+        } else if inner.udp_port_in_use(port) {
+            return Err(io::Error::new(ErrorKind::AddrInUse, format!("port {} is in use", port)));
+        } else {
+            port
+        };
+        let buffer = |size: usize| udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 64], vec![0u8; size]);
+        let mut socket = udp::Socket::new(buffer(inner.udp_buffer_size), buffer(inner.udp_buffer_size));
+        socket.bind(port).map_err(|e| io::Error::new(ErrorKind::AddrNotAvailable, e.to_string()))?;
+        let handle = inner.sockets.add(socket);
+        Ok(UdpSocket {
+            shared: stack.shared.clone(),
+            handle: handle,
+            port: port,
+            read_timeout: None,
+        })
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    pub fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        let handle = self.handle;
+        self.shared.wait(Some(UDP_SEND_TIMEOUT), |inner| {
+            let socket = inner.sockets.get_mut::<udp::Socket>(handle);
+            match socket.send_slice(buf, IpEndpoint::from(addr)) {
+                Ok(()) => Some(Ok(buf.len())),
+                Err(udp::SendError::BufferFull) => None,
+                Err(e) => Some(Err(io::Error::other(e.to_string())))
+            }
+        })
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let handle = self.handle;
+        self.shared.wait(self.read_timeout, |inner| {
+            let socket = inner.sockets.get_mut::<udp::Socket>(handle);
+            match socket.recv_slice(buf) {
+                Ok((size, meta)) => Some(Ok((size, SocketAddr::new(meta.endpoint.addr.into(), meta.endpoint.port)))),
+                Err(udp::RecvError::Exhausted) => None,
+                Err(e) => Some(Err(io::Error::other(e.to_string())))
+            }
+        })
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.shared.inner.lock().unwrap().sockets.remove(self.handle);
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+///Traffic counters of an OVPNClient, counting inner (decrypted) packets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OVPNStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    pub send_errors: u64,
+    pub receive_errors: u64,
+}
+
+impl std::fmt::Display for OVPNStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "sent {} packets ({} bytes), received {} packets ({} bytes), {} send errors, {} receive errors",
+            self.packets_sent, self.bytes_sent, self.packets_received, self.bytes_received, self.send_errors, self.receive_errors)
+    }
+}
+
+impl std::ops::Add for OVPNStats {
+    type Output = OVPNStats;
+    fn add(self, other: OVPNStats) -> OVPNStats {
+        OVPNStats {
+            bytes_sent: self.bytes_sent + other.bytes_sent,
+            bytes_received: self.bytes_received + other.bytes_received,
+            packets_sent: self.packets_sent + other.packets_sent,
+            packets_received: self.packets_received + other.packets_received,
+            send_errors: self.send_errors + other.send_errors,
+            receive_errors: self.receive_errors + other.receive_errors,
+        }
+    }
+}
+
+//Atomics so `OVPNClient::send`, which takes &self, can count too
+#[derive(Default)]
+pub(crate) struct StatsCounters {
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    send_errors: AtomicU64,
+    receive_errors: AtomicU64,
+}
+
+impl StatsCounters {
+    pub(crate) fn sent(&self, size: usize) {
+        self.bytes_sent.fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, size: usize) {
+        self.bytes_received.fetch_add(size as u64, Ordering::Relaxed);
+        self.packets_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn send_error(&self) {
+        self.send_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn receive_error(&self) {
+        self.receive_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> OVPNStats {
+        OVPNStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            packets_received: self.packets_received.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            receive_errors: self.receive_errors.load(Ordering::Relaxed),
+        }
+    }
+}
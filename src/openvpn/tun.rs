@@ -0,0 +1,161 @@
+//Kernel tun device backend (Linux only, `tun` feature)
+//
+//The rest of the crate is userspace-only. This module creates a real tun interface,
+//configures it from the options the server pushed, and pumps packets between it and
+//an `OVPNClient`. Creating the device needs CAP_NET_ADMIN, so it's easiest to try it
+//inside a network namespace, like tests/tun.rs does over the mock backend:
+//`unshare -rn cargo test --no-default-features --features tun,mock --test tun -- --ignored`
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::os::unix::io::AsRawFd;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use libc::{c_char, c_short, c_ulong};
+use super::openvpn::{OVPNClient, OpenVpnReceiveError, OpenVpnSendError};
+use super::pushed::{IpPrefix, PushedConfig};
+
+const TUNSETIFF: c_ulong = 0x400454ca;
+const IFF_TUN: c_short = 0x0001;
+const IFF_NO_PI: c_short = 0x1000;
+const IFNAMSIZ: usize = 16;
+//MTU used when the server didn't push one
+const DEFAULT_MTU: usize = 1500;
+
+#[repr(C)]
+struct IfReq {
+    ifr_name: [c_char; IFNAMSIZ],
+    ifr_flags: c_short,
+    _padding: [u8; 22],
+}
+
+pub struct TunDevice {
+    file: File,
+    name: String,
+    mtu: usize,
+}
+
+impl TunDevice {
+    ///Creates (or attaches to) the tun interface `name`. Pass an empty name to let the
+    ///kernel pick one, like tun0.
+    pub fn create(name: &str) -> Result<TunDevice> {
+        if name.len() >= IFNAMSIZ {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("tun name too long: {}", name)));
+        }
+        let file = OpenOptions::new().read(true).write(true).open("/dev/net/tun")?;
+        let mut ifr = IfReq {
+            ifr_name: [0; IFNAMSIZ],
+            ifr_flags: IFF_TUN | IFF_NO_PI,
+            _padding: [0; 22],
+        };
+        for (i, b) in name.bytes().enumerate() {
+            ifr.ifr_name[i] = b as c_char;
+        }
+        let r = unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut ifr as *mut IfReq) };
+        if r < 0 {
+            return Err(Error::last_os_error());
+        }
+        let name: Vec<u8> = ifr.ifr_name.iter().take_while(|c| **c != 0).map(|c| *c as u8).collect();
+        Ok(TunDevice {
+            file: file,
+            name: String::from_utf8_lossy(&name).into_owned(),
+            mtu: DEFAULT_MTU,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    ///Assigns the pushed addresses and MTU, brings the link up and installs the pushed
+    ///routes. `redirect-gateway` is not applied since replacing the host's default route
+    ///is a decision for the caller.
+    pub fn configure(&mut self, config: &PushedConfig) -> Result<()> {
+        let mtu = config.mtu.unwrap_or(DEFAULT_MTU);
+        self.ip(&["link", "set", "dev", &self.name, "mtu", &mtu.to_string()])?;
+        self.mtu = mtu;
+        for prefix in config.ipv4.iter().chain(config.ipv6.iter()) {
+            self.ip(&[family(prefix), "addr", "add", &prefix.to_string(), "dev", &self.name])?;
+        }
+        self.ip(&["link", "set", "dev", &self.name, "up"])?;
+        for route in config.routes.iter() {
+            self.ip(&[family(route), "route", "replace", &route.to_string(), "dev", &self.name])?;
+        }
+        Ok(())
+    }
+
+    fn ip(&self, args: &[&str]) -> Result<()> {
+        let output = Command::new("ip").args(args).output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!("ip {}: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())))
+        }
+    }
+
+    ///Waits up to `timeout` for a packet to be readable
+    pub fn poll_read(&self, timeout: Duration) -> Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let r = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+        if r < 0 {
+            let e = Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                return Ok(false);
+            }
+            return Err(e);
+        }
+        Ok(r > 0 && fds.revents & libc::POLLIN != 0)
+    }
+
+    ///Reads one packet
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.file.read(buf)
+    }
+
+    ///Writes one packet
+    pub fn write(&mut self, packet: &[u8]) -> Result<usize> {
+        self.file.write(packet)
+    }
+}
+
+fn family(prefix: &IpPrefix) -> &'static str {
+    if prefix.addr.is_ipv4() { "-4" } else { "-6" }
+}
+
+///Moves packets between `tun` and `client` until `stop` is set or either side fails.
+///The client should already be connected and `tun` configured. Packets bigger than the
+///tunnel MTU are dropped with a line to the client's `on_vpn_log`, and counted in the client's `send_errors`.
+pub fn pump(client: &mut OVPNClient, tun: &mut TunDevice, stop: &AtomicBool) -> Result<()> {
+    let mut buffer = vec![0u8; tun.mtu() + 64];
+    while !stop.load(Ordering::Relaxed) {
+        //VPN -> tun, drain everything that's available
+        loop {
+            let mut write_result = Ok(0);
+            match client.receive(&mut |packet| write_result = tun.write(packet)) {
+                Ok(_) => write_result?,
+                Err(OpenVpnReceiveError::NoDataAvailable) => break,
+                Err(OpenVpnReceiveError::Unknown(e)) => return Err(Error::other(e)),
+            };
+        }
+        //tun -> VPN
+        if tun.poll_read(Duration::from_millis(10))? {
+            let n = tun.read(&mut buffer)?;
+            match client.send(&buffer[..n]) {
+                Ok(_) => {},
+                Err(OpenVpnSendError::TooBig{size, mtu}) => {
+                    client.log(format!("tun: dropped a packet of {} bytes, bigger than the tunnel MTU of {}", size, mtu));
+                },
+                Err(OpenVpnSendError::Unknown(e)) => return Err(Error::other(e)),
+            }
+        }
+    }
+    Ok(())
+}
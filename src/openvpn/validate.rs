@@ -0,0 +1,475 @@
+//Profile checks before connecting (`validate` feature)
+//
+//A broken profile otherwise only shows up as a fatal event once we try to connect. This
+//reads a profile the way OVPNClient gets it, a string with everything inline, and reports
+//directives the client doesn't know or support, missing or unusable certificates and keys,
+//weak crypto, and options this build can't honor.
+use std::time::{SystemTime, UNIX_EPOCH};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::der_parser::ber::Class;
+use x509_parser::der_parser::der::parse_der;
+use x509_parser::pem::Pem;
+use x509_parser::public_key::PublicKey;
+use super::compression::CompressionAlgorithm;
+
+//Certificates expiring sooner than this get a warning
+const EXPIRY_WARNING_DAYS: i64 = 30;
+const MIN_RSA_BITS: usize = 2048;
+//OpenSSL's default security level refuses anything smaller
+const MIN_USABLE_RSA_BITS: usize = 1024;
+
+//Client options the library understands
+const CLIENT_DIRECTIVES: &[&str] = &[
+    "allow-compression", "allow-pull-fqdn", "auth", "auth-nocache", "auth-retry", "auth-token",
+    "auth-token-user", "auth-user-pass", "ca", "cert", "cipher", "client", "comp-lzo", "compress",
+    "connect-retry", "connect-retry-max", "connect-timeout", "data-ciphers", "data-ciphers-fallback",
+    "dev", "dev-type", "dhcp-option", "explicit-exit-notify", "extra-certs", "float", "hand-window",
+    "http-proxy", "http-proxy-option", "ifconfig-nowarn", "ignore-unknown-option", "inactive", "keepalive",
+    "key", "key-direction", "mssfix", "mute", "ncp-ciphers", "nobind", "ns-cert-type", "peer-fingerprint",
+    "persist-key", "persist-tun", "ping", "ping-exit", "ping-restart", "pkcs12", "port", "proto",
+    "pull", "pull-filter", "push-peer-info", "rcvbuf", "redirect-gateway", "redirect-private",
+    "remote", "remote-cert-eku", "remote-cert-ku", "remote-cert-tls", "remote-random",
+    "remote-random-hostname", "reneg-sec", "resolv-retry", "route", "route-ipv6", "route-nopull",
+    "rport", "server-poll-timeout", "setenv", "setenv-opt", "sndbuf", "static-challenge",
+    "tls-cert-profile", "tls-cipher", "tls-ciphersuites", "tls-client", "tls-auth", "tls-crypt",
+    "tls-crypt-v2", "tls-timeout", "tls-version-max", "tls-version-min", "topology", "tran-window",
+    "tun-mtu", "verb", "verify-x509-name",
+];
+
+//Accepted but without effect: scripts, daemon and privilege options of the openvpn binary
+const IGNORED_DIRECTIVES: &[&str] = &[
+    "chroot", "daemon", "down", "down-pre", "group", "key-method", "lport", "log", "log-append",
+    "management", "management-hold", "management-query-passwords", "mute-replay-warnings", "route-delay",
+    "route-method", "route-up", "script-security", "status", "up", "up-restart", "user", "writepid",
+];
+
+//Options the client refuses, with why
+const UNSUPPORTED_DIRECTIVES: &[(&str, &str)] = &[
+    ("secret", "static key mode is not supported, only TLS"),
+    ("fragment", "fragment is not supported"),
+    ("socks-proxy", "SOCKS proxies are not supported, only http-proxy"),
+    ("server", "server-only option"),
+    ("server-bridge", "server-only option"),
+    ("mode", "server-only option"),
+    ("tls-server", "server-only option"),
+    ("dh", "server-only option"),
+    ("push", "server-only option"),
+    ("client-config-dir", "server-only option"),
+    ("client-to-client", "server-only option"),
+    ("duplicate-cn", "server-only option"),
+    ("ifconfig-pool", "server-only option"),
+    ("auth-user-pass-verify", "server-only option"),
+];
+
+//Directives naming a file, which has to be inline instead since profiles are passed as a string
+const FILE_DIRECTIVES: &[&str] = &["ca", "cert", "key", "extra-certs", "pkcs12", "tls-auth", "tls-crypt", "tls-crypt-v2"];
+
+const INLINE_TAGS: &[&str] = &[
+    "auth-user-pass", "ca", "cert", "connection", "extra-certs", "http-proxy-user-pass", "key",
+    "peer-fingerprint", "pkcs12", "static-challenge", "tls-auth", "tls-crypt", "tls-crypt-v2",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    ///Worth knowing, nothing to fix
+    Info,
+    ///Connects, but probably not the way it's meant to
+    Warning,
+    ///Won't connect
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+///One problem found in a profile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    ///Line of the profile it's about, starting at 1. `None` for what's missing.
+    pub line: Option<usize>,
+    ///The directive or inline block it's about
+    pub directive: Option<String>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
+
+///Whether a profile with these diagnostics can't connect
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+struct Directive {
+    line: usize,
+    name: String,
+    args: Vec<String>,
+}
+
+struct Inline {
+    line: usize,
+    tag: String,
+    body: String,
+}
+
+struct Profile {
+    directives: Vec<Directive>,
+    inlines: Vec<Inline>,
+}
+
+impl Profile {
+    fn has(&self, name: &str) -> bool {
+        self.directives.iter().any(|d| d.name == name) || self.inline(name).is_some()
+    }
+
+    fn inline(&self, tag: &str) -> Option<&Inline> {
+        self.inlines.iter().find(|i| i.tag == tag)
+    }
+}
+
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn push(&mut self, severity: Severity, line: Option<usize>, directive: &str, message: String) {
+        self.0.push(Diagnostic {
+            severity: severity,
+            line: line,
+            directive: Some(directive.to_owned()),
+            message: message,
+        });
+    }
+}
+
+///Checks `profile` without connecting. Empty when there's nothing to report.
+pub fn validate(profile: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Diagnostics(Vec::new());
+    let profile = parse(profile, &mut diagnostics);
+    check_directives(&profile, &mut diagnostics);
+    check_required(&profile, &mut diagnostics);
+    check_certificates(&profile, &mut diagnostics);
+    diagnostics.0
+}
+
+fn parse(profile: &str, diagnostics: &mut Diagnostics) -> Profile {
+    let mut directives = Vec::new();
+    let mut inlines: Vec<Inline> = Vec::new();
+    let mut open: Option<Inline> = None;
+    for (i, line) in profile.lines().enumerate() {
+        let line_number = i + 1;
+        let trimmed = line.trim();
+        if let Some(inline) = open.as_mut() {
+            if trimmed == format!("</{}>", inline.tag) {
+                inlines.extend(open.take());
+            } else {
+                inline.body.push_str(line);
+                inline.body.push('\n');
+            }
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+        if let Some(tag) = trimmed.strip_prefix('<').and_then(|t| t.strip_suffix('>')) {
+            if !INLINE_TAGS.contains(&tag) {
+                diagnostics.push(Severity::Warning, Some(line_number), tag, format!("unknown inline block <{}>", tag));
+            }
+            open = Some(Inline {
+                line: line_number,
+                tag: tag.to_owned(),
+                body: String::new(),
+            });
+            continue;
+        }
+        let mut words = trimmed.split_whitespace().map(|w| w.trim_matches('"').to_owned());
+        if let Some(name) = words.next() {
+            directives.push(Directive {
+                line: line_number,
+                name: name,
+                args: words.collect(),
+            });
+        }
+    }
+    if let Some(inline) = open {
+        diagnostics.push(Severity::Error, Some(inline.line), &inline.tag, format!("<{}> is never closed", inline.tag));
+    }
+    Profile {
+        directives: directives,
+        inlines: inlines,
+    }
+}
+
+fn check_directives(profile: &Profile, diagnostics: &mut Diagnostics) {
+    //`ignore-unknown-option a b` and `setenv opt a ...` make unknown options harmless
+    let mut tolerated: Vec<&str> = Vec::new();
+    for directive in profile.directives.iter() {
+        match directive.name.as_str() {
+            "ignore-unknown-option" => tolerated.extend(directive.args.iter().map(|a| a.as_str())),
+            "setenv" if directive.args.first().map(|a| a.as_str()) == Some("opt") => {
+                tolerated.extend(directive.args.get(1).map(|a| a.as_str()))
+            },
+            _ => {}
+        }
+    }
+    for directive in profile.directives.iter() {
+        let name = directive.name.as_str();
+        let line = Some(directive.line);
+        if let Some((_, reason)) = UNSUPPORTED_DIRECTIVES.iter().find(|(n, _)| *n == name) {
+            diagnostics.push(Severity::Error, line, name, format!("{} is not supported: {}", name, reason));
+        } else if IGNORED_DIRECTIVES.contains(&name) {
+            diagnostics.push(Severity::Info, line, name, format!("{} is ignored by this client", name));
+        } else if !CLIENT_DIRECTIVES.contains(&name) {
+            if !tolerated.contains(&name) {
+                diagnostics.push(Severity::Warning, line, name, format!("unknown directive {}", name));
+            }
+            continue;
+        }
+        if FILE_DIRECTIVES.contains(&name) {
+            if let Some(file) = directive.args.first().filter(|f| f.as_str() != "[inline]") {
+                diagnostics.push(Severity::Error, line, name, format!("{} refers to the file {}, embed it as <{}> instead", name, file, name));
+            }
+        }
+        check_arguments(directive, diagnostics);
+        if let Some((severity, message)) = build_support(directive) {
+            diagnostics.push(severity, line, name, message);
+        }
+    }
+}
+
+fn check_arguments(directive: &Directive, diagnostics: &mut Diagnostics) {
+    let name = directive.name.as_str();
+    let line = Some(directive.line);
+    let first = directive.args.first().map(|a| a.as_str()).unwrap_or("");
+    match name {
+        "dev" | "dev-type" if !first.starts_with("tun") && !first.is_empty() => {
+            diagnostics.push(Severity::Error, line, name, format!("{} {}: only tun devices are supported", name, first));
+        },
+        "proto" if first.ends_with("-server") => {
+            diagnostics.push(Severity::Error, line, name, format!("proto {} is for servers", first));
+        },
+        "cipher" | "data-ciphers" | "data-ciphers-fallback" | "ncp-ciphers" => {
+            for cipher in first.split(':').filter(|c| !c.is_empty()) {
+                if cipher.eq_ignore_ascii_case("none") {
+                    diagnostics.push(Severity::Error, line, name, format!("{} none turns off encryption", name));
+                } else if is_weak_cipher(cipher) {
+                    diagnostics.push(Severity::Warning, line, name, format!("{} is a weak cipher", cipher));
+                }
+            }
+        },
+        "auth" if first.eq_ignore_ascii_case("none") => {
+            diagnostics.push(Severity::Error, line, name, "auth none turns off packet authentication".into());
+        },
+        "auth" if first.eq_ignore_ascii_case("md5") => {
+            diagnostics.push(Severity::Warning, line, name, "auth MD5 is weak".into());
+        },
+        "tls-version-min" if first == "1.0" || first == "1.1" => {
+            diagnostics.push(Severity::Warning, line, name, format!("TLS {} is obsolete, use 1.2 or later", first));
+        },
+        "tls-cipher" => {
+            for cipher in first.split(':').filter(|c| is_weak_tls_cipher(c)) {
+                diagnostics.push(Severity::Warning, line, name, format!("{} is a weak TLS cipher", cipher));
+            }
+        },
+        "comp-lzo" | "compress" if compression(directive) != Some(CompressionAlgorithm::Stub) => {
+            diagnostics.push(Severity::Warning, line, name, format!("{} compresses before encrypting, which can leak plaintext (VORACLE)", name));
+        },
+        "auth-user-pass" if !first.is_empty() => {
+            diagnostics.push(Severity::Info, line, name, format!("auth-user-pass {} is ignored, credentials are given to the client", first));
+        },
+        "ns-cert-type" => {
+            diagnostics.push(Severity::Warning, line, name, "ns-cert-type is deprecated, use remote-cert-tls".into());
+        },
+        _ => {}
+    }
+}
+
+//Whether this build can do what `directive` asks for
+fn build_support(directive: &Directive) -> Option<(Severity, String)> {
+    let name = directive.name.as_str();
+    match name {
+        "block-outside-dns" | "register-dns" | "ip-win32" | "dhcp-release" if !cfg!(windows) => {
+            Some((Severity::Info, format!("{} only applies on Windows", name)))
+        },
+        "comp-lzo" | "compress" => match compression(directive) {
+            Some(algorithm) if !algorithm.is_available() => {
+                Some((Severity::Error, format!("built without {} compression (the {} feature)", algorithm, algorithm.to_string().to_lowercase())))
+            },
+            _ => None
+        },
+        "pkcs12" if cfg!(feature = "mbedtls") => {
+            Some((Severity::Error, "pkcs12 needs the OpenSSL build, embed <cert> and <key> instead".into()))
+        },
+        "tls-ciphersuites" if cfg!(feature = "mbedtls") => {
+            Some((Severity::Info, "tls-ciphersuites is for TLS 1.3, which the mbed TLS build doesn't use".into()))
+        },
+        _ => None
+    }
+}
+
+fn compression(directive: &Directive) -> Option<CompressionAlgorithm> {
+    let args: Vec<&str> = directive.args.iter().map(|a| a.as_str()).collect();
+    CompressionAlgorithm::from_directive(&directive.name, &args)
+}
+
+fn is_weak_cipher(cipher: &str) -> bool {
+    let cipher = cipher.to_ascii_uppercase();
+    ["BF-", "DES", "RC2", "RC4", "CAST", "IDEA", "SEED"].iter().any(|weak| cipher.starts_with(weak))
+}
+
+fn is_weak_tls_cipher(cipher: &str) -> bool {
+    let cipher = cipher.to_ascii_uppercase();
+    ["NULL", "EXPORT", "RC4", "DES", "MD5", "ANON"].iter().any(|weak| cipher.contains(weak))
+}
+
+fn check_required(profile: &Profile, diagnostics: &mut Diagnostics) {
+    let connection_remote = profile.inlines.iter()
+        .any(|i| i.tag == "connection" && i.body.lines().any(|l| l.trim_start().starts_with("remote ")));
+    if !profile.has("remote") && !connection_remote {
+        diagnostics.push(Severity::Error, None, "remote", "no remote to connect to".into());
+    }
+    if !profile.has("ca") && !profile.has("pkcs12") && !profile.has("peer-fingerprint") {
+        diagnostics.push(Severity::Error, None, "ca", "no CA certificate to check the server with".into());
+    }
+    let cert = profile.has("cert") || profile.has("pkcs12");
+    if profile.has("cert") && !profile.has("key") {
+        diagnostics.push(Severity::Error, None, "key", "a client certificate but no key".into());
+    }
+    if profile.has("key") && !profile.has("cert") {
+        diagnostics.push(Severity::Error, None, "cert", "a key but no client certificate".into());
+    }
+    if !cert && !profile.has("auth-user-pass") {
+        diagnostics.push(Severity::Error, None, "cert", "neither a client certificate nor auth-user-pass".into());
+    }
+}
+
+fn check_certificates(profile: &Profile, diagnostics: &mut Diagnostics) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let mut client_pem = None;
+    for inline in profile.inlines.iter().filter(|i| ["ca", "cert", "extra-certs"].contains(&i.tag.as_str())) {
+        let pems: Vec<Pem> = Pem::iter_from_buffer(inline.body.as_bytes())
+            .filter_map(|pem| pem.ok())
+            .filter(|pem| pem.label == "CERTIFICATE")
+            .collect();
+        if pems.is_empty() {
+            diagnostics.push(Severity::Error, Some(inline.line), &inline.tag, format!("<{}> holds no PEM certificate", inline.tag));
+            continue;
+        }
+        for pem in pems.iter() {
+            match pem.parse_x509() {
+                Ok(cert) => check_certificate(inline, &cert, now, diagnostics),
+                Err(e) => diagnostics.push(Severity::Error, Some(inline.line), &inline.tag, format!("<{}> has an invalid certificate: {}", inline.tag, e)),
+            }
+        }
+        if inline.tag == "cert" {
+            client_pem = pems.into_iter().next();
+        }
+    }
+    if let (Some(pem), Some(key)) = (client_pem, profile.inline("key")) {
+        if let Ok(cert) = pem.parse_x509() {
+            check_key(key, &cert, diagnostics);
+        }
+    }
+}
+
+fn check_certificate(inline: &Inline, cert: &X509Certificate, now: i64, diagnostics: &mut Diagnostics) {
+    let tag = inline.tag.as_str();
+    let line = Some(inline.line);
+    let subject = cert.subject().to_string();
+    let validity = cert.validity();
+    if validity.not_before.timestamp() > now {
+        diagnostics.push(Severity::Error, line, tag, format!("certificate {} is not valid until {}", subject, validity.not_before));
+    } else if validity.not_after.timestamp() < now {
+        diagnostics.push(Severity::Error, line, tag, format!("certificate {} expired on {}", subject, validity.not_after));
+    } else if validity.not_after.timestamp() < now + EXPIRY_WARNING_DAYS * 86400 {
+        diagnostics.push(Severity::Warning, line, tag, format!("certificate {} expires on {}", subject, validity.not_after));
+    }
+    if let Ok(PublicKey::RSA(rsa)) = cert.public_key().parsed() {
+        let bits = rsa.key_size();
+        if bits < MIN_USABLE_RSA_BITS {
+            diagnostics.push(Severity::Error, line, tag, format!("certificate {} has a {} bit RSA key", subject, bits));
+        } else if bits < MIN_RSA_BITS {
+            diagnostics.push(Severity::Warning, line, tag, format!("certificate {} has a weak {} bit RSA key", subject, bits));
+        }
+    }
+    //Self-signed roots are trusted as they are, their signature doesn't matter
+    if cert.subject() != cert.issuer() {
+        let weak = match cert.signature_algorithm.algorithm.to_id_string().as_str() {
+            "1.2.840.113549.1.1.4" => Some("MD5"),
+            "1.2.840.113549.1.1.5" | "1.2.840.10045.4.1" => Some("SHA-1"),
+            _ => None
+        };
+        if let Some(hash) = weak {
+            diagnostics.push(Severity::Warning, line, tag, format!("certificate {} is signed with {}", subject, hash));
+        }
+    }
+}
+
+//Compares the key's public half with the certificate's
+fn check_key(key: &Inline, cert: &X509Certificate, diagnostics: &mut Diagnostics) {
+    let line = Some(key.line);
+    if key.body.contains("ENCRYPTED") {
+        diagnostics.push(Severity::Info, line, "key", "the key is encrypted, so it can't be checked against the certificate".into());
+        return;
+    }
+    let pem = match Pem::iter_from_buffer(key.body.as_bytes()).next() {
+        Some(Ok(pem)) => pem,
+        _ => {
+            diagnostics.push(Severity::Error, line, "key", "<key> holds no PEM private key".into());
+            return;
+        }
+    };
+    let spki = &cert.public_key().subject_public_key.data;
+    let matches = match pem.label.as_str() {
+        "PRIVATE KEY" => rcgen::KeyPair::from_pem(&key.body).ok().map(|k| k.public_key_raw() == spki.as_ref()),
+        "RSA PRIVATE KEY" => match cert.public_key().parsed() {
+            Ok(PublicKey::RSA(rsa)) => pkcs1_public_key(&pem.contents)
+                .map(|(modulus, exponent)| unsigned(&modulus) == unsigned(rsa.modulus) && unsigned(&exponent) == unsigned(rsa.exponent)),
+            _ => Some(false),
+        },
+        "EC PRIVATE KEY" => sec1_public_key(&pem.contents).map(|public_key| public_key == spki.as_ref()),
+        label => {
+            diagnostics.push(Severity::Error, line, "key", format!("<key> holds a {}, not a private key", label));
+            return;
+        }
+    };
+    match matches {
+        Some(true) => {},
+        Some(false) => diagnostics.push(Severity::Error, line, "key", "the key doesn't belong to the client certificate".into()),
+        None => diagnostics.push(Severity::Info, line, "key", format!("can't check this {} against the certificate", pem.label)),
+    }
+}
+
+//Modulus and public exponent of an RSAPrivateKey
+fn pkcs1_public_key(der: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let (_, key) = parse_der(der).ok()?;
+    let fields = key.as_sequence().ok()?;
+    Some((fields.get(1)?.as_slice().ok()?.to_vec(), fields.get(2)?.as_slice().ok()?.to_vec()))
+}
+
+//The optional public key of an ECPrivateKey, `[1] BIT STRING`
+fn sec1_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    let (_, key) = parse_der(der).ok()?;
+    let field = key.as_sequence().ok()?.iter()
+        .find(|f| f.header.class() == Class::ContextSpecific && f.header.tag().0 == 1)?;
+    let (_, bits) = parse_der(field.as_slice().ok()?).ok()?;
+    Some(bits.as_bitstring().ok()?.data.to_vec())
+}
+
+//DER integers carry a leading zero when the top bit is set
+fn unsigned(bytes: &[u8]) -> &[u8] {
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+    &bytes[zeros..]
+}
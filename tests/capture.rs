@@ -0,0 +1,205 @@
+//pcapng and pcap layouts of packet captures: `cargo test --test capture`
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex};
+use libopenvpn3::openvpn::{CaptureFormat, CaptureLimits, Direction, PacketCapture};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 1;
+const ENHANCED_PACKET_BLOCK: u32 = 6;
+//Both headers
+const HEADER_SIZE: usize = 28 + 20;
+
+//A writer whose bytes the test can still read
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+//Splits a capture into (type, body) blocks, checking that both lengths of each agree
+fn blocks(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let total = u32_at(data, offset + 4) as usize;
+        assert_eq!(total % 4, 0, "block at {} isn't padded", offset);
+        assert_eq!(u32_at(data, offset + total - 4) as usize, total, "trailing length of the block at {}", offset);
+        blocks.push((u32_at(data, offset), data[offset + 8..offset + total - 4].to_vec()));
+        offset += total;
+    }
+    assert_eq!(offset, data.len());
+    blocks
+}
+
+fn capture(limits: CaptureLimits) -> (PacketCapture, SharedBuffer) {
+    let buffer = SharedBuffer::default();
+    (PacketCapture::to_writer(Box::new(buffer.clone()), CaptureFormat::Pcapng, limits).unwrap(), buffer)
+}
+
+#[test]
+fn header_blocks() {
+    let (_capture, buffer) = capture(CaptureLimits {
+        snaplen: Some(96),
+        ..CaptureLimits::default()
+    });
+    let data = buffer.0.lock().unwrap().clone();
+    assert_eq!(data.len(), HEADER_SIZE);
+    let blocks = blocks(&data);
+    assert_eq!(blocks.len(), 2);
+    let (block_type, section) = &blocks[0];
+    assert_eq!(*block_type, SECTION_HEADER_BLOCK);
+    //Byte order magic, version 1.0, unknown section length
+    assert_eq!(u32_at(section, 0), 0x1A2B_3C4D);
+    assert_eq!((u16_at(section, 4), u16_at(section, 6)), (1, 0));
+    assert_eq!(&section[8..16], &[0xff; 8]);
+    let (block_type, interface) = &blocks[1];
+    assert_eq!(*block_type, INTERFACE_DESCRIPTION_BLOCK);
+    //LINKTYPE_RAW and the snaplen
+    assert_eq!(u16_at(interface, 0), 101);
+    assert_eq!(u32_at(interface, 4), 96);
+}
+
+#[test]
+fn packet_blocks_are_padded_and_carry_the_direction() {
+    let (capture, buffer) = capture(CaptureLimits::default());
+    let packets: [(Direction, &[u8]); 4] = [
+        (Direction::Outbound, b"12345"),
+        (Direction::Inbound, b"1234"),
+        (Direction::Inbound, b"123456"),
+        (Direction::Outbound, b""),
+    ];
+    for (direction, packet) in packets.iter() {
+        capture.write(*direction, packet);
+    }
+    assert_eq!(capture.packets(), 4);
+    let data = buffer.0.lock().unwrap().clone();
+    let blocks = blocks(&data[HEADER_SIZE..]);
+    assert_eq!(blocks.len(), 4);
+    for ((block_type, body), (direction, packet)) in blocks.iter().zip(packets.iter()) {
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        //Interface, timestamp, captured and original lengths, then the padded packet
+        assert_eq!(u32_at(body, 0), 0);
+        let timestamp = (u32_at(body, 4) as u64) << 32 | u32_at(body, 8) as u64;
+        assert!(timestamp > 1_600_000_000_000_000);
+        assert_eq!(u32_at(body, 12) as usize, packet.len());
+        assert_eq!(u32_at(body, 16) as usize, packet.len());
+        let padded = packet.len().div_ceil(4) * 4;
+        assert_eq!(&body[20..20 + packet.len()], *packet);
+        assert!(body[20 + packet.len()..20 + padded].iter().all(|b| *b == 0));
+        //epb_flags, then the end of options
+        let options = &body[20 + padded..];
+        let flags = if *direction == Direction::Inbound { 1 } else { 2 };
+        assert_eq!(options, &[&2u16.to_le_bytes()[..], &4u16.to_le_bytes()[..], &(flags as u32).to_le_bytes()[..], &[0, 0, 0, 0][..]].concat()[..]);
+    }
+    //12 bytes of block framing, 20 of fields, the packet padded to 8 and 12 of options
+    assert_eq!(u32_at(&data, HEADER_SIZE + 4), 52);
+}
+
+#[test]
+fn snaplen_cuts_packets() {
+    let (capture, buffer) = capture(CaptureLimits {
+        snaplen: Some(3),
+        ..CaptureLimits::default()
+    });
+    capture.write(Direction::Outbound, b"123456");
+    let data = buffer.0.lock().unwrap().clone();
+    let (_, body) = &blocks(&data[HEADER_SIZE..])[0];
+    assert_eq!((u32_at(body, 12), u32_at(body, 16)), (3, 6));
+    assert_eq!(&body[20..24], b"123\0");
+}
+
+#[test]
+fn writers_stop_at_their_size_limit() {
+    let (capture, buffer) = capture(CaptureLimits {
+        max_file_size: Some(HEADER_SIZE as u64 + 2 * 48),
+        ..CaptureLimits::default()
+    });
+    for _ in 0..3 {
+        capture.write(Direction::Outbound, b"1234");
+    }
+    assert!(capture.is_stopped());
+    assert_eq!(capture.packets(), 2);
+    assert_eq!(buffer.0.lock().unwrap().len(), HEADER_SIZE + 2 * 48);
+}
+
+#[test]
+fn files_are_rotated() {
+    let dir = std::env::temp_dir().join(format!("libopenvpn3-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("tunnel.pcapng");
+    let capture = PacketCapture::to_file(&path, CaptureFormat::from_path(&path), CaptureLimits {
+        max_file_size: Some(HEADER_SIZE as u64 + 48),
+        max_rotated_files: 2,
+        ..CaptureLimits::default()
+    }).unwrap();
+    for packet in [b"aaaa", b"bbbb", b"cccc", b"dddd"].iter() {
+        capture.write(Direction::Outbound, *packet);
+    }
+    capture.flush().unwrap();
+    assert!(!capture.is_stopped());
+    //Every file starts with its own headers and holds one packet, the oldest is gone
+    let read = |name: &str| {
+        let data = std::fs::read(dir.join(name)).unwrap();
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 3);
+        blocks[2].1[20..24].to_vec()
+    };
+    assert_eq!(read("tunnel.pcapng"), b"dddd");
+    assert_eq!(read("tunnel.pcapng.1"), b"cccc");
+    assert_eq!(read("tunnel.pcapng.2"), b"bbbb");
+    assert!(!dir.join("tunnel.pcapng.3").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn classic_pcap() {
+    assert_eq!(CaptureFormat::from_path("tunnel.pcap"), CaptureFormat::Pcap);
+    assert_eq!(CaptureFormat::from_path("tunnel.PCAP"), CaptureFormat::Pcap);
+    assert_eq!(CaptureFormat::from_path("tunnel.pcapng"), CaptureFormat::Pcapng);
+    assert_eq!(CaptureFormat::from_path("tunnel"), CaptureFormat::Pcapng);
+
+    let buffer = SharedBuffer::default();
+    let capture = PacketCapture::to_writer(Box::new(buffer.clone()), CaptureFormat::Pcap, CaptureLimits {
+        snaplen: Some(4),
+        //The header and the first two records
+        max_file_size: Some(24 + 19 + 20),
+        ..CaptureLimits::default()
+    }).unwrap();
+    capture.write(Direction::Outbound, b"123");
+    capture.write(Direction::Inbound, b"123456");
+    capture.write(Direction::Inbound, b"1");
+    assert!(capture.is_stopped());
+    assert_eq!(capture.packets(), 2);
+    let data = buffer.0.lock().unwrap().clone();
+    //Magic for microseconds, version 2.4, UTC, the snaplen and LINKTYPE_RAW
+    assert_eq!(u32_at(&data, 0), 0xA1B2_C3D4);
+    assert_eq!((u16_at(&data, 4), u16_at(&data, 6)), (2, 4));
+    assert_eq!((u32_at(&data, 8), u32_at(&data, 12)), (0, 0));
+    assert_eq!((u32_at(&data, 16), u32_at(&data, 20)), (4, 101));
+    //Seconds, microseconds, captured and original lengths, then the packet unpadded
+    let first = &data[24..24 + 19];
+    assert!(u32_at(first, 0) > 1_600_000_000);
+    assert!(u32_at(first, 4) < 1_000_000);
+    assert_eq!((u32_at(first, 8), u32_at(first, 12)), (3, 3));
+    assert_eq!(&first[16..], b"123");
+    let second = &data[24 + 19..];
+    assert_eq!(second.len(), 20);
+    assert_eq!((u32_at(second, 8), u32_at(second, 12)), (4, 6));
+    assert_eq!(&second[16..], b"1234");
+}
@@ -0,0 +1,56 @@
+//Command line parsing of the binary: `cargo test --test cli`
+use libopenvpn3::openvpn::cli::{self, Args};
+
+fn args(args: &[&str]) -> Result<Args, String> {
+    Args::parse(&args.iter().map(|s| s.to_string()).collect::<Vec<String>>())
+}
+
+fn temp_file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("libopenvpn3-cli-{}-{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn options_and_positional_arguments() {
+    let parsed = args(&["a.ovpn", "--stats", "5", "b.ovpn", "--socks5", "127.0.0.1:1080", "--stats", "7"]).unwrap();
+    assert_eq!(parsed.positional, vec!["a.ovpn", "b.ovpn"]);
+    //The last one wins
+    assert_eq!(parsed.option("stats"), Some("7"));
+    assert_eq!(parsed.option("socks5"), Some("127.0.0.1:1080"));
+    assert_eq!(parsed.option("http-proxy"), None);
+    //Values are taken as they are, even when they look like options
+    let parsed = args(&["--pass", "--secret"]).unwrap();
+    assert_eq!(parsed.option("pass"), Some("--secret"));
+    assert!(parsed.positional.is_empty());
+
+    assert_eq!(args(&["a.ovpn", "--stats"]).unwrap_err(), "--stats needs a value");
+    assert!(args(&[]).unwrap().positional.is_empty());
+}
+
+#[test]
+fn auth_files() {
+    assert_eq!(cli::parse_auth_file("alice\nhunter2\n"), (Some("alice".into()), Some("hunter2".into())));
+    //CRLF and trailing spaces go, leading ones stay, lines after the second are ignored
+    assert_eq!(cli::parse_auth_file("alice \r\n pass word\t\r\nextra\n"), (Some("alice".into()), Some(" pass word".into())));
+    assert_eq!(cli::parse_auth_file("alice"), (Some("alice".into()), None));
+    assert_eq!(cli::parse_auth_file(""), (None, None));
+
+    let path = temp_file("auth", "bob\nsecret\n");
+    assert_eq!(cli::read_auth_file(&path).unwrap(), (Some("bob".into()), Some("secret".into())));
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(cli::read_auth_file(&path).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+}
+
+#[test]
+fn auth_file_takes_precedence_over_user_and_pass() {
+    let from_options = args(&["--user", "carol", "--pass", "pw"]).unwrap();
+    assert_eq!(cli::credentials(&from_options).unwrap(), (Some("carol".into()), Some("pw".into())));
+    assert_eq!(cli::credentials(&args(&["--user", "carol"]).unwrap()).unwrap(), (Some("carol".into()), None));
+
+    let path = temp_file("precedence", "dave\nfile-pw\n");
+    let both = args(&["--user", "carol", "--pass", "pw", "--auth-file", path.to_str().unwrap()]).unwrap();
+    assert_eq!(cli::credentials(&both).unwrap(), (Some("dave".into()), Some("file-pw".into())));
+    std::fs::remove_file(&path).unwrap();
+    assert!(cli::credentials(&both).is_err());
+}
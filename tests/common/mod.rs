@@ -0,0 +1,323 @@
+//Local OpenVPN server for the integration tests
+//
+//Each test gets its own `openvpn` server process on loopback, with a throwaway PKI from the
+//`pki` module, its own port and its own tunnel network. The server's tun device needs root,
+//so the tests are ignored by default. Run without root, `/dev/net/tun` or `openvpn`, they
+//fail saying which is missing.
+#![allow(dead_code)]
+use std::fs;
+use std::io::Read;
+use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::{CompressionPolicy, OVPNClient, OVPNEvent, OnVpnEvent, OnVpnLog, Packet, PacketBuilder};
+use libopenvpn3::openvpn::packet::{TcpHeader, Transport};
+use libopenvpn3::openvpn::pki::{Pki, ProfileOptions};
+pub use libopenvpn3::openvpn::pki::{Proto, TlsWrap};
+
+const SERVER_START_TIMEOUT: Duration = Duration::from_secs(15);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub proto: Proto,
+    pub tls_wrap: TlsWrap,
+    ///Username and password the server accepts, on top of the client certificate
+    pub credentials: Option<(String, String)>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            proto: Proto::Udp,
+            tls_wrap: TlsWrap::None,
+            credentials: None,
+        }
+    }
+}
+
+///Why the tests can't run here, if they can't
+pub fn missing_requirements() -> Option<String> {
+    let found = Command::new("openvpn").arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        return Some("openvpn is not installed".into());
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return Some("the server's tun device needs root".into());
+    }
+    if !Path::new("/dev/net/tun").exists() {
+        return Some("/dev/net/tun is missing".into());
+    }
+    None
+}
+
+///Temporary directory, removed on drop
+pub struct TestDir(PathBuf);
+
+impl TestDir {
+    pub fn new(name: &str) -> TestDir {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("libopenvpn3-{}-{}-{}", name, std::process::id(), id));
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+pub struct Server {
+    child: Child,
+    dir: TestDir,
+    pki: Pki,
+    options: ProfileOptions,
+}
+
+impl Server {
+    ///Starts a server and waits until it's ready. Panics when the tests can't run here.
+    pub fn start(config: ServerConfig) -> Server {
+        if let Some(reason) = missing_requirements() {
+            panic!("can't start a local openvpn server: {}", reason);
+        }
+        let dir = TestDir::new("server");
+        let pki = Pki::generate("server").unwrap();
+        //Tunnel networks must not overlap between servers running at the same time
+        let network = ((std::process::id() as usize * 7 + NEXT_ID.fetch_add(1, Ordering::SeqCst)) % 250) as u8;
+        let mut options = ProfileOptions {
+            remote: "127.0.0.1".into(),
+            port: free_port(config.proto),
+            proto: config.proto,
+            tls_wrap: config.tls_wrap,
+            network: Ipv4Addr::new(10, 201, network, 0),
+            ..ProfileOptions::default()
+        };
+        if let Some((username, password)) = &config.credentials {
+            let script = dir.path().join("verify.sh");
+            //via-file gives the username and password on the first two lines
+            fs::write(&script, format!("#!/bin/sh\n[ \"$(sed -n 1p \"$1\")\" = '{}' ] && [ \"$(sed -n 2p \"$1\")\" = '{}' ]\n", username, password)).unwrap();
+            Command::new("chmod").arg("755").arg(&script).status().unwrap();
+            options.auth_user_pass_verify = Some(script.display().to_string());
+        }
+        fs::write(dir.path().join("server.conf"), pki.server_profile(&options) + "log server.log\n").unwrap();
+        let child = Command::new("openvpn")
+            .args(["--config", "server.conf"])
+            .current_dir(dir.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut server = Server {
+            child: child,
+            dir: dir,
+            pki: pki,
+            options: options,
+        };
+        server.wait_ready();
+        server
+    }
+
+    fn wait_ready(&mut self) {
+        let deadline = Instant::now() + SERVER_START_TIMEOUT;
+        loop {
+            if self.log().contains("Initialization Sequence Completed") {
+                return;
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                panic!("openvpn exited with {}:\n{}", status, self.log());
+            }
+            if Instant::now() >= deadline {
+                panic!("openvpn didn't start:\n{}", self.log());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("server.log")).unwrap_or_default()
+    }
+
+    ///The server's own address on the tunnel
+    pub fn tunnel_ip(&self) -> Ipv4Addr {
+        let network = self.options.network.octets();
+        Ipv4Addr::new(network[0], network[1], network[2], 1)
+    }
+
+    ///Inline profile for a new client of this server
+    pub fn client_profile(&self) -> String {
+        let client = self.pki.client("client").unwrap();
+        self.pki.client_profile(&client, &self.options)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+//A port nothing listens on right now
+fn free_port(proto: Proto) -> u16 {
+    match proto {
+        Proto::Udp => UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port(),
+        Proto::Tcp => TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port(),
+    }
+}
+
+///OVPNClient that keeps its events
+pub struct Client {
+    pub client: OVPNClient,
+    events: Arc<Mutex<Vec<OVPNEvent>>>,
+}
+
+impl Client {
+    ///Connects to `server` and waits for CONNECTED or a fatal event
+    pub fn connect(server: &Server, username: Option<&str>, password: Option<&str>) -> Client {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let on_vpn_event: OnVpnEvent = {
+            let events = events.clone();
+            Arc::new(Mutex::new(move |event: OVPNEvent| events.lock().unwrap().push(event)))
+        };
+        let on_vpn_log: OnVpnLog = Arc::new(Mutex::new(|line: String| eprintln!("client: {}", line)));
+        let client = OVPNClient::new(server.client_profile(), username, password, None, None, Some(on_vpn_log), Some(on_vpn_event), None, None, CompressionPolicy::default(), None)
+            .unwrap();
+        assert!(client.connect().is_ok());
+        let client = Client {
+            client: client,
+            events: events,
+        };
+        client.wait_event(|e| e.name == "CONNECTED" || e.is_fatal() || e.name == "DISCONNECTED", CONNECT_TIMEOUT);
+        client
+    }
+
+    pub fn events(&self) -> Vec<OVPNEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn wait_event<F: Fn(&OVPNEvent) -> bool>(&self, f: F, timeout: Duration) -> Option<OVPNEvent> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(event) = self.events.lock().unwrap().iter().find(|e| f(e)) {
+                return Some(event.clone());
+            }
+            if Instant::now() >= deadline {
+                return None;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.events().iter().any(|e| e.name == "CONNECTED")
+    }
+
+    ///Our address on the tunnel
+    pub fn tunnel_ip(&self) -> Ipv4Addr {
+        match self.client.pushed_config().and_then(|c| c.ipv4).map(|prefix| prefix.addr) {
+            Some(std::net::IpAddr::V4(ip)) => ip,
+            other => panic!("no IPv4 address pushed: {:?}", other),
+        }
+    }
+
+    ///Waits for a received packet `f` accepts, skipping others
+    pub fn receive_matching<F: Fn(&Packet) -> bool>(&mut self, f: F) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while Instant::now() < deadline {
+            let mut received = None;
+            if self.client.receive(&mut |packet| received = Some(packet.to_vec())).is_err() {
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            if let Some(packet) = received {
+                if Packet::parse(&packet).map(|p| f(&p)).unwrap_or(false) {
+                    return Some(packet);
+                }
+            }
+        }
+        None
+    }
+
+    ///Pings `destination` through the tunnel and waits for the reply
+    pub fn ping(&mut self, destination: Ipv4Addr) -> bool {
+        let echo = PacketBuilder::ipv4(self.tunnel_ip(), destination)
+            .icmp(8, 0, [0x12, 0x34, 0, 1])
+            .payload(b"libopenvpn3 ping")
+            .build();
+        assert!(self.client.send(&echo).is_ok());
+        self.receive_matching(|p| match p.transport() {
+            Some(Transport::Icmp(icmp)) => icmp.icmp_type == 0 && icmp.rest == [0x12, 0x34, 0, 1] && p.source() == destination,
+            _ => false
+        }).is_some()
+    }
+
+    ///Opens a TCP connection to `listener`, which must be bound on the server's tunnel
+    ///address, by crafting the handshake, and sends `data` over it. Returns what the
+    ///listener read.
+    pub fn tcp_exchange(&mut self, listener: &TcpListener, data: &[u8]) -> Vec<u8> {
+        let server = listener.local_addr().unwrap();
+        let destination = match server.ip() {
+            std::net::IpAddr::V4(ip) => ip,
+            _ => panic!("IPv4 listener expected"),
+        };
+        let source = self.tunnel_ip();
+        let source_port = 40000;
+        let sequence = 1000;
+        let syn = PacketBuilder::ipv4(source, destination).tcp(source_port, server.port(), sequence, 0, TcpHeader::SYN).build();
+        assert!(self.client.send(&syn).is_ok());
+        let syn_ack = self.receive_matching(|p| match p.transport() {
+            Some(Transport::Tcp(tcp)) => tcp.flags & (TcpHeader::SYN | TcpHeader::ACK) == TcpHeader::SYN | TcpHeader::ACK && tcp.destination_port == source_port,
+            _ => false
+        }).expect("no SYN-ACK");
+        let server_sequence = match Packet::parse(&syn_ack).unwrap().transport() {
+            Some(Transport::Tcp(tcp)) => tcp.sequence,
+            _ => unreachable!(),
+        };
+        let segment = PacketBuilder::ipv4(source, destination)
+            .tcp(source_port, server.port(), sequence + 1, server_sequence.wrapping_add(1), TcpHeader::ACK | TcpHeader::PSH)
+            .payload(data)
+            .build();
+        assert!(self.client.send(&segment).is_ok());
+        listener.set_nonblocking(true).unwrap();
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(_) if Instant::now() < deadline => std::thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("no connection: {}", e),
+            }
+        };
+        stream.set_nonblocking(false).unwrap();
+        stream.set_read_timeout(Some(REPLY_TIMEOUT)).unwrap();
+        let mut received = vec![0u8; data.len()];
+        stream.read_exact(&mut received).unwrap();
+        //We have no TCP stack to close the connection with
+        let reset = PacketBuilder::ipv4(source, destination)
+            .tcp(source_port, server.port(), sequence + 1 + data.len() as u32, 0, TcpHeader::RST)
+            .build();
+        let _ = self.client.send(&reset);
+        received
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self.client.disconnect();
+    }
+}
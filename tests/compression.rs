@@ -0,0 +1,39 @@
+//Compression policy applied to profiles: `cargo test --test compression`
+use libopenvpn3::openvpn::{CompressionAlgorithm, CompressionPolicy};
+
+const PROFILE: &str = "client\nremote vpn.example.com 1194\nallow-compression yes\n";
+
+#[test]
+fn policy_replaces_the_profiles_own() {
+    let applied = CompressionPolicy::Refuse.apply(PROFILE).unwrap();
+    assert_eq!(applied, "client\nremote vpn.example.com 1194\nallow-compression no\n");
+    assert!(CompressionPolicy::default().apply(PROFILE).unwrap().ends_with("allow-compression asym\n"));
+}
+
+#[test]
+fn refuse_rejects_compressing_profiles() {
+    for directive in ["comp-lzo", "comp-lzo adaptive", "compress lz4-v2", "compress lzo"].iter() {
+        let profile = format!("{}{}\n", PROFILE, directive);
+        assert!(CompressionPolicy::Refuse.apply(&profile).is_err(), "{}", directive);
+    }
+    //Framing only, nothing compressed
+    for directive in ["compress", "compress stub-v2", "comp-lzo no"].iter() {
+        let profile = format!("{}{}\n", PROFILE, directive);
+        assert!(CompressionPolicy::Refuse.apply(&profile).is_ok(), "{}", directive);
+    }
+}
+
+#[test]
+fn algorithms_missing_from_the_build_are_rejected() {
+    let profile = format!("{}compress lz4\n", PROFILE);
+    assert_eq!(CompressionPolicy::Allow.apply(&profile).is_ok(), cfg!(feature = "lz4"));
+    assert_eq!(CompressionAlgorithm::from_directive("comp-lzo", &["yes"]), Some(CompressionAlgorithm::Lzo));
+    assert_eq!(CompressionAlgorithm::Lzo.is_available(), cfg!(feature = "lzo"));
+}
+
+#[test]
+fn parses_policy_names() {
+    assert_eq!("asym".parse::<CompressionPolicy>(), Ok(CompressionPolicy::Asymmetric));
+    assert_eq!("refuse".parse::<CompressionPolicy>(), Ok(CompressionPolicy::Refuse));
+    assert!("maybe".parse::<CompressionPolicy>().is_err());
+}
@@ -0,0 +1,373 @@
+//DNS through the tunnel, against a fake server answering at the packet level behind the
+//mock backend: `cargo test --no-default-features --features stack,mock --test dns`
+#![cfg(all(feature = "stack", feature = "mock"))]
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::{MockConfig, MockHandle, OVPNClient, Packet, PacketBuilder};
+use libopenvpn3::openvpn::dns::{Resolver, TunnelResolver};
+use libopenvpn3::openvpn::packet::{TcpHeader, Transport};
+use libopenvpn3::openvpn::stack::{Stack, StackConfig};
+
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 1);
+const SERVER_SEQUENCE: u32 = 5000;
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const FLAGS_ANSWER: u16 = 0x8180;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+//Compression pointer to the name in the question
+const QUESTION_NAME: [u8; 2] = [0xc0, 0x0c];
+
+#[derive(Debug, Clone, PartialEq)]
+struct Query {
+    id: u16,
+    flags: u16,
+    questions: u16,
+    name: String,
+    qtype: u16,
+    tcp: bool,
+}
+
+fn parse_query(message: &[u8], tcp: bool) -> Query {
+    let mut labels = Vec::new();
+    let mut offset = 12;
+    while message[offset] != 0 {
+        let len = message[offset] as usize;
+        labels.push(String::from_utf8(message[offset + 1..offset + 1 + len].to_vec()).unwrap());
+        offset += 1 + len;
+    }
+    assert_eq!(&message[offset + 3..offset + 5], &[0, 1], "class IN");
+    Query {
+        id: u16::from_be_bytes([message[0], message[1]]),
+        flags: u16::from_be_bytes([message[2], message[3]]),
+        questions: u16::from_be_bytes([message[4], message[5]]),
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([message[offset + 1], message[offset + 2]]),
+        tcp: tcp,
+    }
+}
+
+struct Record {
+    name: Vec<u8>,
+    rtype: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+fn record(rtype: u16, ttl: u32, data: &[u8]) -> Record {
+    Record {
+        name: QUESTION_NAME.to_vec(),
+        rtype: rtype,
+        ttl: ttl,
+        data: data.to_vec(),
+    }
+}
+
+fn a(ttl: u32, ip: [u8; 4]) -> Record {
+    record(TYPE_A, ttl, &ip)
+}
+
+fn aaaa(ttl: u32, ip: &str) -> Record {
+    record(TYPE_AAAA, ttl, &ip.parse::<std::net::Ipv6Addr>().unwrap().octets())
+}
+
+fn response(query: &Query, flags: u16, records: &[Record]) -> Vec<u8> {
+    let mut message = query.id.to_be_bytes().to_vec();
+    message.extend_from_slice(&flags.to_be_bytes());
+    message.extend_from_slice(&[0, 1]);
+    message.extend_from_slice(&(records.len() as u16).to_be_bytes());
+    message.extend_from_slice(&[0, 0, 0, 0]);
+    for label in query.name.split('.') {
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&query.qtype.to_be_bytes());
+    message.extend_from_slice(&[0, 1]);
+    for record in records {
+        message.extend_from_slice(&record.name);
+        message.extend_from_slice(&record.rtype.to_be_bytes());
+        message.extend_from_slice(&[0, 1]);
+        message.extend_from_slice(&record.ttl.to_be_bytes());
+        message.extend_from_slice(&(record.data.len() as u16).to_be_bytes());
+        message.extend_from_slice(&record.data);
+    }
+    message
+}
+
+type Answer = dyn Fn(&Query) -> Vec<u8> + Send;
+
+//Client side of a TCP connection to the fake server
+struct TcpConnection {
+    next_sequence: u32,
+    sent: u32,
+    received: Vec<u8>,
+}
+
+//Answers the DNS queries the stack sends to SERVER_IP, over UDP and TCP
+struct FakeServer {
+    handle: MockHandle,
+    answer: Box<Answer>,
+    queries: Arc<Mutex<Vec<Query>>>,
+    connections: HashMap<u16, TcpConnection>,
+}
+
+impl FakeServer {
+    fn run(mut self) {
+        let mut seen = 0;
+        loop {
+            let sent = self.handle.sent();
+            for packet in sent[seen..].iter() {
+                self.handle_packet(packet);
+            }
+            seen = sent.len();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn reply(&self) -> PacketBuilder {
+        PacketBuilder::ipv4(SERVER_IP, CLIENT_IP)
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) {
+        let packet = match Packet::parse(packet) {
+            Ok(packet) => packet,
+            Err(_) => return
+        };
+        match packet.transport() {
+            Some(Transport::Udp(udp)) if udp.destination_port == 53 => {
+                let query = parse_query(packet.payload(), false);
+                self.queries.lock().unwrap().push(query.clone());
+                let response = (self.answer)(&query);
+                self.handle.inject(&self.reply().udp(53, udp.source_port).payload(&response).build());
+            },
+            Some(Transport::Tcp(tcp)) if tcp.destination_port == 53 => {
+                let port = tcp.source_port;
+                if tcp.flags & TcpHeader::SYN != 0 {
+                    self.connections.insert(port, TcpConnection {
+                        next_sequence: tcp.sequence.wrapping_add(1),
+                        sent: 0,
+                        received: Vec::new(),
+                    });
+                    self.handle.inject(&self.reply().tcp(53, port, SERVER_SEQUENCE, tcp.sequence.wrapping_add(1), TcpHeader::SYN | TcpHeader::ACK).build());
+                    return;
+                }
+                let connection = match self.connections.get_mut(&port) {
+                    Some(connection) => connection,
+                    None => return
+                };
+                let mut segments = Vec::new();
+                if !packet.payload().is_empty() {
+                    connection.received.extend_from_slice(packet.payload());
+                    connection.next_sequence = connection.next_sequence.wrapping_add(packet.payload().len() as u32);
+                    let received = &connection.received;
+                    if received.len() >= 2 && received.len() == 2 + u16::from_be_bytes([received[0], received[1]]) as usize {
+                        let query = parse_query(&received[2..], true);
+                        self.queries.lock().unwrap().push(query.clone());
+                        let response = (self.answer)(&query);
+                        let mut framed = (response.len() as u16).to_be_bytes().to_vec();
+                        framed.extend_from_slice(&response);
+                        segments.push((TcpHeader::PSH | TcpHeader::ACK, framed.clone()));
+                        segments.push((TcpHeader::FIN | TcpHeader::ACK, Vec::new()));
+                    } else {
+                        segments.push((TcpHeader::ACK, Vec::new()));
+                    }
+                }
+                if tcp.flags & TcpHeader::FIN != 0 {
+                    connection.next_sequence = connection.next_sequence.wrapping_add(1);
+                    segments.push((TcpHeader::ACK, Vec::new()));
+                }
+                for (flags, payload) in segments {
+                    let sequence = SERVER_SEQUENCE + 1 + connection.sent;
+                    let segment = PacketBuilder::ipv4(SERVER_IP, CLIENT_IP)
+                        .tcp(53, port, sequence, connection.next_sequence, flags)
+                        .payload(&payload)
+                        .build();
+                    connection.sent += payload.len() as u32 + (flags & TcpHeader::FIN != 0) as u32;
+                    self.handle.inject(&segment);
+                }
+            },
+            _ => {}
+        }
+    }
+}
+
+//A resolver over a stack whose fake DNS server answers with `answer`. Returns what it was asked.
+fn resolver<F>(search_domains: &[&str], answer: F) -> (TunnelResolver, Arc<Mutex<Vec<Query>>>)
+where
+    F: Fn(&Query) -> Vec<u8> + Send + 'static,
+{
+    let (client, handle) = OVPNClient::mock(MockConfig {
+        loopback: false,
+        ..MockConfig::default()
+    }, None, None);
+    assert!(client.connect().is_ok());
+    let pushed = client.wait_pushed_config(Duration::from_secs(5)).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !handle.is_connected() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let stack = Arc::new(Stack::new(client, StackConfig::from_pushed(&pushed)).unwrap());
+    let queries = Arc::new(Mutex::new(Vec::new()));
+    let server = FakeServer {
+        handle: handle,
+        answer: Box::new(answer),
+        queries: queries.clone(),
+        connections: HashMap::new(),
+    };
+    std::thread::spawn(move || server.run());
+    let mut resolver = TunnelResolver::new(stack, vec![IpAddr::V4(SERVER_IP)], search_domains.iter().map(|d| d.to_string()).collect());
+    resolver.set_timeout(Duration::from_secs(2));
+    (resolver, queries)
+}
+
+fn ips(ips: &[&str]) -> Vec<IpAddr> {
+    ips.iter().map(|ip| ip.parse().unwrap()).collect()
+}
+
+#[test]
+fn follows_compressed_names_and_skips_cnames() {
+    let (resolver, queries) = resolver(&[], |query| {
+        //www.example.com is an alias of cdn.www.example.com, which has the addresses
+        let cdn = [&[3u8, b'c', b'd', b'n'][..], &QUESTION_NAME[..]].concat();
+        let alias = record(TYPE_CNAME, 300, &cdn);
+        let mut addresses = match query.qtype {
+            TYPE_A => vec![a(300, [192, 0, 2, 1]), a(300, [192, 0, 2, 2])],
+            _ => vec![aaaa(300, "2001:db8::1")],
+        };
+        for address in addresses.iter_mut() {
+            address.name = cdn.clone();
+        }
+        //A record of the other type is ignored
+        addresses.push(if query.qtype == TYPE_A { aaaa(300, "2001:db8::9") } else { a(300, [192, 0, 2, 9]) });
+        addresses.insert(0, alias);
+        response(query, FLAGS_ANSWER, &addresses)
+    });
+    assert_eq!(resolver.lookup("www.example.com").unwrap(), ips(&["192.0.2.1", "192.0.2.2", "2001:db8::1"]));
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.len(), 2);
+    //Recursion desired, one question, A then AAAA, each with its own id
+    for (query, qtype) in queries.iter().zip([TYPE_A, TYPE_AAAA].iter()) {
+        assert_eq!((query.flags, query.questions, query.name.as_str(), query.qtype, query.tcp), (0x0100, 1, "www.example.com", *qtype, false));
+    }
+    assert_ne!(queries[0].id, queries[1].id);
+}
+
+#[test]
+fn answers_are_cached_for_their_lowest_ttl() {
+    let (resolver, queries) = resolver(&[], |query| match query.name.as_str() {
+        "cached.example" => response(query, FLAGS_ANSWER, &[a(300, [192, 0, 2, 1]), a(600, [192, 0, 2, 2])]),
+        //One record that can't be kept makes the whole answer uncacheable
+        _ => response(query, FLAGS_ANSWER, &[a(300, [192, 0, 2, 3]), a(0, [192, 0, 2, 4])]),
+    });
+    for _ in 0..2 {
+        assert_eq!(resolver.lookup("cached.example").unwrap(), ips(&["192.0.2.1", "192.0.2.2"]));
+    }
+    //Names are cached whatever their case
+    assert!(resolver.lookup("CACHED.example").is_ok());
+    //An empty AAAA answer has no TTL to keep it by
+    assert_eq!(queries.lock().unwrap().len(), 4);
+    for _ in 0..2 {
+        assert_eq!(resolver.lookup("expired.example").unwrap(), ips(&["192.0.2.3", "192.0.2.4"]));
+    }
+    assert_eq!(queries.lock().unwrap().iter().filter(|q| q.name == "expired.example").count(), 4);
+}
+
+#[test]
+fn search_domains_come_first_for_short_names() {
+    let (mut resolver, queries) = resolver(&["corp.example", ".example.net."], |query| match query.name.as_str() {
+        "intranet.example.net" | "host.sub" | "mail.corp.corp.example" | "absolute.example" =>
+            response(query, FLAGS_ANSWER, &[a(300, [192, 0, 2, 1])]),
+        _ => response(query, FLAGS_ANSWER | RCODE_NXDOMAIN, &[]),
+    });
+    let resolved = Arc::new(Mutex::new(Vec::new()));
+    {
+        let resolved = resolved.clone();
+        resolver.set_on_resolved(Arc::new(move |host: &str, addrs: &[IpAddr]| resolved.lock().unwrap().push((host.to_owned(), addrs.to_vec()))));
+    }
+    let asked = |queries: &Mutex<Vec<Query>>| {
+        let mut names: Vec<String> = queries.lock().unwrap().drain(..).map(|q| q.name).collect();
+        names.dedup();
+        names
+    };
+    assert!(resolver.lookup("intranet").is_ok());
+    assert_eq!(asked(&queries), vec!["intranet.corp.example", "intranet.example.net"]);
+    //Names with a dot are tried as they are first
+    assert!(resolver.lookup("host.sub").is_ok());
+    assert_eq!(asked(&queries), vec!["host.sub"]);
+    assert!(resolver.lookup("mail.corp").is_ok());
+    assert_eq!(asked(&queries), vec!["mail.corp", "mail.corp.corp.example"]);
+    //A trailing dot means no search
+    assert_eq!(resolver.lookup("absolute.example.").unwrap(), ips(&["192.0.2.1"]));
+    assert_eq!(asked(&queries), vec!["absolute.example"]);
+    let e = resolver.lookup("nowhere").unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert_eq!(asked(&queries), vec!["nowhere.corp.example", "nowhere.example.net", "nowhere"]);
+
+    let resolved = resolved.lock().unwrap();
+    assert_eq!(resolved.iter().map(|(host, _)| host.as_str()).collect::<Vec<&str>>(), vec!["intranet", "host.sub", "mail.corp", "absolute.example."]);
+    assert_eq!(resolved[0].1, ips(&["192.0.2.1"]));
+}
+
+#[test]
+fn truncated_answers_are_asked_again_over_tcp() {
+    let (resolver, queries) = resolver(&[], |query| {
+        if !query.tcp {
+            return response(query, FLAGS_ANSWER | FLAG_TRUNCATED, &[]);
+        }
+        match query.qtype {
+            TYPE_A => response(query, FLAGS_ANSWER, &(1..=40).map(|i| a(300, [198, 51, 100, i])).collect::<Vec<Record>>()),
+            _ => response(query, FLAGS_ANSWER, &[]),
+        }
+    });
+    let expected: Vec<IpAddr> = (1..=40).map(|i| IpAddr::V4(Ipv4Addr::new(198, 51, 100, i))).collect();
+    assert_eq!(resolver.lookup("big.example").unwrap(), expected);
+    let queries = queries.lock().unwrap();
+    assert_eq!(queries.iter().map(|q| (q.qtype, q.tcp)).collect::<Vec<(u16, bool)>>(),
+        vec![(TYPE_A, false), (TYPE_A, true), (TYPE_AAAA, false), (TYPE_AAAA, true)]);
+    //The same query, id included, goes over TCP
+    assert_eq!(queries[0].id, queries[1].id);
+}
+
+#[test]
+fn errors() {
+    let (resolver, queries) = resolver(&[], |query| match query.name.as_str() {
+        "failing.example" => response(query, FLAGS_ANSWER | RCODE_SERVFAIL, &[]),
+        _ => {
+            //Cut off in the middle of the address
+            let mut message = response(query, FLAGS_ANSWER, &[a(300, [192, 0, 2, 1])]);
+            message.truncate(message.len() - 2);
+            message
+        }
+    });
+    assert_eq!(resolver.lookup("cut.example").unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(resolver.lookup("failing.example").unwrap_err().kind(), ErrorKind::Other);
+    //Every attempt is used before giving up
+    assert_eq!(queries.lock().unwrap().iter().filter(|q| q.name == "failing.example").count(), 4);
+    //A label longer than 63 bytes can't be encoded
+    let long = format!("{}.example", "x".repeat(64));
+    assert_eq!(resolver.lookup(&long).unwrap_err().kind(), ErrorKind::InvalidInput);
+    //Nor an empty label, or a name longer than 255 bytes on the wire
+    queries.lock().unwrap().clear();
+    for name in ["a..example", ".example", "."].iter() {
+        assert_eq!(resolver.lookup(name).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", name);
+    }
+    let longest = ["x".repeat(63), "x".repeat(63), "x".repeat(63), "x".repeat(61)].join(".");
+    assert_eq!(longest.len(), 253);
+    assert_eq!(resolver.lookup(&format!("{}.y", longest)).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert!(queries.lock().unwrap().is_empty());
+    //The longest name is asked for, and isn't found
+    assert_eq!(resolver.lookup(&longest).unwrap_err().kind(), ErrorKind::InvalidData);
+    assert_eq!(queries.lock().unwrap().last().unwrap().name, longest);
+    //Addresses aren't looked up
+    queries.lock().unwrap().clear();
+    assert_eq!(resolver.resolve("[2001:db8::1]").unwrap(), ips(&["2001:db8::1"]));
+    assert_eq!(resolver.resolve("192.0.2.7").unwrap(), ips(&["192.0.2.7"]));
+    assert!(queries.lock().unwrap().is_empty());
+}
@@ -0,0 +1,119 @@
+//Packet filter verdicts and counters: `cargo test --test filter`
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use libopenvpn3::openvpn::{Direction, FilterMatch, FilterRule, PacketBuilder, PacketFilter, Verdict};
+use libopenvpn3::openvpn::packet::{PROTOCOL_ICMP, PROTOCOL_UDP};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+
+//Outbound UDP to `ip:port`
+fn udp_to(ip: [u8; 4], port: u16) -> Vec<u8> {
+    PacketBuilder::ipv4(LOCAL, Ipv4Addr::from(ip)).udp(40000, port).payload(b"data").build()
+}
+
+fn counters(filter: &PacketFilter) -> Vec<(String, u64, u64)> {
+    filter.stats().into_iter().map(|s| (s.name, s.packets, s.bytes)).collect()
+}
+
+#[test]
+fn first_deciding_rule_wins() {
+    let filter = PacketFilter::new();
+    filter.add_rule(FilterRule::allow("dns", FilterMatch::All(vec![FilterMatch::Protocol(PROTOCOL_UDP), FilterMatch::Ports(53, 53)])));
+    filter.add_rule(FilterRule::block("lan", FilterMatch::Prefix("192.168.0.0/16".parse().unwrap())));
+    filter.add_rule(FilterRule::block("icmp", FilterMatch::Protocol(PROTOCOL_ICMP)));
+    let ping = PacketBuilder::ipv4(LOCAL, Ipv4Addr::new(8, 8, 8, 8)).icmp(8, 0, [0, 1, 0, 1]).build();
+    let cases: Vec<(Vec<u8>, Verdict)> = vec![
+        //Allowed before the LAN rule sees it
+        (udp_to([192, 168, 1, 1], 53), Verdict::Allow),
+        (udp_to([192, 168, 1, 1], 54), Verdict::Drop),
+        (udp_to([8, 8, 8, 8], 443), Verdict::Allow),
+        (ping, Verdict::Drop),
+        //Garbage matches no built-in rule
+        (vec![0x45, 0, 0], Verdict::Allow),
+    ];
+    for (packet, verdict) in cases.iter() {
+        assert_eq!(filter.check(Direction::Outbound, packet), *verdict);
+    }
+    let size = udp_to([0, 0, 0, 0], 0).len() as u64;
+    assert_eq!(counters(&filter), vec![("dns".into(), 1, size), ("lan".into(), 1, size), ("icmp".into(), 1, 28)]);
+}
+
+#[test]
+fn matches_use_the_remote_end() {
+    let filter = PacketFilter::new();
+    filter.add_rule(FilterRule::block("web", FilterMatch::Ports(80, 443)));
+    //Inbound, the server's port is the source
+    let from_web = PacketBuilder::ipv4(Ipv4Addr::new(192, 0, 2, 1), LOCAL).udp(443, 40000).build();
+    let to_us = PacketBuilder::ipv4(Ipv4Addr::new(192, 0, 2, 1), LOCAL).udp(40000, 443).build();
+    assert_eq!(filter.check(Direction::Inbound, &from_web), Verdict::Drop);
+    assert_eq!(filter.check(Direction::Inbound, &to_us), Verdict::Allow);
+
+    let filter = PacketFilter::new();
+    filter.add_rule(FilterRule::block("outbound only", FilterMatch::Any).direction(Direction::Outbound));
+    assert_eq!(filter.check(Direction::Inbound, &from_web), Verdict::Allow);
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([192, 0, 2, 1], 1)), Verdict::Drop);
+}
+
+#[test]
+fn drop_by_default_keeps_only_what_is_allowed() {
+    let filter = PacketFilter::new();
+    filter.add_rule(FilterRule::allow("vpn", FilterMatch::Prefix("10.0.0.0/8".parse().unwrap())));
+    filter.set_drop_by_default(true);
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([10, 1, 1, 1], 22)), Verdict::Allow);
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([8, 8, 8, 8], 53)), Verdict::Drop);
+    filter.set_drop_by_default(false);
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([8, 8, 8, 8], 53)), Verdict::Allow);
+}
+
+#[test]
+fn rewrites_feed_the_next_rules() {
+    let filter = PacketFilter::new();
+    //Sends what goes to 192.0.2.1 to 198.51.100.1 instead
+    filter.add_rule(FilterRule::custom("redirect", Arc::new(|_, packet: &[u8]| {
+        if packet.len() >= 20 && packet[16..20] == [192, 0, 2, 1] {
+            Verdict::Rewrite(udp_to([198, 51, 100, 1], 53))
+        } else {
+            Verdict::Continue
+        }
+    })));
+    filter.add_rule(FilterRule::block("blocked", FilterMatch::Prefix("198.51.100.0/24".parse().unwrap())).direction(Direction::Inbound));
+    //Sees the redirected packet, only rewrites those to 198.51.100.1
+    filter.add_rule(FilterRule::custom("port", Arc::new(|_, packet: &[u8]| {
+        if packet.len() >= 20 && packet[16..20] == [198, 51, 100, 1] {
+            Verdict::Rewrite(udp_to([198, 51, 100, 1], 5353))
+        } else {
+            Verdict::Continue
+        }
+    })));
+    let verdict = filter.check(Direction::Outbound, &udp_to([192, 0, 2, 1], 80));
+    assert_eq!(verdict, Verdict::Rewrite(udp_to([198, 51, 100, 1], 5353)));
+    //Rules with no opinion on the packet count nothing
+    let size = udp_to([0, 0, 0, 0], 0).len() as u64;
+    assert_eq!(counters(&filter), vec![("redirect".into(), 1, size), ("blocked".into(), 0, 0), ("port".into(), 1, size)]);
+
+    //A drop after a rewrite drops
+    filter.add_rule(FilterRule::block("all", FilterMatch::Any));
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([192, 0, 2, 1], 80)), Verdict::Drop);
+    assert!(filter.remove_rule("all"));
+    assert!(!filter.remove_rule("all"));
+    assert_eq!(filter.stats().len(), 3);
+}
+
+#[test]
+fn custom_rules_can_change_the_filter() {
+    //Lets one packet through, then blocks everything from the next one on
+    let filter = Arc::new(PacketFilter::new());
+    let weak = Arc::downgrade(&filter);
+    filter.add_rule(FilterRule::custom("once", Arc::new(move |_, _: &[u8]| {
+        if let Some(filter) = weak.upgrade() {
+            filter.remove_rule("once");
+            filter.add_rule(FilterRule::block("closed", FilterMatch::Any));
+            //Counters can be read from inside a rule too
+            assert_eq!(filter.stats().len(), 1);
+        }
+        Verdict::Allow
+    })));
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([192, 0, 2, 1], 53)), Verdict::Allow);
+    assert_eq!(filter.check(Direction::Outbound, &udp_to([192, 0, 2, 1], 53)), Verdict::Drop);
+    assert_eq!(counters(&filter), vec![("closed".into(), 1, udp_to([0, 0, 0, 0], 0).len() as u64)]);
+}
@@ -0,0 +1,227 @@
+//HTTP proxy requests and responses, to destinations kept off the tunnel and to a fake peer
+//behind the mock backend: `cargo test --no-default-features --features http-proxy,mock --test http_proxy`
+#![cfg(all(feature = "http-proxy", feature = "mock"))]
+mod peer;
+
+use std::io::{Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use libopenvpn3::openvpn::{IpPrefix, MockConfig, OVPNClient, RouteTable};
+use libopenvpn3::openvpn::dns::Resolver;
+use libopenvpn3::openvpn::http_proxy::HttpProxyServer;
+use libopenvpn3::openvpn::stack::{Stack, StackConfig};
+use peer::{Peer, Service};
+
+const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+//Addresses stay as they are, every name is localhost
+struct FixedResolver;
+
+impl Resolver for FixedResolver {
+    fn resolve(&self, host: &str) -> Result<Vec<IpAddr>> {
+        Ok(vec![host.parse().unwrap_or(IpAddr::V4(LOCALHOST))])
+    }
+}
+
+//A proxy over a stack whose empty route table sends everything directly
+fn server(credentials: Option<(&str, &str)>) -> SocketAddr {
+    let (client, _) = OVPNClient::mock(MockConfig::default(), None, None);
+    let config = StackConfig {
+        ipv4: Some(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)), 24)),
+        routes: Some(RouteTable::new()),
+        ..StackConfig::default()
+    };
+    serve(Stack::new(client, config).unwrap(), credentials)
+}
+
+//Answers a request with 204 and hangs up once it has all of it, echoes anything else
+fn origin_or_echo() -> Arc<Service> {
+    Arc::new(|received: &mut Vec<u8>| {
+        let is_request = received.starts_with(b"GET ") || received.starts_with(b"POST ");
+        if !is_request {
+            return (std::mem::take(received), false);
+        }
+        let chunked = received.windows(8).any(|w| w.eq_ignore_ascii_case(b"chunked\r"));
+        if received.ends_with(b"\r\n\r\n") && (!chunked || received.ends_with(b"\r\n0\r\n\r\n")) {
+            received.clear();
+            (b"HTTP/1.1 204 No Content\r\n\r\n".to_vec(), true)
+        } else {
+            (Vec::new(), false)
+        }
+    })
+}
+
+//A proxy over a stack that tunnels 10.0.0.0/8 to the fake peer and the rest directly
+fn tunnel_server() -> (SocketAddr, Peer) {
+    let (stack, peer) = peer::stack_with(origin_or_echo(), |config| {
+        let mut routes = RouteTable::new();
+        routes.include(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8));
+        StackConfig { routes: Some(routes), ..config }
+    });
+    (serve(stack, None), peer)
+}
+
+fn serve(stack: Stack, credentials: Option<(&str, &str)>) -> SocketAddr {
+    let mut server = HttpProxyServer::bind(Arc::new(stack), SocketAddr::new(IpAddr::V4(LOCALHOST), 0)).unwrap()
+        .with_resolver(Arc::new(FixedResolver));
+    if let Some((username, password)) = credentials {
+        server = server.with_credentials(username, password);
+    }
+    let addr = server.local_addr().unwrap();
+    std::thread::spawn(move || server.run());
+    addr
+}
+
+//Sends `request` and reads the response until the proxy closes the connection
+fn exchange(server: SocketAddr, request: &[u8]) -> String {
+    let mut client = TcpStream::connect(server).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(request).unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn refused_port() -> u16 {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+//Hands over the request head it receives, then answers with 204 and hangs up
+fn origin_server() -> (u16, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        sender.send(String::from_utf8(head).unwrap()).unwrap();
+        stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+    });
+    (port, receiver)
+}
+
+#[test]
+fn connect_tunnels_the_pipelined_bytes() {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let echo = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut hello = [0u8; 5];
+        stream.read_exact(&mut hello).unwrap();
+        stream.write_all(&hello).unwrap();
+    });
+    let request = format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\n\r\nhello", port, port);
+    assert_eq!(exchange(server(None), request.as_bytes()), "HTTP/1.1 200 Connection Established\r\n\r\nhello");
+    echo.join().unwrap();
+}
+
+#[test]
+fn forward_rewrites_the_request() {
+    let (port, head) = origin_server();
+    let request = format!("GET http://example.com:{}/path?q=1 HTTP/1.1\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n", port);
+    assert_eq!(exchange(server(None), request.as_bytes()), "HTTP/1.1 204 No Content\r\n\r\n");
+    assert_eq!(head.recv_timeout(Duration::from_secs(5)).unwrap(),
+        format!("GET /path?q=1 HTTP/1.1\r\nHost: example.com:{}\r\nAccept: */*\r\nConnection: close\r\n\r\n", port));
+
+    //A Host header the client sent stays, and no path means /
+    let (port, head) = origin_server();
+    let request = format!("GET http://example.com:{} HTTP/1.0\r\nhost: example.com\r\n\r\n", port);
+    exchange(server(None), request.as_bytes());
+    assert_eq!(head.recv_timeout(Duration::from_secs(5)).unwrap(), "GET / HTTP/1.0\r\nhost: example.com\r\nConnection: close\r\n\r\n");
+}
+
+#[test]
+fn bad_requests_and_unreachable_hosts() {
+    let server = server(None);
+    let bad_request = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+    //Origin form, https:// without CONNECT, an unclosed IPv6 literal and a port that isn't one
+    assert_eq!(exchange(server, b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n"), bad_request);
+    assert_eq!(exchange(server, b"GET https://example.com/ HTTP/1.1\r\n\r\n"), bad_request);
+    assert_eq!(exchange(server, b"CONNECT [::1:443 HTTP/1.1\r\n\r\n"), bad_request);
+    assert_eq!(exchange(server, b"CONNECT example.com:https HTTP/1.1\r\n\r\n"), bad_request);
+    let request = format!("CONNECT example.com:{} HTTP/1.1\r\n\r\n", refused_port());
+    assert_eq!(exchange(server, request.as_bytes()), "HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+}
+
+#[test]
+fn basic_credentials() {
+    let request = |authorization: Option<&str>| {
+        let mut request = format!("CONNECT example.com:{} HTTP/1.1\r\n", refused_port());
+        if let Some(authorization) = authorization {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        request
+    };
+    let required = "HTTP/1.1 407 Proxy Authentication Required\r\nConnection: close\r\nContent-Length: 0\r\n\
+        Proxy-Authenticate: Basic realm=\"openvpn\"\r\n\r\n";
+    //RFC 7617's example, then every length of padding and a non-ASCII password
+    let vectors = [
+        ("Aladdin", "open sesame", "QWxhZGRpbjpvcGVuIHNlc2FtZQ=="),
+        ("a", "b", "YTpi"),
+        ("ab", "c", "YWI6Yw=="),
+        ("ab", "cd", "YWI6Y2Q="),
+        ("user", "päss", "dXNlcjpww6Rzcw=="),
+    ];
+    //The scheme in any case, and not base64 at all
+    let proxy = server(Some(("a", "b")));
+    let response = exchange(proxy, request(Some("bAsIc  YTpi")).as_bytes());
+    assert!(response.starts_with("HTTP/1.1 502 "), "{}", response);
+    assert_eq!(exchange(proxy, request(Some("Basic YTpi!")).as_bytes()), required);
+    assert_eq!(exchange(proxy, request(Some("Bearer YTpi")).as_bytes()), required);
+
+    for (username, password, encoded) in vectors.iter() {
+        let server = server(Some((username, password)));
+        assert_eq!(exchange(server, request(None).as_bytes()), required);
+        assert_eq!(exchange(server, request(Some("Basic d3Jvbmc6d3Jvbmc=")).as_bytes()), required);
+        //Past authentication, the connection is refused
+        let response = exchange(server, request(Some(&format!("Basic {}", encoded))).as_bytes());
+        assert!(response.starts_with("HTTP/1.1 502 "), "{}:{} gave {}", username, password, response);
+    }
+}
+
+#[test]
+fn forward_through_the_tunnel_drops_hop_by_hop_headers() {
+    let (server, peer) = tunnel_server();
+    let request = "POST HTTP://10.8.0.1/submit HTTP/1.1\r\nHost: 10.8.0.1\r\nConnection: keep-alive, X-Hop\r\n\
+        X-Hop: 1\r\nX-Kept: 2\r\nTE: trailers\r\nTrailer: Expires\r\nUpgrade: h2c\r\nKeep-Alive: timeout=5\r\n\
+        Transfer-Encoding: chunked\r\n\r\n4\r\nbody\r\n0\r\n\r\n";
+    assert_eq!(exchange(server, request.as_bytes()), "HTTP/1.1 204 No Content\r\n\r\n");
+    let connections = peer.wait_connections(|c| c.iter().all(|c| c.closed_by_client));
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].server, Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)), 80)));
+    //Our own framing and Connection header replace the client's
+    assert_eq!(String::from_utf8(connections[0].received.clone()).unwrap(),
+        "POST /submit HTTP/1.1\r\nHost: 10.8.0.1\r\nX-Kept: 2\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n4\r\nbody\r\n0\r\n\r\n");
+}
+
+#[test]
+fn connect_through_the_tunnel() {
+    let (server, peer) = tunnel_server();
+    let mut client = TcpStream::connect(server).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.write_all(b"CONNECT 10.8.0.1:443 HTTP/1.1\r\nHost: 10.8.0.1:443\r\n\r\nhello").unwrap();
+    let expected = b"HTTP/1.1 200 Connection Established\r\n\r\nhello";
+    let mut response = vec![0u8; expected.len()];
+    client.read_exact(&mut response).unwrap();
+    assert_eq!(&response[..], &expected[..]);
+    client.write_all(b" again").unwrap();
+    let mut echoed = [0u8; 6];
+    client.read_exact(&mut echoed).unwrap();
+    assert_eq!(&echoed, b" again");
+    drop(client);
+
+    let connections = peer.wait_connections(|c| c.iter().all(|c| c.closed_by_client));
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].server, Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)), 443)));
+    assert_eq!(connections[0].received, b"hello again");
+    assert!(connections[0].closed_by_client);
+}
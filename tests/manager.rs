@@ -0,0 +1,113 @@
+//Tunnels on the mock backend under a TunnelManager:
+//`cargo test --no-default-features --features mock --test manager`
+#![cfg(feature = "mock")]
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::{ManagerLimits, MockConfig, TunnelConfig, TunnelManager, TunnelState};
+use libopenvpn3::openvpn::manager::{ManagerError, TunnelEvent};
+
+fn mock_tunnel(mock: MockConfig) -> TunnelConfig {
+    TunnelConfig {
+        mock: Some(mock),
+        ..TunnelConfig::new("client\nremote vpn.example.com 1194\n".into())
+    }
+}
+
+fn wait_for(manager: &TunnelManager, id: &str, state: TunnelState) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while manager.state(id) != Some(state.clone()) {
+        assert!(Instant::now() < deadline, "{} is {:?}, not {:?}", id, manager.state(id), state);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn new_tunnels_keep_their_addresses() {
+    let config = TunnelConfig::new(String::new());
+    assert_eq!(config.replacement_ipv4, None);
+    assert_eq!(config.replacement_ipv6, None);
+}
+
+#[test]
+fn starts_and_stops() {
+    let manager = TunnelManager::new(ManagerLimits::default());
+    manager.add("a", mock_tunnel(MockConfig::default())).unwrap();
+    assert!(matches!(manager.add("a", mock_tunnel(MockConfig::default())), Err(ManagerError::AlreadyExists(_))));
+    assert_eq!(manager.state("a"), Some(TunnelState::Stopped));
+    manager.start("a").unwrap();
+    wait_for(&manager, "a", TunnelState::Connected);
+    //Starting a running tunnel does nothing
+    manager.start("a").unwrap();
+    manager.stop("a").unwrap();
+    assert_eq!(manager.state("a"), Some(TunnelState::Stopped));
+    assert!(matches!(manager.start("b"), Err(ManagerError::NotFound(_))));
+    manager.remove("a").unwrap();
+    assert!(manager.ids().is_empty());
+}
+
+#[test]
+fn failures_are_kept() {
+    let manager = TunnelManager::new(ManagerLimits::default());
+    manager.add("refused", mock_tunnel(MockConfig {
+        connect_error: Some("mock: refused".into()),
+        ..MockConfig::default()
+    })).unwrap();
+    assert!(matches!(manager.start("refused"), Err(ManagerError::Connection(_))));
+    assert_eq!(manager.state("refused"), Some(TunnelState::Failed("mock: refused".into())));
+
+    manager.add("auth", mock_tunnel(MockConfig::auth_failure())).unwrap();
+    manager.start("auth").unwrap();
+    //The DISCONNECTED after AUTH_FAILED doesn't hide it
+    wait_for(&manager, "auth", TunnelState::Failed("AUTH_FAILED: mock: wrong username or password".into()));
+    let errors = manager.start_all();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, "refused");
+}
+
+#[test]
+fn limits() {
+    let manager = TunnelManager::new(ManagerLimits {
+        max_tunnels: Some(2),
+        max_running: Some(1),
+    });
+    manager.add("a", mock_tunnel(MockConfig::default())).unwrap();
+    manager.add("b", mock_tunnel(MockConfig::default())).unwrap();
+    assert!(matches!(manager.add("c", mock_tunnel(MockConfig::default())), Err(ManagerError::LimitReached(_))));
+    manager.start("a").unwrap();
+    assert!(matches!(manager.start("b"), Err(ManagerError::LimitReached(_))));
+    manager.stop("a").unwrap();
+    manager.start("b").unwrap();
+
+    manager.shutdown(Duration::from_secs(5));
+    assert!(manager.ids().is_empty());
+    assert!(matches!(manager.add("a", mock_tunnel(MockConfig::default())), Err(ManagerError::ShuttingDown)));
+}
+
+#[test]
+fn events_are_tagged_with_their_tunnel() {
+    let manager = TunnelManager::new(ManagerLimits::default());
+    let events: Arc<Mutex<Vec<TunnelEvent>>> = Arc::new(Mutex::new(Vec::new()));
+    {
+        let events = events.clone();
+        manager.set_on_event(Arc::new(Mutex::new(move |event: TunnelEvent| events.lock().unwrap().push(event))));
+    }
+    manager.add("a", mock_tunnel(MockConfig::default())).unwrap();
+    manager.add("b", mock_tunnel(MockConfig::default())).unwrap();
+    assert!(manager.start_all().is_empty());
+    wait_for(&manager, "a", TunnelState::Connected);
+    wait_for(&manager, "b", TunnelState::Connected);
+    manager.stop("b").unwrap();
+    //The state changes right before the event is handed over
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while events.lock().unwrap().len() < 9 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let events = events.lock().unwrap();
+    let names = |tunnel: &str| events.iter()
+        .filter(|e| e.tunnel == tunnel)
+        .map(|e| e.event.name.clone())
+        .collect::<Vec<String>>();
+    assert_eq!(names("a"), vec!["RESOLVE", "CONNECTING", "ASSIGN_IP", "CONNECTED"]);
+    assert_eq!(names("b"), vec!["RESOLVE", "CONNECTING", "ASSIGN_IP", "CONNECTED", "DISCONNECTED"]);
+}
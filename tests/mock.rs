@@ -0,0 +1,82 @@
+//OVPNClient over the mock backend: `cargo test --no-default-features --features mock --test mock`
+#![cfg(feature = "mock")]
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use libopenvpn3::openvpn::{MockConfig, MockHandle, OVPNClient, OVPNEvent, OnVpnEvent, OnVpnLog, PacketBuilder};
+
+fn client(config: MockConfig) -> (OVPNClient, MockHandle, Arc<Mutex<Vec<OVPNEvent>>>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let on_vpn_event: OnVpnEvent = {
+        let events = events.clone();
+        Arc::new(Mutex::new(move |event: OVPNEvent| events.lock().unwrap().push(event)))
+    };
+    let on_vpn_log: OnVpnLog = Arc::new(Mutex::new(|_: String| {}));
+    let (client, handle) = OVPNClient::mock(config, Some(on_vpn_log), Some(on_vpn_event));
+    (client, handle, events)
+}
+
+#[test]
+fn connects_and_loops_packets_back() {
+    let (mut client, handle, _) = client(MockConfig::default());
+    assert!(client.connect().is_ok());
+    let pushed = client.wait_pushed_config(Duration::from_secs(5)).unwrap();
+    assert_eq!(pushed.dns_servers, vec![IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))]);
+    //CONNECTED comes right after the pushed options
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !handle.is_connected() && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let packet = PacketBuilder::ipv4(Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(1, 1, 1, 1))
+        .udp(40000, 53)
+        .payload(b"hello")
+        .build();
+    assert!(client.send(&packet).is_ok());
+    let mut received = Vec::new();
+    assert!(client.receive(&mut |p| received = p.to_vec()).is_ok());
+    assert_eq!(received, packet);
+    assert_eq!(handle.sent(), vec![packet.clone()]);
+
+    handle.drop_connection();
+    assert!(client.send(&packet).is_err());
+}
+
+#[test]
+fn auth_failure_is_fatal() {
+    let (client, handle, events) = client(MockConfig::auth_failure());
+    assert!(client.connect().is_ok());
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while events.lock().unwrap().len() < 4 && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let events = events.lock().unwrap();
+    assert!(events.iter().any(|e| e.name == "AUTH_FAILED" && e.is_fatal()));
+    assert!(!handle.is_connected());
+    assert!(client.pushed_config().is_none());
+}
+
+#[test]
+fn replacement_ips_follow_the_pushed_address() {
+    let (mut client, handle, _) = client(MockConfig{loopback: false, ..MockConfig::default()});
+    let replacement = Ipv4Addr::new(10, 255, 0, 2);
+    client.set_replacement_ips(Some(replacement), None);
+    assert!(client.connect().is_ok());
+    client.wait_pushed_config(Duration::from_secs(5)).unwrap();
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while !handle.is_connected() && std::time::Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    //10.8.0.2 is what the mock pushes
+    let tunnel = Ipv4Addr::new(10, 8, 0, 2);
+    let outbound = PacketBuilder::ipv4(replacement, Ipv4Addr::new(1, 1, 1, 1)).udp(40000, 53).payload(b"query").build();
+    assert!(client.send(&outbound).is_ok());
+    let expected = PacketBuilder::ipv4(tunnel, Ipv4Addr::new(1, 1, 1, 1)).udp(40000, 53).payload(b"query").build();
+    assert_eq!(handle.sent(), vec![expected]);
+
+    handle.inject(&PacketBuilder::ipv4(Ipv4Addr::new(1, 1, 1, 1), tunnel).udp(53, 40000).payload(b"answer").build());
+    let mut received = Vec::new();
+    assert!(client.receive(&mut |p| received = p.to_vec()).is_ok());
+    assert_eq!(received, PacketBuilder::ipv4(Ipv4Addr::new(1, 1, 1, 1), replacement).udp(53, 40000).payload(b"answer").build());
+}
@@ -0,0 +1,196 @@
+//Address translation over crafted packets, checking that every checksum still verifies
+use std::net::{Ipv4Addr, Ipv6Addr};
+use libopenvpn3::openvpn::Nat;
+
+const LOCAL_IPV4: Ipv4Addr = Ipv4Addr::new(10, 255, 0, 2);
+const TUNNEL_IPV4: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 6);
+const REMOTE_IPV4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const LOCAL_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const TUNNEL_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0x8, 0, 0, 0, 0, 0, 0x1000);
+const REMOTE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0, 0, 0, 0, 0, 0x1111);
+
+fn nat() -> Nat {
+    let mut nat = Nat::new();
+    nat.map_ipv4(LOCAL_IPV4, TUNNEL_IPV4);
+    nat.map_ipv6(LOCAL_IPV6, TUNNEL_IPV6);
+    nat
+}
+
+fn sum(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|w| u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)]) as u32)
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_header_sum(source: &[u8], destination: &[u8], protocol: u8, len: usize) -> u32 {
+    sum(source) + sum(destination) + protocol as u32 + len as u32
+}
+
+fn ipv4(protocol: u8, source: Ipv4Addr, destination: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x45, 0, 0, 0, 0x12, 0x34, 0x40, 0, 64, protocol, 0, 0];
+    packet[2..4].copy_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    let checksum = fold(sum(&packet));
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+fn ipv6(next_header: u8, source: Ipv6Addr, destination: Ipv6Addr, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x60, 0, 0, 0, 0, 0, next_header, 64];
+    packet[4..6].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+//Transport payload with its checksum at `at` filled in, over the pseudo header when `pseudo` is given
+fn with_checksum(mut payload: Vec<u8>, at: usize, pseudo: Option<u32>) -> Vec<u8> {
+    let checksum = fold(sum(&payload) + pseudo.unwrap_or(0));
+    payload[at..at + 2].copy_from_slice(&checksum.to_be_bytes());
+    payload
+}
+
+fn tcp() -> Vec<u8> {
+    let mut segment = vec![0xc3, 0x50, 0, 80, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x02, 0xff, 0xff, 0, 0, 0, 0];
+    segment.extend_from_slice(b"hello");
+    segment
+}
+
+fn udp() -> Vec<u8> {
+    let mut datagram = vec![0xc3, 0x50, 0, 53, 0, 13, 0, 0];
+    datagram.extend_from_slice(b"hello");
+    datagram
+}
+
+fn ipv4_checksum_ok(packet: &[u8]) -> bool {
+    fold(sum(&packet[..20])) == 0
+}
+
+fn transport_checksum_ok(packet: &[u8], protocol: u8) -> bool {
+    let (source, destination, payload) = if packet[0] >> 4 == 4 {
+        (&packet[12..16], &packet[16..20], &packet[20..])
+    } else {
+        (&packet[8..24], &packet[24..40], &packet[40..])
+    };
+    fold(sum(payload) + pseudo_header_sum(source, destination, protocol, payload.len())) == 0
+}
+
+#[test]
+fn ipv4_tcp_round_trip() {
+    let segment = with_checksum(tcp(), 16, Some(pseudo_header_sum(&LOCAL_IPV4.octets(), &REMOTE_IPV4.octets(), 6, 25)));
+    let original = ipv4(6, LOCAL_IPV4, REMOTE_IPV4, &segment);
+    let mut packet = original.clone();
+    assert!(nat().outbound(&mut packet));
+    assert_eq!(&packet[12..16], &TUNNEL_IPV4.octets());
+    assert_eq!(&packet[16..20], &REMOTE_IPV4.octets());
+    assert!(ipv4_checksum_ok(&packet));
+    assert!(transport_checksum_ok(&packet, 6));
+
+    //The reply comes back to the tunnel address
+    let mut reply = packet.clone();
+    reply[12..16].copy_from_slice(&REMOTE_IPV4.octets());
+    reply[16..20].copy_from_slice(&TUNNEL_IPV4.octets());
+    assert!(nat().inbound(&mut reply));
+    assert_eq!(&reply[16..20], &LOCAL_IPV4.octets());
+    assert!(ipv4_checksum_ok(&reply));
+    assert!(transport_checksum_ok(&reply, 6));
+}
+
+#[test]
+fn ipv4_udp_checksums() {
+    let datagram = with_checksum(udp(), 6, Some(pseudo_header_sum(&LOCAL_IPV4.octets(), &REMOTE_IPV4.octets(), 17, 13)));
+    let mut packet = ipv4(17, LOCAL_IPV4, REMOTE_IPV4, &datagram);
+    assert!(nat().outbound(&mut packet));
+    assert!(ipv4_checksum_ok(&packet));
+    assert!(transport_checksum_ok(&packet, 17));
+
+    //No checksum stays no checksum
+    let mut packet = ipv4(17, LOCAL_IPV4, REMOTE_IPV4, &udp());
+    assert!(nat().outbound(&mut packet));
+    assert_eq!(&packet[26..28], &[0, 0]);
+    assert!(ipv4_checksum_ok(&packet));
+}
+
+#[test]
+fn ipv4_icmp_error_quotes_are_rewritten() {
+    //Port unreachable about a datagram we sent from the tunnel address
+    let quoted = ipv4(17, TUNNEL_IPV4, REMOTE_IPV4, &udp()[..8]);
+    let mut icmp = vec![3, 3, 0, 0, 0, 0, 0, 0];
+    icmp.extend_from_slice(&quoted);
+    let icmp = with_checksum(icmp, 2, None);
+    let mut packet = ipv4(1, REMOTE_IPV4, TUNNEL_IPV4, &icmp);
+    assert!(nat().inbound(&mut packet));
+    assert_eq!(&packet[16..20], &LOCAL_IPV4.octets());
+    assert_eq!(&packet[28 + 12..28 + 16], &LOCAL_IPV4.octets());
+    assert!(ipv4_checksum_ok(&packet));
+    assert!(ipv4_checksum_ok(&packet[28..]));
+    assert_eq!(fold(sum(&packet[20..])), 0);
+}
+
+#[test]
+fn ipv4_icmp_echo() {
+    let icmp = with_checksum(vec![8, 0, 0, 0, 0, 1, 0, 1, b'p', b'i', b'n', b'g'], 2, None);
+    let mut packet = ipv4(1, LOCAL_IPV4, REMOTE_IPV4, &icmp);
+    assert!(nat().outbound(&mut packet));
+    assert!(ipv4_checksum_ok(&packet));
+    //ICMPv4 has no pseudo header, so the payload is unchanged
+    assert_eq!(&packet[20..], &icmp[..]);
+}
+
+#[test]
+fn ipv6_tcp_and_udp() {
+    let segment = with_checksum(tcp(), 16, Some(pseudo_header_sum(&LOCAL_IPV6.octets(), &REMOTE_IPV6.octets(), 6, 25)));
+    let mut packet = ipv6(6, LOCAL_IPV6, REMOTE_IPV6, &segment);
+    assert!(nat().outbound(&mut packet));
+    assert_eq!(&packet[8..24], &TUNNEL_IPV6.octets());
+    assert!(transport_checksum_ok(&packet, 6));
+
+    let datagram = with_checksum(udp(), 6, Some(pseudo_header_sum(&REMOTE_IPV6.octets(), &TUNNEL_IPV6.octets(), 17, 13)));
+    let mut packet = ipv6(17, REMOTE_IPV6, TUNNEL_IPV6, &datagram);
+    assert!(nat().inbound(&mut packet));
+    assert_eq!(&packet[24..40], &LOCAL_IPV6.octets());
+    assert!(transport_checksum_ok(&packet, 17));
+}
+
+#[test]
+fn ipv6_icmp_echo() {
+    let echo = vec![128, 0, 0, 0, 0, 1, 0, 1, b'p', b'i', b'n', b'g'];
+    let echo = with_checksum(echo, 2, Some(pseudo_header_sum(&LOCAL_IPV6.octets(), &REMOTE_IPV6.octets(), 58, 12)));
+    let mut packet = ipv6(58, LOCAL_IPV6, REMOTE_IPV6, &echo);
+    assert!(nat().outbound(&mut packet));
+    assert!(transport_checksum_ok(&packet, 58));
+}
+
+#[test]
+fn other_addresses_and_disabled_nat_are_left_alone() {
+    let other = Ipv4Addr::new(192, 168, 1, 10);
+    let original = ipv4(17, other, REMOTE_IPV4, &udp());
+    let mut packet = original.clone();
+    assert!(!nat().outbound(&mut packet));
+    assert_eq!(packet, original);
+
+    let mut nat = nat();
+    nat.set_enabled(false);
+    let original = ipv4(17, LOCAL_IPV4, REMOTE_IPV4, &udp());
+    let mut packet = original.clone();
+    assert!(!nat.outbound(&mut packet));
+    assert_eq!(packet, original);
+
+    //No IPv6 mapping
+    let mut nat = Nat::new();
+    nat.map_ipv4(LOCAL_IPV4, TUNNEL_IPV4);
+    let original = ipv6(17, LOCAL_IPV6, REMOTE_IPV6, &udp());
+    let mut packet = original.clone();
+    assert!(!nat.outbound(&mut packet));
+    assert_eq!(packet, original);
+}
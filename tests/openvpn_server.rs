@@ -0,0 +1,71 @@
+//Handshakes and traffic against a local `openvpn` server, see tests/common.
+//Run as root with `sudo -E cargo test --features pki --test openvpn_server -- --ignored`
+#![cfg(all(feature = "native", feature = "pki"))]
+mod common;
+
+use std::net::TcpListener;
+use common::{Client, Proto, Server, ServerConfig, TlsWrap};
+
+//Connects, pings the server and sends it some data over TCP
+fn exchange_traffic(config: ServerConfig, username: Option<&str>, password: Option<&str>) {
+    let server = Server::start(config);
+    let mut client = Client::connect(&server, username, password);
+    assert!(client.is_connected(), "events: {:?}\nserver log:\n{}", client.events(), server.log());
+    assert!(client.ping(server.tunnel_ip()), "no echo reply");
+    let listener = TcpListener::bind((server.tunnel_ip(), 0)).unwrap();
+    assert_eq!(client.tcp_exchange(&listener, b"hello through the tunnel"), b"hello through the tunnel");
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn udp() {
+    exchange_traffic(ServerConfig::default(), None, None);
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn tcp() {
+    exchange_traffic(ServerConfig {
+        proto: Proto::Tcp,
+        ..ServerConfig::default()
+    }, None, None);
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn tls_auth() {
+    exchange_traffic(ServerConfig {
+        tls_wrap: TlsWrap::Auth,
+        ..ServerConfig::default()
+    }, None, None);
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn tls_crypt() {
+    exchange_traffic(ServerConfig {
+        tls_wrap: TlsWrap::Crypt,
+        ..ServerConfig::default()
+    }, None, None);
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn username_and_password() {
+    exchange_traffic(ServerConfig {
+        credentials: Some(("alice".into(), "secret".into())),
+        ..ServerConfig::default()
+    }, Some("alice"), Some("secret"));
+}
+
+#[test]
+#[ignore = "needs root, /dev/net/tun and openvpn, run with --ignored"]
+fn wrong_password_is_rejected() {
+    let server = Server::start(ServerConfig {
+        credentials: Some(("alice".into(), "secret".into())),
+        ..ServerConfig::default()
+    });
+    let client = Client::connect(&server, Some("alice"), Some("wrong"));
+    assert!(!client.is_connected());
+    assert!(client.events().iter().any(|e| e.name == "AUTH_FAILED"), "events: {:?}", client.events());
+}
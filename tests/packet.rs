@@ -0,0 +1,102 @@
+//Building packets and parsing them back
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use libopenvpn3::openvpn::packet::{IpHeader, TcpHeader, Transport, PROTOCOL_UDP};
+use libopenvpn3::openvpn::{Packet, PacketBuilder, PacketError};
+
+const LOCAL_IPV4: Ipv4Addr = Ipv4Addr::new(10, 255, 0, 2);
+const REMOTE_IPV4: Ipv4Addr = Ipv4Addr::new(1, 1, 1, 1);
+const LOCAL_IPV6: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+const REMOTE_IPV6: Ipv6Addr = Ipv6Addr::new(0x2606, 0x4700, 0, 0, 0, 0, 0, 0x1111);
+
+#[test]
+fn tcp_round_trip() {
+    let data = PacketBuilder::ipv4(LOCAL_IPV4, REMOTE_IPV4)
+        .tcp(40000, 443, 7, 0, TcpHeader::SYN)
+        .payload(b"hello")
+        .build();
+    let packet = Packet::parse(&data).unwrap();
+    assert_eq!(packet.source(), IpAddr::V4(LOCAL_IPV4));
+    assert_eq!(packet.destination(), IpAddr::V4(REMOTE_IPV4));
+    assert_eq!(packet.source_port(), Some(40000));
+    assert_eq!(packet.destination_port(), Some(443));
+    assert_eq!(packet.payload(), b"hello");
+    match packet.transport() {
+        Some(Transport::Tcp(tcp)) => {
+            assert_eq!(tcp.sequence, 7);
+            assert_eq!(tcp.flags, TcpHeader::SYN);
+        },
+        other => panic!("expected TCP, got {:?}", other)
+    }
+    assert_eq!(packet.verify_checksums(), Ok(()));
+}
+
+#[test]
+fn udp_and_icmpv6_over_ipv6() {
+    let data = PacketBuilder::ipv6(LOCAL_IPV6, REMOTE_IPV6)
+        .udp(5353, 53)
+        .payload(b"query")
+        .build();
+    let packet = Packet::parse(&data).unwrap();
+    assert!(packet.is_ipv6());
+    assert_eq!(packet.protocol(), PROTOCOL_UDP);
+    assert_eq!(packet.payload(), b"query");
+    assert_eq!(packet.verify_checksums(), Ok(()));
+
+    let data = PacketBuilder::ipv6(LOCAL_IPV6, REMOTE_IPV6)
+        .icmp(128, 0, [0, 1, 0, 1])
+        .build();
+    let packet = Packet::parse(&data).unwrap();
+    assert!(matches!(packet.transport(), Some(Transport::Icmpv6(icmp)) if icmp.icmp_type == 128));
+    assert_eq!(packet.verify_checksums(), Ok(()));
+}
+
+#[test]
+fn ipv6_extension_headers_are_walked() {
+    let data = PacketBuilder::ipv6(LOCAL_IPV6, REMOTE_IPV6)
+        .udp(5353, 53)
+        .payload(b"query")
+        .build();
+    //Hop-by-hop options with a PadN, then an authentication header with a 12-byte ICV
+    let hop_by_hop = [51, 0, 1, 4, 0, 0, 0, 0];
+    let mut authentication = vec![PROTOCOL_UDP, 4, 0, 0];
+    authentication.extend_from_slice(&[0x11; 20]);
+    let mut extended = data[..40].to_vec();
+    extended[6] = 0;
+    let payload_len = (data.len() - 40 + hop_by_hop.len() + authentication.len()) as u16;
+    extended[4..6].copy_from_slice(&payload_len.to_be_bytes());
+    extended.extend_from_slice(&hop_by_hop);
+    extended.extend_from_slice(&authentication);
+    extended.extend_from_slice(&data[40..]);
+    let packet = Packet::parse(&extended).unwrap();
+    assert_eq!(packet.protocol(), PROTOCOL_UDP);
+    assert_eq!(packet.transport_offset(), Some(40 + 8 + 24));
+    assert_eq!(packet.destination_port(), Some(53));
+    assert_eq!(packet.payload(), b"query");
+
+    //An authentication header running past the packet
+    let mut truncated = extended[..40 + 8 + 2].to_vec();
+    truncated[4..6].copy_from_slice(&10u16.to_be_bytes());
+    assert_eq!(Packet::parse(&truncated).err(), Some(PacketError::Truncated));
+}
+
+#[test]
+fn trailing_padding_is_ignored() {
+    let mut data = PacketBuilder::ipv4(LOCAL_IPV4, REMOTE_IPV4).ttl(3).udp(1, 2).build();
+    let len = data.len();
+    data.extend_from_slice(&[0; 6]);
+    let packet = Packet::parse(&data).unwrap();
+    assert_eq!(packet.len(), len);
+    assert!(matches!(packet.ip(), IpHeader::V4(ip) if ip.ttl == 3));
+}
+
+#[test]
+fn malformed_packets_are_rejected() {
+    let data = PacketBuilder::ipv4(LOCAL_IPV4, REMOTE_IPV4).tcp(1, 2, 0, 0, TcpHeader::ACK).build();
+    assert_eq!(Packet::parse(&data[..30]).err(), Some(PacketError::Truncated));
+    assert_eq!(Packet::parse(&[]).err(), Some(PacketError::Truncated));
+    assert_eq!(Packet::parse(&[0x50; 40]).err(), Some(PacketError::UnknownVersion(5)));
+
+    let mut corrupted = data.clone();
+    corrupted[data.len() - 1] ^= 0xff;
+    assert!(matches!(Packet::parse(&corrupted).unwrap().verify_checksums(), Err(PacketError::BadChecksum(_))));
+}
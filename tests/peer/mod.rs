@@ -0,0 +1,223 @@
+//Fake hosts on the far side of the tunnel, for the tests over a stack
+//
+//A stack over a connected mock backend, and a thread that answers what the stack sends at
+//the packet level, the way tests/dns.rs does for DNS: every address on the tunnel accepts
+//TCP connections and hands their bytes to a service, and echoes UDP datagrams back.
+#![allow(dead_code)]
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::{MockConfig, MockHandle, OVPNClient, Packet, PacketBuilder};
+use libopenvpn3::openvpn::packet::{TcpHeader, Transport};
+use libopenvpn3::openvpn::stack::{Stack, StackConfig};
+
+pub const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+const PEER_SEQUENCE: u32 = 7000;
+//Well below the MSS smoltcp announces
+const SEGMENT_SIZE: usize = 1000;
+
+///Given the bytes received on a connection and not consumed yet, what to send back and
+///whether to close the connection afterwards
+pub type Service = dyn Fn(&mut Vec<u8>) -> (Vec<u8>, bool) + Send + Sync;
+
+///Sends back everything it receives
+pub fn echo() -> Arc<Service> {
+    Arc::new(|received: &mut Vec<u8>| (std::mem::take(received), false))
+}
+
+///A UDP datagram received: the client port, where it was sent and its payload
+pub type Datagram = (u16, SocketAddr, Vec<u8>);
+
+///A TCP connection as the peer saw it
+#[derive(Debug, Clone, Default)]
+pub struct Connection {
+    pub client_port: u16,
+    pub server: Option<SocketAddr>,
+    ///Everything the client sent
+    pub received: Vec<u8>,
+    ///The client sent a FIN
+    pub closed_by_client: bool,
+}
+
+struct TcpState {
+    next_sequence: u32,
+    sent: u32,
+    unconsumed: Vec<u8>,
+    closed: bool,
+}
+
+#[derive(Clone)]
+pub struct Peer {
+    pub handle: MockHandle,
+    connections: Arc<Mutex<Vec<Connection>>>,
+    datagrams: Arc<Mutex<Vec<Datagram>>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Peer {
+    ///TCP connections in the order they were opened
+    pub fn connections(&self) -> Vec<Connection> {
+        self.connections.lock().unwrap().clone()
+    }
+
+    pub fn datagrams(&self) -> Vec<Datagram> {
+        self.datagrams.lock().unwrap().clone()
+    }
+
+    ///Waits until `f` is true of the connections
+    pub fn wait_connections<F: Fn(&[Connection]) -> bool>(&self, f: F) -> Vec<Connection> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let connections = self.connections();
+            if f(&connections) || Instant::now() >= deadline {
+                return connections;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        //The last one is the thread's own
+        if Arc::strong_count(&self.stop) == 2 {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+///A stack over a connected mock, and the peer answering it with `service`
+pub fn stack(service: Arc<Service>) -> (Stack, Peer) {
+    stack_with(service, |config| config)
+}
+
+///Like `stack`, with the configuration from the pushed one changed by `f`
+pub fn stack_with<F: FnOnce(StackConfig) -> StackConfig>(service: Arc<Service>, f: F) -> (Stack, Peer) {
+    let (client, handle) = OVPNClient::mock(MockConfig {
+        loopback: false,
+        ..MockConfig::default()
+    }, None, None);
+    assert!(client.connect().is_ok());
+    let pushed = client.wait_pushed_config(Duration::from_secs(5)).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !handle.is_connected() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let stack = Stack::new(client, f(StackConfig::from_pushed(&pushed))).unwrap();
+    let peer = Peer {
+        handle: handle,
+        connections: Arc::new(Mutex::new(Vec::new())),
+        datagrams: Arc::new(Mutex::new(Vec::new())),
+        stop: Arc::new(AtomicBool::new(false)),
+    };
+    let thread_peer = peer.clone();
+    std::thread::spawn(move || run(thread_peer, service));
+    (stack, peer)
+}
+
+fn run(peer: Peer, service: Arc<Service>) {
+    let mut states: HashMap<(IpAddr, u16, u16), TcpState> = HashMap::new();
+    let mut seen = 0;
+    while !peer.stop.load(Ordering::Relaxed) {
+        let sent = peer.handle.sent();
+        for packet in sent[seen..].iter() {
+            handle_packet(&peer, &service, &mut states, packet);
+        }
+        seen = sent.len();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+fn handle_packet(peer: &Peer, service: &Arc<Service>, states: &mut HashMap<(IpAddr, u16, u16), TcpState>, packet: &[u8]) {
+    let packet = match Packet::parse(packet) {
+        Ok(packet) => packet,
+        Err(_) => return
+    };
+    let (client, server) = match (packet.source(), packet.destination()) {
+        (IpAddr::V4(client), IpAddr::V4(server)) => (client, server),
+        _ => return
+    };
+    let reply = || PacketBuilder::ipv4(server, client);
+    match packet.transport() {
+        Some(Transport::Udp(udp)) => {
+            let to = SocketAddr::new(IpAddr::V4(server), udp.destination_port);
+            peer.datagrams.lock().unwrap().push((udp.source_port, to, packet.payload().to_vec()));
+            peer.handle.inject(&reply().udp(udp.destination_port, udp.source_port).payload(packet.payload()).build());
+        },
+        Some(Transport::Tcp(tcp)) => {
+            let key = (IpAddr::V4(server), tcp.destination_port, tcp.source_port);
+            let connection_index = |connections: &[Connection]| connections.iter()
+                .rposition(|c| c.server == Some(SocketAddr::new(key.0, key.1)) && c.client_port == key.2);
+            if tcp.flags & TcpHeader::SYN != 0 {
+                states.insert(key, TcpState {
+                    next_sequence: tcp.sequence.wrapping_add(1),
+                    sent: 0,
+                    unconsumed: Vec::new(),
+                    closed: false,
+                });
+                peer.connections.lock().unwrap().push(Connection {
+                    client_port: tcp.source_port,
+                    server: Some(SocketAddr::new(key.0, key.1)),
+                    ..Connection::default()
+                });
+                peer.handle.inject(&reply().tcp(key.1, key.2, PEER_SEQUENCE, tcp.sequence.wrapping_add(1), TcpHeader::SYN | TcpHeader::ACK).build());
+                return;
+            }
+            let state = match states.get_mut(&key) {
+                Some(state) => state,
+                None => return
+            };
+            let mut segments: Vec<(u8, Vec<u8>)> = Vec::new();
+            let payload = packet.payload();
+            //Retransmissions of what we already have only get acknowledged again
+            let fresh = tcp.sequence == state.next_sequence;
+            if !payload.is_empty() {
+                if fresh {
+                    state.next_sequence = state.next_sequence.wrapping_add(payload.len() as u32);
+                    state.unconsumed.extend_from_slice(payload);
+                    {
+                        let mut connections = peer.connections.lock().unwrap();
+                        if let Some(i) = connection_index(&connections) {
+                            connections[i].received.extend_from_slice(payload);
+                        }
+                    }
+                    if !state.closed {
+                        let (response, close) = service(&mut state.unconsumed);
+                        for chunk in response.chunks(SEGMENT_SIZE) {
+                            segments.push((TcpHeader::PSH | TcpHeader::ACK, chunk.to_vec()));
+                        }
+                        if close {
+                            state.closed = true;
+                            segments.push((TcpHeader::FIN | TcpHeader::ACK, Vec::new()));
+                        }
+                    }
+                }
+                if segments.is_empty() {
+                    segments.push((TcpHeader::ACK, Vec::new()));
+                }
+            }
+            if tcp.flags & TcpHeader::FIN != 0 && tcp.sequence.wrapping_add(payload.len() as u32) == state.next_sequence {
+                state.next_sequence = state.next_sequence.wrapping_add(1);
+                {
+                    let mut connections = peer.connections.lock().unwrap();
+                    if let Some(i) = connection_index(&connections) {
+                        connections[i].closed_by_client = true;
+                    }
+                }
+                segments.push((TcpHeader::ACK, Vec::new()));
+                if !state.closed {
+                    state.closed = true;
+                    segments.push((TcpHeader::FIN | TcpHeader::ACK, Vec::new()));
+                }
+            }
+            for (flags, payload) in segments {
+                let sequence = PEER_SEQUENCE.wrapping_add(1).wrapping_add(state.sent);
+                state.sent += payload.len() as u32 + (flags & TcpHeader::FIN != 0) as u32;
+                peer.handle.inject(&reply().tcp(key.1, key.2, sequence, state.next_sequence, flags).payload(&payload).build());
+            }
+        },
+        _ => {}
+    }
+}
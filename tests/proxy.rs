@@ -0,0 +1,77 @@
+//Proxy configs applied to profiles: `cargo test --test proxy`
+use libopenvpn3::openvpn::{OVPNEvent, OVPNEventKind, ProxyConfig, ProxyServer};
+
+const PROFILE: &str = "client\nremote vpn.example.com 1194\nhttp-proxy old.example.com 3128 creds.txt\n";
+
+#[test]
+fn server_replaces_the_profiles_proxy() {
+    let proxy = ProxyConfig::new(ProxyServer::http("proxy.corp", 8080));
+    assert_eq!(proxy.apply(PROFILE).unwrap(), "client\nremote vpn.example.com 1194\nhttp-proxy proxy.corp 8080 auto-nct\n");
+    let proxy = ProxyConfig::new(ProxyServer::http("::1", 3128));
+    assert_eq!(proxy.apply(PROFILE).unwrap(), "client\nremote vpn.example.com 1194\nhttp-proxy ::1 3128 auto-nct\n");
+}
+
+#[test]
+fn connection_blocks_lose_their_proxies() {
+    let profile = "client\n<connection>\nremote a.example.com 1194\nhttp-proxy a.proxy 3128\nhttp-proxy-option AGENT x\n</connection>\n\
+        <connection>\nremote b.example.com 1194\n</connection>\n";
+    //Replaced by the one given, for every block
+    assert_eq!(ProxyConfig::new(ProxyServer::http("proxy.corp", 8080)).apply(profile).unwrap(),
+        "client\n<connection>\nremote a.example.com 1194\n</connection>\n<connection>\nremote b.example.com 1194\n</connection>\n\
+        http-proxy proxy.corp 8080 auto-nct\n");
+    //Kept when the blocks agree, a block without one going through the profile's
+    let agreeing = "client\nhttp-proxy a.proxy 3128\n<connection>\nremote a.example.com 1194\nhttp-proxy a.proxy 3128\n</connection>\n\
+        <connection>\nremote b.example.com 1194\n</connection>\n";
+    assert_eq!(ProxyConfig::default().apply(agreeing).unwrap(),
+        "client\n<connection>\nremote a.example.com 1194\n</connection>\n<connection>\nremote b.example.com 1194\n</connection>\n\
+        http-proxy a.proxy 3128 auto-nct\n");
+    //One block going direct and one through a proxy can't be kept
+    let e = ProxyConfig::default().with_credentials("alice", "hunter2").apply(profile).unwrap_err();
+    assert!(e.contains("same proxy"), "{}", e);
+}
+
+#[test]
+fn socks_is_refused() {
+    let profile = "client\nremote vpn.example.com 1194\nsocks-proxy 127.0.0.1 1080\n";
+    assert!(ProxyConfig::default().apply(profile).unwrap_err().contains("SOCKS"));
+    //Even when another proxy would replace it, rather than leave a profile the user expects to use SOCKS
+    assert!(ProxyConfig::new(ProxyServer::http("proxy.corp", 8080)).apply(profile).is_err());
+    let in_block = "client\n<connection>\nremote vpn.example.com 1194\nsocks-proxy 127.0.0.1 1080\n</connection>\n";
+    assert!(ProxyConfig::new(ProxyServer::http("proxy.corp", 8080)).apply(in_block).is_err());
+    for url in ["socks://127.0.0.1:1080", "socks5://[::1]:1080"].iter() {
+        assert!(url.parse::<ProxyServer>().unwrap_err().contains("SOCKS"), "{}", url);
+    }
+}
+
+#[test]
+fn credentials_go_to_the_profiles_proxy() {
+    let profile = format!("{}<http-proxy-user-pass>\nold\nsecret\n</http-proxy-user-pass>\n", PROFILE);
+    let proxy = ProxyConfig {
+        allow_cleartext_auth: true,
+        ..ProxyConfig::default()
+    }.with_credentials("alice", "hunter2");
+    assert_eq!(proxy.apply(&profile).unwrap(), "client\nremote vpn.example.com 1194\nhttp-proxy old.example.com 3128 auto\n\
+        <http-proxy-user-pass>\nalice\nhunter2\n</http-proxy-user-pass>\n");
+}
+
+#[test]
+fn rejects_what_it_cant_apply() {
+    let credentials = ProxyConfig::default().with_credentials("alice", "hunter2");
+    assert!(credentials.apply("client\nremote vpn.example.com 1194\n").is_err());
+    assert!(ProxyConfig::default().apply("client\nremote vpn.example.com 1194\nhttp-proxy proxy.corp\n").is_err());
+    assert!(ProxyConfig::new(ProxyServer::http("proxy.corp", 8080)).with_credentials("alice\nremote evil 1", "x").apply(PROFILE).is_err());
+}
+
+#[test]
+fn parses_proxy_urls() {
+    assert_eq!("http://proxy.corp:8080".parse::<ProxyServer>(), Ok(ProxyServer::http("proxy.corp", 8080)));
+    assert_eq!("http://[::1]:3128".parse::<ProxyServer>(), Ok(ProxyServer::http("::1", 3128)));
+    assert_eq!(ProxyServer::http("::1", 3128).to_string(), "http://[::1]:3128");
+    assert!("proxy.corp:8080".parse::<ProxyServer>().is_err());
+    assert!("http://proxy.corp".parse::<ProxyServer>().is_err());
+}
+
+#[test]
+fn need_creds_event_has_its_kind() {
+    assert_eq!(OVPNEvent::new("PROXY_NEED_CREDS", "", true, true).kind(), OVPNEventKind::ProxyNeedCreds);
+}
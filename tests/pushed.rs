@@ -0,0 +1,36 @@
+//Pushed options from PUSH_REPLY and the CONNECTED event: `cargo test --test pushed`
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use libopenvpn3::openvpn::{IpPrefix, PushedConfig};
+
+const CONNECTED: &str = "user@vpn.example.com:1194 (192.0.2.1) via UDPv4 on tun/10.8.0.2/fd00::2 gw=[10.8.0.1/fd00::1]";
+
+#[test]
+fn connected_info_fills_everything() {
+    let mut config = PushedConfig::default();
+    config.apply_connected_info(CONNECTED);
+    assert_eq!(config.ipv4, Some(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)), 32)));
+    assert_eq!(config.ipv6, Some(IpPrefix::new(IpAddr::V6("fd00::2".parse().unwrap()), 128)));
+    assert_eq!(config.gateway_ipv4, Some(Ipv4Addr::new(10, 8, 0, 1)));
+    assert_eq!(config.gateway_ipv6, Some("fd00::1".parse::<Ipv6Addr>().unwrap()));
+}
+
+#[test]
+fn connected_info_fills_ipv6_next_to_a_pushed_ipv4() {
+    let mut config = PushedConfig::parse("PUSH_REPLY,route-gateway 10.8.0.1,topology subnet,ifconfig 10.8.0.2 255.255.255.0").unwrap();
+    config.apply_connected_info(CONNECTED);
+    //The pushed netmask stays, the IPv6 address and gateway come from the event
+    assert_eq!(config.ipv4, Some(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)), 24)));
+    assert_eq!(config.ipv6, Some(IpPrefix::new(IpAddr::V6("fd00::2".parse().unwrap()), 128)));
+    assert_eq!(config.gateway_ipv4, Some(Ipv4Addr::new(10, 8, 0, 1)));
+    assert_eq!(config.gateway_ipv6, Some("fd00::1".parse::<Ipv6Addr>().unwrap()));
+}
+
+#[test]
+fn connected_info_without_ipv6() {
+    let mut config = PushedConfig::default();
+    config.apply_connected_info("user@vpn.example.com:1194 (192.0.2.1) via TCPv4 on tun/10.8.0.6/ gw=[10.8.0.5/]");
+    assert_eq!(config.ipv4, Some(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 6)), 32)));
+    assert_eq!(config.ipv6, None);
+    assert_eq!(config.gateway_ipv4, Some(Ipv4Addr::new(10, 8, 0, 5)));
+    assert_eq!(config.gateway_ipv6, None);
+}
@@ -0,0 +1,155 @@
+//Rules and strategies picking a tunnel: `cargo test --no-default-features --features mock --test router`
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use libopenvpn3::openvpn::{IpPrefix, RouteMatch, RouteRule, Strategy, TunnelRouter};
+use libopenvpn3::openvpn::router::TunnelStatus;
+
+//Tunnels by name, with whether they're up and their load
+#[derive(Default)]
+struct Tunnels(HashMap<String, (bool, u64)>);
+
+impl Tunnels {
+    fn with(mut self, tunnel: &str, up: bool, load: u64) -> Tunnels {
+        self.0.insert(tunnel.to_owned(), (up, load));
+        self
+    }
+}
+
+impl TunnelStatus for Tunnels {
+    fn is_up(&self, tunnel: &str) -> bool {
+        self.0.get(tunnel).map(|t| t.0).unwrap_or(false)
+    }
+
+    fn load(&self, tunnel: &str) -> u64 {
+        self.0.get(tunnel).map(|t| t.1).unwrap_or(u64::MAX)
+    }
+}
+
+fn rule(matcher: RouteMatch, tunnels: &[&str], strategy: Strategy) -> RouteRule {
+    RouteRule::new(matcher, tunnels.iter().map(|t| t.to_string()).collect(), strategy)
+}
+
+fn addr(ip: [u8; 4], port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)
+}
+
+fn prefix(ip: &str, len: u8) -> RouteMatch {
+    RouteMatch::Prefix(IpPrefix::new(ip.parse().unwrap(), len))
+}
+
+//An IPv4 UDP packet, checksums left out since routing doesn't look at them
+fn udp_packet(source: Ipv4Addr, destination: Ipv4Addr, port: u16, payload: &[u8]) -> Vec<u8> {
+    let length = 28 + payload.len() as u16;
+    let mut packet = vec![0x45, 0];
+    packet.extend_from_slice(&length.to_be_bytes());
+    packet.extend_from_slice(&[0, 0, 0, 0, 64, 17, 0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    packet.extend_from_slice(&40000u16.to_be_bytes());
+    packet.extend_from_slice(&port.to_be_bytes());
+    packet.extend_from_slice(&(length - 20).to_be_bytes());
+    packet.extend_from_slice(&[0, 0]);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+#[test]
+fn first_matching_rule_wins() {
+    let tunnels = Tunnels::default().with("office", true, 0).with("home", true, 0).with("web", true, 0);
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(prefix("10.0.0.0", 8), &["office"], Strategy::Failover));
+    router.add_rule(rule(RouteMatch::Domain("example.org.".into()), &["home"], Strategy::Failover));
+    router.add_rule(rule(RouteMatch::Ports(80, 443), &["web"], Strategy::Failover));
+    let cases: &[(SocketAddr, Option<&str>)] = &[
+        (addr([10, 1, 2, 3], 443), Some("office")),
+        (addr([192, 0, 2, 1], 80), Some("web")),
+        (addr([192, 0, 2, 1], 443), Some("web")),
+        (addr([192, 0, 2, 1], 444), None),
+        (addr([11, 0, 0, 1], 22), None),
+    ];
+    for (destination, tunnel) in cases {
+        assert_eq!(router.route_addr(&tunnels, destination).as_deref(), *tunnel, "{}", destination);
+    }
+    //Domains match themselves and their subdomains, whatever the case or trailing dot
+    assert_eq!(router.route_domain(&tunnels, "example.org", 22).as_deref(), Some("home"));
+    assert_eq!(router.route_domain(&tunnels, "WWW.Example.Org.", 22).as_deref(), Some("home"));
+    assert_eq!(router.route_domain(&tunnels, "notexample.org", 22).as_deref(), None);
+    //A name never matches an address prefix, the port rule decides
+    assert_eq!(router.route_domain(&tunnels, "intranet", 443).as_deref(), Some("web"));
+}
+
+#[test]
+fn down_tunnels_fall_through_to_the_next_rule() {
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(prefix("10.0.0.0", 8), &["office", "office-backup"], Strategy::Failover));
+    router.add_rule(rule(RouteMatch::Any, &["home"], Strategy::Failover));
+    let destination = addr([10, 0, 0, 1], 22);
+    let tunnels = Tunnels::default().with("office", true, 0).with("office-backup", true, 0).with("home", true, 0);
+    assert_eq!(router.route_addr(&tunnels, &destination).as_deref(), Some("office"));
+    let tunnels = tunnels.with("office", false, 0);
+    assert_eq!(router.route_addr(&tunnels, &destination).as_deref(), Some("office-backup"));
+    let tunnels = tunnels.with("office-backup", false, 0);
+    assert_eq!(router.route_addr(&tunnels, &destination).as_deref(), Some("home"));
+    let tunnels = tunnels.with("home", false, 0);
+    assert_eq!(router.route_addr(&tunnels, &destination), None);
+}
+
+#[test]
+fn round_robin_takes_turns_among_tunnels_up() {
+    let tunnels = Tunnels::default().with("a", true, 0).with("b", false, 0).with("c", true, 0);
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(RouteMatch::Any, &["a", "b", "c"], Strategy::RoundRobin));
+    let picked: Vec<String> = (0..4).map(|_| router.route_domain(&tunnels, "example.com", 443).unwrap()).collect();
+    assert_eq!(picked, vec!["a", "c", "a", "c"]);
+}
+
+#[test]
+fn least_loaded_skips_tunnels_down() {
+    let tunnels = Tunnels::default().with("a", true, 300).with("b", false, 0).with("c", true, 200);
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(RouteMatch::Any, &["a", "b", "c"], Strategy::LeastLoaded));
+    assert_eq!(router.route_domain(&tunnels, "example.com", 443).as_deref(), Some("c"));
+    let tunnels = tunnels.with("a", true, 100);
+    assert_eq!(router.route_domain(&tunnels, "example.com", 443).as_deref(), Some("a"));
+}
+
+#[test]
+fn resolutions_apply_domain_rules_to_packets() {
+    let tunnels = Tunnels::default().with("streaming", true, 0).with("default", true, 0);
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(RouteMatch::Domain("video.example".into()), &["streaming"], Strategy::Failover));
+    router.add_rule(rule(RouteMatch::Any, &["default"], Strategy::Failover));
+    let packet = udp_packet(Ipv4Addr::new(10, 8, 0, 2), Ipv4Addr::new(203, 0, 113, 7), 443, b"quic");
+    assert_eq!(router.route_packet(&tunnels, &packet).as_deref(), Some("default"));
+    router.record_resolution("cdn.video.example", &["203.0.113.7".parse().unwrap(), "2001:db8::7".parse().unwrap()]);
+    assert_eq!(router.route_packet(&tunnels, &packet).as_deref(), Some("streaming"));
+    assert_eq!(router.route_addr(&tunnels, &"[2001:db8::7]:443".parse().unwrap()).as_deref(), Some("streaming"));
+    //Not an IP packet
+    assert_eq!(router.route_packet(&tunnels, &[0x45, 0]), None);
+}
+
+#[cfg(feature = "mock")]
+#[test]
+fn manager_tunnels_are_up_once_connected() {
+    use std::time::{Duration, Instant};
+    use libopenvpn3::openvpn::{ManagerLimits, MockConfig, TunnelConfig, TunnelManager, TunnelState};
+    let manager = TunnelManager::new(ManagerLimits::default());
+    for id in ["a", "b"].iter() {
+        manager.add(id, TunnelConfig {
+            mock: Some(MockConfig::default()),
+            ..TunnelConfig::new(String::new())
+        }).unwrap();
+    }
+    let mut router = TunnelRouter::new();
+    router.add_rule(rule(RouteMatch::Any, &["a", "b"], Strategy::Failover));
+    assert_eq!(router.route_domain(&manager, "example.com", 443), None);
+    manager.start("b").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while manager.state("b") != Some(TunnelState::Connected) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(router.route_domain(&manager, "example.com", 443).as_deref(), Some("b"));
+    //Load is the traffic of each tunnel, a tunnel nobody added is never the least loaded
+    assert_eq!(manager.load("b"), 0);
+    assert_eq!(manager.load("c"), u64::MAX);
+}
@@ -0,0 +1,96 @@
+//Split tunneling decisions: `cargo test --test routes`
+use std::net::IpAddr;
+use libopenvpn3::openvpn::{IpPrefix, PushedConfig, RouteTable};
+
+const PUSH_REPLY: &str = "PUSH_REPLY,route-gateway 10.8.0.1,topology subnet,ifconfig 10.8.0.2 255.255.255.0,\
+    ifconfig-ipv6 fd00::2/64 fd00::1";
+
+fn prefix(prefix: &str) -> IpPrefix {
+    prefix.parse().unwrap()
+}
+
+//Every destination with whether it should go through the tunnel
+fn check(table: &RouteTable, cases: &[(&str, bool)]) {
+    for (destination, tunnel) in cases {
+        let ip: IpAddr = destination.parse().unwrap();
+        assert_eq!(table.should_tunnel(&ip), *tunnel, "{}", destination);
+    }
+}
+
+#[test]
+fn explicit_routes_only() {
+    let mut table = RouteTable::from_profile("client\n\
+        route 192.168.10.0 255.255.255.0\n\
+        route 192.168.10.128 255.255.255.128 net_gateway\n\
+        route-ipv6 2001:db8::/32\n\
+        route-ipv6 2001:db8:1::/48 net_gateway\n\
+        <ca>\nroute 1.1.1.1\n</ca>\n\
+        # route 8.8.8.8\n");
+    table.apply_pushed(&PushedConfig::parse(PUSH_REPLY).unwrap());
+    check(&table, &[
+        ("192.168.10.1", true),
+        //The longer net_gateway route wins
+        ("192.168.10.200", false),
+        ("192.168.11.1", false),
+        ("2001:db8::1", true),
+        ("2001:db8:1::1", false),
+        ("2001:db9::1", false),
+        //The tunnel's own networks
+        ("10.8.0.77", true),
+        ("fd00::77", true),
+        //Inline blocks and comments aren't routes
+        ("1.1.1.1", false),
+        ("8.8.8.8", false),
+    ]);
+}
+
+#[test]
+fn redirect_gateway_def1_sends_the_rest_through_the_tunnel() {
+    let mut table = RouteTable::from_profile("client\nroute 198.51.100.0 255.255.255.0 net_gateway\n");
+    table.apply_pushed(&PushedConfig::parse(&format!("{},redirect-gateway def1 bypass-dhcp,route 198.51.100.64 255.255.255.192", PUSH_REPLY)).unwrap());
+    check(&table, &[
+        ("8.8.8.8", true),
+        ("198.51.100.1", false),
+        //A pushed route inside the bypassed network wins by being longer
+        ("198.51.100.70", true),
+        ("10.8.0.1", true),
+    ]);
+}
+
+#[test]
+fn route_nopull_keeps_pushed_routes_out() {
+    let mut table = RouteTable::from_profile("client\nroute-nopull\nroute 172.16.0.0 255.240.0.0\n");
+    table.apply_pushed(&PushedConfig::parse(&format!("{},redirect-gateway def1,route 192.0.2.0 255.255.255.0", PUSH_REPLY)).unwrap());
+    check(&table, &[
+        ("172.20.0.1", true),
+        ("192.0.2.1", false),
+        ("8.8.8.8", false),
+        //The tunnel's own network always goes through it
+        ("10.8.0.1", true),
+    ]);
+}
+
+#[test]
+fn user_overrides_come_first() {
+    let mut table = RouteTable::from_profile("client\nredirect-gateway def1\nroute 10.0.0.0 255.0.0.0 net_gateway\nroute-ipv6 ::/0\n");
+    table.exclude(prefix("192.168.0.0/16"));
+    table.exclude(prefix("fe80::/10"));
+    table.include(prefix("10.1.0.0/16"));
+    //Ties go to the bypass
+    table.include(prefix("192.168.1.0/24"));
+    table.exclude(prefix("192.168.1.0/24"));
+    check(&table, &[
+        ("192.168.5.5", false),
+        ("192.168.1.1", false),
+        ("10.1.2.3", true),
+        ("10.2.0.1", false),
+        ("8.8.8.8", true),
+        ("fe80::1", false),
+        ("2001:db8::1", true),
+    ]);
+}
+
+#[test]
+fn empty_table_tunnels_nothing() {
+    check(&RouteTable::new(), &[("8.8.8.8", false), ("::1", false)]);
+}
@@ -0,0 +1,279 @@
+//SOCKS5 handshake and replies, to destinations kept off the tunnel and to a fake peer
+//behind the mock backend: `cargo test --no-default-features --features socks5,mock --test socks5`
+#![cfg(all(feature = "socks5", feature = "mock"))]
+mod peer;
+
+use std::io::{Read, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use libopenvpn3::openvpn::{IpPrefix, MockConfig, OVPNClient, RouteTable};
+use libopenvpn3::openvpn::dns::Resolver;
+use libopenvpn3::openvpn::socks5::Socks5Server;
+use libopenvpn3::openvpn::stack::{Stack, StackConfig};
+use peer::{Peer, CLIENT_IP};
+
+const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+const PEER_IP: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 1);
+
+struct FixedResolver;
+
+impl Resolver for FixedResolver {
+    fn resolve(&self, _host: &str) -> Result<Vec<IpAddr>> {
+        Ok(vec![IpAddr::V4(LOCALHOST)])
+    }
+}
+
+//A server over a stack whose empty route table sends everything directly
+fn server(credentials: Option<(&str, &str)>) -> SocketAddr {
+    let (client, _) = OVPNClient::mock(MockConfig::default(), None, None);
+    let config = StackConfig {
+        ipv4: Some(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)), 24)),
+        routes: Some(RouteTable::new()),
+        ..StackConfig::default()
+    };
+    serve(Stack::new(client, config).unwrap(), credentials)
+}
+
+//A server over a stack that tunnels 10.0.0.0/8 to the fake peer and the rest directly
+fn tunnel_server() -> (SocketAddr, Peer) {
+    let (stack, peer) = peer::stack_with(peer::echo(), |config| {
+        let mut routes = RouteTable::new();
+        routes.include(IpPrefix::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8));
+        StackConfig { routes: Some(routes), ..config }
+    });
+    (serve(stack, None), peer)
+}
+
+fn serve(stack: Stack, credentials: Option<(&str, &str)>) -> SocketAddr {
+    let mut server = Socks5Server::bind(Arc::new(stack), SocketAddr::new(IpAddr::V4(LOCALHOST), 0)).unwrap()
+        .with_resolver(Arc::new(FixedResolver));
+    if let Some((username, password)) = credentials {
+        server = server.with_credentials(username, password);
+    }
+    let addr = server.local_addr().unwrap();
+    std::thread::spawn(move || server.run());
+    addr
+}
+
+fn socks_client(server: SocketAddr) -> TcpStream {
+    let client = TcpStream::connect(server).unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client
+}
+
+fn read_n(stream: &mut TcpStream, n: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; n];
+    stream.read_exact(&mut buffer).unwrap();
+    buffer
+}
+
+//Echoes the first chunk a client sends
+fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buffer = [0u8; 64];
+        let n = stream.read(&mut buffer).unwrap();
+        stream.write_all(&buffer[..n]).unwrap();
+    });
+    addr
+}
+
+#[test]
+fn greeting_picks_a_method() {
+    let server = server(None);
+    let mut client = socks_client(server);
+    client.write_all(&[5, 2, 0x02, 0x00]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0x00]);
+
+    //Only username/password offered, none needed
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0x02]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0xff]);
+}
+
+#[test]
+fn username_and_password() {
+    let server = server(Some(("alice", "hunter2")));
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0x00]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0xff]);
+
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0x02]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0x02]);
+    client.write_all(b"\x01\x05alice\x05wrong").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 1]);
+
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0x02]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0x02]);
+    client.write_all(b"\x01\x05alice\x07hunter2").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+}
+
+#[test]
+fn connect_to_an_ipv4_address() {
+    let server = server(None);
+    let echo = echo_server();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    client.write_all(&request).unwrap();
+    //VER REP RSV ATYP, then the address we connected from
+    let reply = read_n(&mut client, 10);
+    assert_eq!(&reply[..8], &[5, 0, 0, 1, 127, 0, 0, 1]);
+    assert_ne!(u16::from_be_bytes([reply[8], reply[9]]), 0);
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_n(&mut client, 5), b"hello");
+}
+
+#[test]
+fn connect_to_a_domain() {
+    let server = server(None);
+    let echo = echo_server();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    let mut request = vec![5, 1, 0, 3, 11];
+    request.extend_from_slice(b"example.com");
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    client.write_all(&request).unwrap();
+    assert_eq!(&read_n(&mut client, 10)[..4], &[5, 0, 0, 1]);
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_n(&mut client, 5), b"hello");
+}
+
+#[test]
+fn failures_have_their_reply_codes() {
+    let server = server(None);
+    let refused = {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&refused.to_be_bytes());
+    client.write_all(&request).unwrap();
+    assert_eq!(read_n(&mut client, 10), vec![5, 5, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    //BIND isn't supported
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    client.write_all(&[5, 2, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap();
+    assert_eq!(read_n(&mut client, 10), vec![5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    client.write_all(&[5, 1, 0, 9]).unwrap();
+    assert_eq!(read_n(&mut client, 10), vec![5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn udp_associate_relays_datagrams() {
+    let server = server(None);
+    let target = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    target.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    client.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+    //The relay listens where the client reached the server
+    let reply = read_n(&mut client, 10);
+    assert_eq!(&reply[..8], &[5, 0, 0, 1, 127, 0, 0, 1]);
+    let relay = SocketAddr::new(IpAddr::V4(LOCALHOST), u16::from_be_bytes([reply[8], reply[9]]));
+
+    let local = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    //RSV RSV FRAG ATYP DST.ADDR DST.PORT DATA
+    let mut datagram = vec![0, 0, 0, 1, 127, 0, 0, 1];
+    datagram.extend_from_slice(&target.local_addr().unwrap().port().to_be_bytes());
+    datagram.extend_from_slice(b"ping");
+    local.send_to(&datagram, relay).unwrap();
+    let mut buffer = [0u8; 64];
+    let (size, relay_out) = target.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"ping");
+
+    //The answer comes with the target's IPv4 address, not a v4-mapped IPv6 one
+    target.send_to(b"pong", relay_out).unwrap();
+    local.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let (size, from) = local.recv_from(&mut buffer).unwrap();
+    assert_eq!(from, relay);
+    let mut expected = vec![0, 0, 0, 1, 127, 0, 0, 1];
+    expected.extend_from_slice(&target.local_addr().unwrap().port().to_be_bytes());
+    expected.extend_from_slice(b"pong");
+    assert_eq!(&buffer[..size], &expected[..]);
+
+    //Fragments are dropped
+    datagram[2] = 1;
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    local.send_to(&datagram, relay).unwrap();
+    assert!(target.recv_from(&mut buffer).is_err());
+}
+
+#[test]
+fn connect_through_the_tunnel() {
+    let (server, peer) = tunnel_server();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    client.write_all(&[5, 1, 0, 1, 10, 8, 0, 1, 0, 7]).unwrap();
+    //Connected from the stack's address
+    let reply = read_n(&mut client, 10);
+    assert_eq!((&reply[..4], &reply[4..8]), (&[5, 0, 0, 1][..], &CLIENT_IP.octets()[..]));
+    client.write_all(b"hello").unwrap();
+    assert_eq!(read_n(&mut client, 5), b"hello");
+    drop(client);
+
+    let connections = peer.wait_connections(|c| c.iter().all(|c| c.closed_by_client));
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].server, Some(SocketAddr::new(IpAddr::V4(PEER_IP), 7)));
+    assert_eq!(connections[0].client_port, u16::from_be_bytes([reply[8], reply[9]]));
+    assert_eq!(connections[0].received, b"hello");
+    assert!(connections[0].closed_by_client);
+
+    //Destinations outside the routes still go directly
+    let echo = echo_server();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&echo.port().to_be_bytes());
+    client.write_all(&request).unwrap();
+    assert_eq!(&read_n(&mut client, 10)[..8], &[5, 0, 0, 1, 127, 0, 0, 1]);
+    client.write_all(b"direct").unwrap();
+    assert_eq!(read_n(&mut client, 6), b"direct");
+    assert_eq!(peer.connections().len(), 1);
+}
+
+#[test]
+fn udp_associate_through_the_tunnel() {
+    let (server, peer) = tunnel_server();
+    let mut client = socks_client(server);
+    client.write_all(&[5, 1, 0]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+    client.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+    let reply = read_n(&mut client, 10);
+    let relay = SocketAddr::new(IpAddr::V4(LOCALHOST), u16::from_be_bytes([reply[8], reply[9]]));
+
+    let local = UdpSocket::bind((LOCALHOST, 0)).unwrap();
+    local.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let header = [0, 0, 0, 1, 10, 8, 0, 1, 0x27, 0x0f];
+    local.send_to(&[&header[..], b"ping"].concat(), relay).unwrap();
+    //The peer echoes it, and it comes back with the peer's address
+    let mut buffer = [0u8; 64];
+    let (size, from) = local.recv_from(&mut buffer).unwrap();
+    assert_eq!(from, relay);
+    assert_eq!(&buffer[..size], &[&header[..], b"ping"].concat()[..]);
+
+    let datagrams = peer.datagrams();
+    assert_eq!(datagrams.len(), 1);
+    assert_eq!((datagrams[0].1, &datagrams[0].2[..]), (SocketAddr::new(IpAddr::V4(PEER_IP), 9999), &b"ping"[..]));
+}
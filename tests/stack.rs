@@ -0,0 +1,141 @@
+//TCP and UDP sockets through the tunnel, against a fake peer behind the mock backend:
+//`cargo test --no-default-features --features stack,mock --test stack`
+#![cfg(all(feature = "stack", feature = "mock"))]
+mod peer;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::stack::{TcpStream, UdpSocket};
+use peer::{Service, CLIENT_IP};
+
+const PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1)), 7);
+
+//Echoes until it gets "quit", which it answers with "bye" before closing
+fn echo_until_quit() -> Arc<Service> {
+    Arc::new(|received: &mut Vec<u8>| {
+        if received.ends_with(b"quit") {
+            received.clear();
+            (b"bye".to_vec(), true)
+        } else {
+            (std::mem::take(received), false)
+        }
+    })
+}
+
+fn next_port(port: u16) -> u16 {
+    if port == 65534 { 49152 } else { port + 1 }
+}
+
+#[test]
+fn tcp_connects_reads_and_is_closed_by_the_peer() {
+    let (stack, peer) = peer::stack(echo_until_quit());
+    let mut stream = TcpStream::connect(&stack, PEER).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5)));
+    assert_eq!(stream.peer_addr().unwrap(), PEER);
+    assert_eq!(stream.local_addr().unwrap().ip(), IpAddr::V4(CLIENT_IP));
+
+    stream.write_all(b"hello").unwrap();
+    let mut buffer = [0u8; 5];
+    stream.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"hello");
+
+    stream.write_all(b"quit").unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bye");
+    //The peer's side is closed for good
+    assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+    drop(stream);
+
+    let connections = peer.wait_connections(|c| c.iter().all(|c| c.closed_by_client));
+    assert_eq!(connections.len(), 1);
+    assert_eq!(connections[0].received, b"helloquit");
+    assert!(connections[0].closed_by_client);
+}
+
+#[test]
+fn tcp_shutdown_sends_a_fin_and_still_reads() {
+    let (stack, peer) = peer::stack(peer::echo());
+    let mut stream = TcpStream::connect(&stack, PEER).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5)));
+    stream.write_all(b"last words").unwrap();
+    stream.flush().unwrap();
+    stream.shutdown();
+    //The echo arrives after our FIN, then the peer's FIN ends the stream
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert_eq!(received, b"last words");
+    let connections = peer.wait_connections(|c| c.iter().all(|c| c.closed_by_client));
+    assert!(connections[0].closed_by_client);
+    assert_eq!(stream.write(b"more").unwrap_err().kind(), ErrorKind::BrokenPipe);
+}
+
+#[test]
+fn tcp_connection_to_nowhere_times_out() {
+    let (stack, peer) = peer::stack(peer::echo());
+    //The peer stops answering
+    drop(peer);
+    std::thread::sleep(Duration::from_millis(50));
+    let e = TcpStream::connect_timeout(&stack, PEER, Duration::from_millis(300)).err().unwrap();
+    assert_eq!(e.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn udp_round_trip() {
+    let (stack, peer) = peer::stack(peer::echo());
+    let mut socket = UdpSocket::bind(&stack, 0).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5)));
+    let to = SocketAddr::new(PEER.ip(), 9999);
+    assert_eq!(socket.send_to(b"ping", to).unwrap(), 4);
+    let mut buffer = [0u8; 64];
+    let (size, from) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!((&buffer[..size], from), (&b"ping"[..], to));
+    assert_eq!(peer.datagrams(), vec![(socket.local_port(), to, b"ping".to_vec())]);
+}
+
+#[test]
+fn udp_read_timeout() {
+    let (stack, _peer) = peer::stack(peer::echo());
+    let mut socket = UdpSocket::bind(&stack, 0).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100)));
+    let mut buffer = [0u8; 64];
+    assert_eq!(socket.recv_from(&mut buffer).unwrap_err().kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn ephemeral_ports_skip_ports_in_use() {
+    let (stack, peer) = peer::stack(peer::echo());
+    let first = UdpSocket::bind(&stack, 0).unwrap();
+    let port = first.local_port();
+    assert!((49152..65535).contains(&port));
+    //The next ephemeral port is taken, so TCP gets the one after
+    let taken = UdpSocket::bind(&stack, next_port(port)).unwrap();
+    let stream = TcpStream::connect(&stack, PEER).unwrap();
+    assert_eq!(stream.local_addr().unwrap().port(), next_port(next_port(port)));
+    assert_eq!(peer.connections()[0].client_port, next_port(next_port(port)));
+    //And UDP skips the TCP one
+    let second = UdpSocket::bind(&stack, 0).unwrap();
+    assert_eq!(second.local_port(), next_port(next_port(next_port(port))));
+
+    assert_eq!(UdpSocket::bind(&stack, port).err().unwrap().kind(), ErrorKind::AddrInUse);
+    assert_eq!(UdpSocket::bind(&stack, taken.local_port()).err().unwrap().kind(), ErrorKind::AddrInUse);
+    drop(first);
+    assert!(UdpSocket::bind(&stack, port).is_ok());
+}
+
+#[test]
+fn backend_errors_reach_waiting_sockets() {
+    let (stack, peer) = peer::stack(peer::echo());
+    let mut stream = TcpStream::connect(&stack, PEER).unwrap();
+    stream.set_write_timeout(Some(Duration::from_secs(5)));
+    peer.handle.drop_connection();
+    //Queued, then failing to go out
+    stream.write_all(b"lost").unwrap();
+    let start = Instant::now();
+    let e = stream.flush().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert_eq!(e.kind(), ErrorKind::Other);
+    assert!(e.to_string().contains("mock: not connected"), "{}", e);
+}
@@ -0,0 +1,61 @@
+//A real tun device over the mock backend. Creating it needs CAP_NET_ADMIN, so run it in a
+//network namespace: `unshare -rn cargo test --no-default-features --features tun,mock --test tun -- --ignored`
+#![cfg(all(feature = "tun", feature = "mock", target_os = "linux"))]
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use libopenvpn3::openvpn::tun::{pump, TunDevice};
+use libopenvpn3::openvpn::{MockConfig, OVPNClient, Packet, PacketBuilder};
+
+const LOCAL: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+const PEER: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 9);
+
+fn wait(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+#[ignore = "needs CAP_NET_ADMIN and /dev/net/tun, run it under `unshare -rn` with --ignored"]
+fn packets_go_between_the_device_and_the_tunnel() {
+    let config = MockConfig {
+        loopback: false,
+        ..MockConfig::default()
+    };
+    let (mut client, handle) = OVPNClient::mock(config, None, None);
+    assert!(client.connect().is_ok());
+    let pushed = client.wait_pushed_config(Duration::from_secs(5)).unwrap();
+    wait("CONNECTED", || handle.is_connected());
+
+    let mut tun = TunDevice::create("ovpntest0").unwrap();
+    tun.configure(&pushed).unwrap();
+    assert_eq!(tun.mtu(), 1500);
+    let stop = Arc::new(AtomicBool::new(false));
+    let pumping = {
+        let stop = stop.clone();
+        std::thread::spawn(move || pump(&mut client, &mut tun, &stop))
+    };
+
+    //Out through the device into the tunnel
+    let socket = UdpSocket::bind((LOCAL, 0)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let port = socket.local_addr().unwrap().port();
+    socket.send_to(b"ping", (PEER, 9999)).unwrap();
+    wait("the packet in the tunnel", || handle.sent().iter().any(|sent| {
+        Packet::parse(sent).map(|p| p.destination() == PEER && p.destination_port() == Some(9999) && p.payload() == b"ping").unwrap_or(false)
+    }));
+
+    //And back from the tunnel to the socket
+    handle.inject(&PacketBuilder::ipv4(PEER, LOCAL).udp(9999, port).payload(b"pong").build());
+    let mut buffer = [0u8; 16];
+    let (size, from) = socket.recv_from(&mut buffer).unwrap();
+    assert_eq!(&buffer[..size], b"pong");
+    assert_eq!(from, (PEER, 9999).into());
+
+    stop.store(true, Ordering::SeqCst);
+    pumping.join().unwrap().unwrap();
+}
@@ -0,0 +1,52 @@
+//Profile checks against profiles from the `pki` module: `cargo test --features validate,pki --test validate`
+#![cfg(all(feature = "validate", feature = "pki"))]
+use libopenvpn3::openvpn::pki::{Pki, ProfileOptions, TlsWrap};
+use libopenvpn3::openvpn::validate::{has_errors, validate, Severity};
+
+#[test]
+fn generated_profile_is_clean() {
+    let pki = Pki::generate("vpn.example.com").unwrap();
+    let client = pki.client("alice").unwrap();
+    let options = ProfileOptions {
+        tls_wrap: TlsWrap::Crypt,
+        ..ProfileOptions::default()
+    };
+    let diagnostics = validate(&pki.client_profile(&client, &options));
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+}
+
+#[test]
+fn key_of_another_client_is_rejected() {
+    let pki = Pki::generate("vpn.example.com").unwrap();
+    let mut alice = pki.client("alice").unwrap();
+    alice.key_pem = pki.client("bob").unwrap().key_pem;
+    let diagnostics = validate(&pki.client_profile(&alice, &ProfileOptions::default()));
+    assert!(has_errors(&diagnostics));
+    assert!(diagnostics.iter().any(|d| d.directive.as_deref() == Some("key") && d.message.contains("doesn't belong")));
+}
+
+#[test]
+fn reports_directives_with_their_lines() {
+    let pki = Pki::generate("vpn.example.com").unwrap();
+    let client = pki.client("alice").unwrap();
+    let profile = format!("dev tap\ncipher BF-CBC\nfrobnicate 3\ntls-auth ta.key 1\nsocks-proxy 127.0.0.1 1080\n{}", pki.client_profile(&client, &ProfileOptions::default()));
+    let diagnostics = validate(&profile);
+    let at = |line: usize| diagnostics.iter().find(|d| d.line == Some(line)).map(|d| d.severity);
+    assert_eq!(at(1), Some(Severity::Error));
+    assert_eq!(at(2), Some(Severity::Warning));
+    assert_eq!(at(3), Some(Severity::Warning));
+    assert_eq!(at(4), Some(Severity::Error));
+    assert_eq!(at(5), Some(Severity::Error));
+    assert!(diagnostics.iter().any(|d| d.line == Some(5) && d.message.contains("SOCKS")));
+}
+
+#[test]
+fn missing_pieces_are_errors() {
+    let diagnostics = validate("client\ndev tun\n<ca>\nnot a certificate\n</ca>\n");
+    let mut directives: Vec<&str> = diagnostics.iter()
+        .filter(|d| d.severity == Severity::Error)
+        .filter_map(|d| d.directive.as_deref())
+        .collect();
+    directives.sort();
+    assert_eq!(directives, vec!["ca", "cert", "remote"]);
+}